@@ -0,0 +1,409 @@
+use crate::{
+    config::{AnalyzerConfig, Config},
+    jobs::{
+        AnalyzerJob, TranslatorJob, TranslatorJobQueue, record_failure, translator_dependencies,
+    },
+    storage::{
+        FileDb, PipelineStage, RowId, SOURCE_FILE_KEY, TextSegment, create_db_connection,
+        get_file_meta, is_stage_done, list_includes, list_tachie, load_translated_segments,
+        mark_stage_done, mark_stage_started, save_variables, with_db,
+    },
+};
+use anyhow::{Context, Result as AnyResult, bail};
+use apalis::prelude::{Data, Storage};
+use auto_context::auto_context as anyhow_context;
+use regex::Regex;
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    sync::Arc,
+};
+use tokio::sync::RwLock;
+use whatlang::{Info, Lang};
+
+#[allow(unused)]
+type AnalyzerResult<T> = AnyResult<T>;
+
+/// Finds engine variable tokens using the configured `analyzer.variable_patterns`.
+pub struct VariableMatcher {
+    patterns: Vec<Regex>,
+}
+
+impl VariableMatcher {
+    #[anyhow_context]
+    pub fn new(patterns: &[String]) -> AnalyzerResult<Self> {
+        Ok(VariableMatcher {
+            patterns: patterns
+                .iter()
+                .map(|pattern| Regex::new(pattern))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Every token in `text`, sorted, duplicates kept.
+    pub fn tokens(&self, text: &str) -> Vec<String> {
+        let mut tokens = self
+            .patterns
+            .iter()
+            .flat_map(|pattern| pattern.find_iter(text))
+            .map(|found| found.as_str().to_string())
+            .collect::<Vec<_>>();
+        tokens.sort();
+        tokens
+    }
+}
+
+/// Share of non-ASCII characters above which a file is reported as mojibake.
+const MOJIBAKE_RATIO: f64 = 0.01;
+
+/// Statistics of a file that looks wrongly transcoded, see [`detect_mojibake`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct MojibakeReport {
+    /// `U+FFFD` left behind by a lossy decode.
+    pub replacement_chars: usize,
+    /// UTF-8 that was decoded as Latin-1/Windows-1252, e.g. `ã‚`.
+    pub latin1_sequences: usize,
+    /// UTF-8 that was decoded as Shift_JIS, e.g. `縺ゅ`.
+    pub shift_jis_markers: usize,
+    pub non_ascii_chars: usize,
+    /// 1-based line of the first suspicious character.
+    pub first_line: usize,
+}
+
+impl MojibakeReport {
+    pub fn suspicious(&self) -> usize {
+        self.replacement_chars + self.latin1_sequences + self.shift_jis_markers
+    }
+}
+
+impl Display for MojibakeReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "looks like mojibake from line {}: {} of {} non-ASCII characters are suspicious \
+             ({} replacement, {} UTF-8 read as Latin-1, {} UTF-8 read as Shift_JIS)",
+            self.first_line,
+            self.suspicious(),
+            self.non_ascii_chars,
+            self.replacement_chars,
+            self.latin1_sequences,
+            self.shift_jis_markers,
+        )
+    }
+}
+
+/// Heuristically flags text that was transcoded with the wrong encoding before
+/// translation wastes effort on it.
+pub fn detect_mojibake(content: &str) -> Option<MojibakeReport> {
+    let mut report = MojibakeReport {
+        replacement_chars: 0,
+        latin1_sequences: 0,
+        shift_jis_markers: 0,
+        non_ascii_chars: 0,
+        first_line: 0,
+    };
+    for (index, line) in content.lines().enumerate() {
+        let before = report.suspicious();
+        let mut prev = None;
+        for c in line.chars() {
+            if !c.is_ascii() {
+                report.non_ascii_chars += 1;
+            }
+            match c {
+                '\u{FFFD}' => report.replacement_chars += 1,
+                // The most frequent kanji produced by UTF-8 kana read as Shift_JIS.
+                '縺' | '繧' | '繝' => report.shift_jis_markers += 1,
+                // A UTF-8 lead byte read as Latin-1 followed by a continuation byte,
+                // which Windows-1252 may have turned into punctuation.
+                _ if matches!(prev, Some('\u{C2}'..='\u{EF}'))
+                    && (matches!(c, '\u{80}'..='\u{BF}')
+                        || "€‚ƒ„…†‡ˆ‰Š‹ŒŽ‘’“”•–—˜™š›œžŸ".contains(c)) =>
+                {
+                    report.latin1_sequences += 1
+                }
+                _ => {}
+            }
+            prev = Some(c);
+        }
+        if before == 0 && report.suspicious() > 0 {
+            report.first_line = index + 1;
+        }
+    }
+
+    let ratio = report.suspicious() as f64 / report.non_ascii_chars.max(1) as f64;
+    (report.suspicious() > 0 && ratio >= MOJIBAKE_RATIO).then_some(report)
+}
+
+/// Texts with fewer letters than this are too short to tell their language by.
+const MIN_DETECTION_LETTERS: usize = 12;
+
+/// The language of a segment's text, if the text is long enough and the
+/// detection reliable enough to go by.
+pub fn detect_segment_language(text: &str) -> Option<Lang> {
+    if text.chars().filter(|c| c.is_alphabetic()).count() < MIN_DETECTION_LETTERS {
+        return None;
+    }
+    whatlang::detect(text)
+        .filter(Info::is_reliable)
+        .map(|info| info.lang())
+}
+
+fn source_content(segment: &TextSegment) -> &str {
+    match segment {
+        TextSegment::IMessage(message) => &message.content,
+        TextSegment::INonMessage(non_message) => &non_message.content,
+    }
+}
+
+/// Records every distinct variable token of the file with its occurrence count.
+#[anyhow_context]
+pub async fn catalog_variables(db: FileDb, matcher: &VariableMatcher) -> AnalyzerResult<()> {
+    let mut variables = BTreeMap::new();
+    for (_, segment, _) in load_translated_segments(db.clone()).await? {
+        for token in matcher.tokens(source_content(&segment)) {
+            *variables.entry(token).or_insert(0) += 1;
+        }
+    }
+    save_variables(db, variables).await?;
+    Ok(())
+}
+
+/// Ids of translated segments whose variable tokens differ from their source, i.e.
+/// the model dropped, duplicated or altered a variable.
+#[anyhow_context]
+pub async fn check_variable_integrity(
+    db: FileDb,
+    matcher: &VariableMatcher,
+) -> AnalyzerResult<Vec<RowId>> {
+    let mut broken = Vec::new();
+    for (id, segment, translation) in load_translated_segments(db).await? {
+        let Some(translation) = translation else {
+            continue;
+        };
+        if matcher.tokens(source_content(&segment)) != matcher.tokens(&translation.content) {
+            broken.push(id);
+        }
+    }
+    Ok(broken)
+}
+
+/// Segments sharing a source [`fingerprint`] but translated differently.
+///
+/// [`fingerprint`]: TextSegment::fingerprint
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InconsistencyGroup {
+    pub fingerprint: String,
+    pub source: String,
+    /// Each distinct translation with the segments carrying it.
+    pub translations: BTreeMap<String, Vec<RowId>>,
+}
+
+impl Display for InconsistencyGroup {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` is translated {} ways:",
+            self.source,
+            self.translations.len()
+        )?;
+        for (translation, ids) in &self.translations {
+            let ids = ids.iter().map(RowId::to_string).collect::<Vec<_>>();
+            write!(f, "\n  `{translation}` (segment {})", ids.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Groups translated segments by source fingerprint and returns the groups whose
+/// translations disagree, e.g. from runs that predate the translation cache.
+#[anyhow_context]
+pub async fn detect_inconsistent_translations(
+    db: FileDb,
+) -> AnalyzerResult<Vec<InconsistencyGroup>> {
+    let mut groups = BTreeMap::<String, InconsistencyGroup>::new();
+    for (id, segment, translation) in load_translated_segments(db).await? {
+        let Some(translation) = translation else {
+            continue;
+        };
+        let fingerprint = segment.fingerprint();
+        groups
+            .entry(fingerprint.clone())
+            .or_insert_with(|| InconsistencyGroup {
+                fingerprint,
+                source: source_content(&segment).to_string(),
+                translations: BTreeMap::new(),
+            })
+            .translations
+            .entry(translation.content)
+            .or_default()
+            .push(id);
+    }
+    Ok(groups
+        .into_values()
+        .filter(|group| group.translations.len() > 1)
+        .collect())
+}
+
+/// Which file includes which: the source file of `db` paired with the path of
+/// each of its `#include` directives, as written in the script.
+#[anyhow_context]
+pub async fn include_graph(db: FileDb) -> AnalyzerResult<Vec<(String, String)>> {
+    let Some(source) = get_file_meta(db.clone(), SOURCE_FILE_KEY).await? else {
+        bail!("The source file is not recorded, parse the file again");
+    };
+    let edges = list_includes(db)
+        .await?
+        .into_iter()
+        .map(|include| (source.clone(), include))
+        .collect();
+    Ok(edges)
+}
+
+/// Runs every analysis on the database of `name`.
+#[anyhow_context]
+pub async fn analyze_file(name: &str, config: &AnalyzerConfig) -> AnalyzerResult<()> {
+    let matcher = VariableMatcher::new(&config.variable_patterns)?;
+    with_db(name, |db| {
+        Box::pin(async move {
+            mark_stage_started(db.clone(), PipelineStage::Analyzer).await?;
+
+            for (tachie, count) in list_tachie(db.clone()).await? {
+                println!("[{name}] tachie `{tachie}` used {count} time(s)");
+            }
+            for include in list_includes(db.clone()).await? {
+                println!("[{name}] includes `{include}`");
+            }
+
+            catalog_variables(db.clone(), &matcher).await?;
+
+            mark_stage_done(db, PipelineStage::Analyzer).await
+        })
+    })
+    .await
+}
+
+/// Reports the translations in the database of `name` that lost variables or
+/// disagree with each other. Only meaningful once the file is translated, so
+/// it is run by the assembler rather than by [`analyze_file`].
+#[anyhow_context]
+pub async fn check_translations(name: &str, config: &AnalyzerConfig) -> AnalyzerResult<()> {
+    let matcher = VariableMatcher::new(&config.variable_patterns)?;
+    with_db(name, |db| {
+        Box::pin(async move {
+            for id in check_variable_integrity(db.clone(), &matcher).await? {
+                eprintln!("[{name}] segment {id}: translated variables differ from the source");
+            }
+            for group in detect_inconsistent_translations(db).await? {
+                eprintln!("[{name}] {group}");
+            }
+            Ok(())
+        })
+    })
+    .await
+}
+
+#[allow(unused)]
+pub async fn analyzer_main(
+    job: AnalyzerJob,
+    translator: Data<Arc<RwLock<TranslatorJobQueue>>>,
+    config: Data<Arc<Config>>,
+) -> AnyResult<()> {
+    let analyzed = async {
+        analyze_file(&job.file_name, &config.analyzer).await?;
+
+        let db = create_db_connection(&job.file_name).await?;
+        let dependencies = translator_dependencies(&config);
+        if dependencies.contains(&PipelineStage::Analyzer) {
+            for stage in dependencies {
+                if !is_stage_done(db.clone(), *stage).await? {
+                    return Ok(());
+                }
+            }
+            let mut translator = translator.write().await;
+            translator
+                .push(TranslatorJob {
+                    file_name: job.file_name.clone(),
+                    file_path: job.file_path.clone(),
+                })
+                .await?;
+        }
+        AnyResult::Ok(())
+    };
+    record_failure(&job.file_name, PipelineStage::Analyzer, analyzed.await).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::ParserConfig,
+        parser::parse_string,
+        storage::{
+            create_db_connection, insert_segments, load_translated_segments, set_translation,
+        },
+    };
+
+    /// A migrated database of `script` whose first messages are translated, in
+    /// order, into `translations`.
+    async fn translated(name: &str, script: &str, translations: &[&str]) -> FileDb {
+        let db = create_db_connection(&format!("analyzer-test-{name}"))
+            .await
+            .unwrap();
+        let segments = parse_string(script, "test.sc", &ParserConfig::default()).unwrap();
+        insert_segments(db.clone(), &segments, 500).await.unwrap();
+        let ids = load_translated_segments(db.clone())
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|(_, segment, _)| matches!(segment, TextSegment::IMessage(_)))
+            .map(|(id, _, _)| id);
+        for (id, translation) in ids.zip(translations) {
+            set_translation(db.clone(), id, translation.to_string())
+                .await
+                .unwrap();
+        }
+        db
+    }
+
+    #[tokio::test]
+    async fn translations_that_lost_a_variable_are_reported() {
+        let script = ".message 1 %PLAYER%、こんにちは\n.message 2 %PLAYER%、さようなら\n";
+        let db = translated("variables", script, &["Hello, %PLAYER%", "Goodbye"]).await;
+        let matcher = VariableMatcher::new(&AnalyzerConfig::default().variable_patterns).unwrap();
+
+        let broken = check_variable_integrity(db, &matcher).await.unwrap();
+        assert_eq!(broken.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn identical_sources_translated_differently_are_reported() {
+        let script = ".message 1 はい\n.message 2 はい\n.message 3 いいえ\n";
+        let db = translated("inconsistent", script, &["Yes", "Yeah", "No"]).await;
+
+        let groups = detect_inconsistent_translations(db).await.unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].source, "はい");
+        let translations = groups[0].translations.keys().collect::<Vec<_>>();
+        assert_eq!(translations, ["Yeah", "Yes"]);
+    }
+
+    #[tokio::test]
+    async fn untranslated_files_have_nothing_to_check() {
+        let script = ".message 1 %PLAYER%、こんにちは\n.message 2 %PLAYER%、こんにちは\n";
+        let db = translated("untranslated", script, &[]).await;
+        let matcher = VariableMatcher::new(&AnalyzerConfig::default().variable_patterns).unwrap();
+
+        assert!(
+            check_variable_integrity(db.clone(), &matcher)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            detect_inconsistent_translations(db)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
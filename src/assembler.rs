@@ -0,0 +1,544 @@
+use crate::{
+    analyzer::check_translations,
+    config::{AssemblerConfig, Config, ParserConfig},
+    jobs::{AssemblerJob, record_failure},
+    parser::{CORNER_BRACKETS, VecSink, parse_content},
+    profile::{self, Phase},
+    storage::{
+        FileDb, IMessageModel, PipelineStage, Provenance, RowId, TextSegment, TextSegmentBuilder,
+        Translation, count_segments, load_localized, load_translated_segments, mark_stage_done,
+        mark_stage_started, translation_provenance, visit_translated_segments, with_db,
+    },
+};
+use anyhow::{Context, Result as AnyResult, bail};
+use apalis::prelude::Data;
+use auto_context::auto_context as anyhow_context;
+use encoding_rs::{Encoding, UTF_8};
+use similar::TextDiff;
+use std::{
+    collections::HashMap,
+    fs::{File, copy, create_dir_all, remove_file, rename},
+    io::{self, BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+#[allow(unused)]
+type AssemblerResult<T> = AnyResult<T>;
+
+const UTF_8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Renders one segment back into a script line.
+fn render(segment: &TextSegment) -> String {
+    match segment {
+        TextSegment::IMessage(message) => {
+            if let Some(line) = render_raw(message) {
+                return line;
+            }
+            let mut line = format!("{}.message {}", message.indent, message.id);
+            if !message.tachie.is_empty() {
+                line = format!("{line} {}", message.tachie);
+            }
+            if let Some(variant) = &message.tachie_variant {
+                line = format!("{line}{}{}", variant.delimiter, variant.name);
+            }
+            line = if message.name.is_empty() {
+                format!("{line} {}", message.content)
+            } else {
+                let brackets = message.brackets.as_deref().unwrap_or(CORNER_BRACKETS);
+                let (open, close) =
+                    brackets.split_at(brackets.chars().next().map_or(0, char::len_utf8));
+                format!("{line} {} {open}{}{close}", message.name, message.content)
+            };
+            if let Some(comment) = &message.comment {
+                line.push_str(comment);
+            }
+            line
+        }
+        TextSegment::INonMessage(non_message) => non_message.content.clone(),
+    }
+}
+
+/// The source line of `message` with its name and content replaced, keeping
+/// the spacing and digits of the source, if it was recorded.
+fn render_raw(message: &IMessageModel) -> Option<String> {
+    let mut line = message.raw.clone();
+    let (start, end) = message.content_span?;
+    line.get(start..end)?;
+    line.replace_range(start..end, &message.content);
+    // The name comes before the content, its span is still valid.
+    if let Some((start, end)) = message.name_span {
+        line.get(start..end)?;
+        line.replace_range(start..end, &message.name);
+    }
+    Some(line)
+}
+
+/// Renders segments, already in source order, into a script.
+pub fn render_segments(segments: &[TextSegment]) -> String {
+    let mut output = segments.iter().map(render).collect::<Vec<_>>().join("\n");
+    output.push('\n');
+    output
+}
+
+/// What the segments of a file are assembled with, see [`Substitutions::apply`].
+struct Substitutions<'a> {
+    translated_names: bool,
+    lang: Option<&'a str>,
+    /// The translations into `lang`, if given.
+    localized: Option<HashMap<RowId, Translation>>,
+    /// Where translations came from, with `provenance_comments`.
+    provenance: Option<HashMap<RowId, Provenance>>,
+}
+
+impl<'a> Substitutions<'a> {
+    #[anyhow_context]
+    async fn load(
+        db: FileDb,
+        config: &AssemblerConfig,
+        lang: Option<&'a str>,
+    ) -> AssemblerResult<Self> {
+        let localized = match lang {
+            Some(lang) => Some(load_localized(db.clone(), lang).await?),
+            None => None,
+        };
+        // Translations into additional languages aren't audited.
+        let provenance = match lang {
+            None if config.provenance_comments => Some(translation_provenance(db).await?),
+            _ => None,
+        };
+        Ok(Substitutions {
+            translated_names: config.translated_names,
+            lang,
+            localized,
+            provenance,
+        })
+    }
+
+    /// `segment` with its `translation`, or its one into `lang` if given, and the
+    /// glossary name of its speaker when `translated_names` is set. Preceded by a
+    /// comment on where the translation came from with `provenance_comments`.
+    /// Nothing for segments spliced in from an `#include`, whose directive is
+    /// written instead.
+    fn apply(
+        &mut self,
+        id: RowId,
+        mut segment: TextSegment,
+        translation: Option<Translation>,
+    ) -> AssemblerResult<Vec<TextSegment>> {
+        if segment.origin().is_some() {
+            return Ok(Vec::new());
+        }
+        let mut assembled = Vec::with_capacity(2);
+        if let Some(provenance) = self
+            .provenance
+            .as_ref()
+            .and_then(|provenance| provenance.get(&id))
+            && let TextSegment::IMessage(message) = &segment
+            && translation.is_some()
+        {
+            let origin = if provenance.locked { "locked" } else { "auto" };
+            let comment = format!(
+                "{};; translated by {} at {} ({origin})",
+                message.indent,
+                provenance.backend,
+                provenance.translated_at.format("%Y-%m-%d %H:%M:%S UTC"),
+            );
+            let comment = TextSegmentBuilder::new_non_message()
+                .line(message.line)
+                .content(comment)
+                .build()?;
+            assembled.push(TextSegment::INonMessage(comment));
+        }
+        if let TextSegment::IMessage(message) = &mut segment {
+            if self.translated_names
+                && let Some(name) = translation
+                    .as_ref()
+                    .and_then(|translation| translation.translated_name.clone())
+            {
+                message.name = name;
+            }
+            let translation = match self.lang {
+                Some(_) => self
+                    .localized
+                    .as_mut()
+                    .and_then(|localized| localized.remove(&id)),
+                None => translation,
+            };
+            if let Some(translation) = translation {
+                message.content = translation.content;
+            }
+        }
+        assembled.push(segment);
+        Ok(assembled)
+    }
+}
+
+/// Renders the database of a file with its translations, or those into `lang`
+/// if given, see [`Substitutions::apply`]. Untranslated messages are written as
+/// they are.
+#[anyhow_context]
+pub async fn assemble(
+    db: FileDb,
+    config: &AssemblerConfig,
+    lang: Option<&str>,
+) -> AssemblerResult<String> {
+    let mut substitutions = Substitutions::load(db.clone(), config, lang).await?;
+    let mut segments = Vec::new();
+    for (id, segment, translation) in load_translated_segments(db).await? {
+        segments.extend(substitutions.apply(id, segment, translation)?);
+    }
+    Ok(render_segments(&segments))
+}
+
+/// Like [`assemble`], but encodes each segment and writes it to `writer` as it is
+/// read, a page of segments at a time, so that huge files don't have to fit in
+/// memory, see `assembler.stream_above`.
+#[anyhow_context]
+pub async fn assemble_streaming<W: Write + Send>(
+    db: FileDb,
+    writer: &mut W,
+    config: &AssemblerConfig,
+    lang: Option<&str>,
+    encoding: &'static Encoding,
+) -> AssemblerResult<()> {
+    let mut substitutions = Substitutions::load(db.clone(), config, lang).await?;
+    let mut bom = config.write_bom;
+    let mut written = false;
+    visit_translated_segments(db, |id, segment, translation| {
+        let line = segment.line();
+        for segment in substitutions.apply(id, segment, translation)? {
+            let bytes = encode_output(&format!("{}\n", render(&segment)), encoding, bom)
+                .with_context(|| format!("Segment at line {line}"))?;
+            writer.write_all(&bytes)?;
+            (bom, written) = (false, true);
+        }
+        Ok(())
+    })
+    .await?;
+    if !written {
+        // What `render_segments` renders an empty file to.
+        writer.write_all(&encode_output("\n", encoding, bom)?)?;
+    }
+    Ok(())
+}
+
+/// Parses `content` and renders it back untranslated, returning a unified diff
+/// when the result differs from the source.
+#[anyhow_context]
+pub fn round_trip_diff(
+    name: &str,
+    content: &str,
+    config: &ParserConfig,
+) -> AssemblerResult<Option<String>> {
+    let mut sink = VecSink::default();
+    parse_content(content, config, &mut sink)?;
+    let assembled = render_segments(&sink.segments);
+    if assembled == content {
+        return Ok(None);
+    }
+    let diff = TextDiff::from_lines(content, assembled.as_str())
+        .unified_diff()
+        .header(&format!("{name} (source)"), &format!("{name} (assembled)"))
+        .to_string();
+    Ok(Some(diff))
+}
+
+/// Transcodes the assembled UTF-8 text, failing on the first character the target
+/// encoding cannot represent instead of emitting HTML numeric character references.
+#[anyhow_context]
+pub fn encode_output(
+    text: &str,
+    encoding: &'static Encoding,
+    write_bom: bool,
+) -> AssemblerResult<Vec<u8>> {
+    if encoding.output_encoding() != encoding {
+        bail!("`{}` cannot be used as an output encoding", encoding.name());
+    }
+    if write_bom && encoding != UTF_8 {
+        bail!(
+            "A BOM can only be written for UTF-8, not `{}`",
+            encoding.name()
+        );
+    }
+
+    let (bytes, _, had_errors) = encoding.encode(text);
+    if had_errors {
+        for (line, content) in text.lines().enumerate() {
+            for ch in content.chars() {
+                let (_, _, unmappable) = encoding.encode(ch.encode_utf8(&mut [0; 4]));
+                if unmappable {
+                    bail!(
+                        "Character `{ch}` (U+{:04X}) on output line {} cannot be encoded as `{}`",
+                        ch as u32,
+                        line + 1,
+                        encoding.name()
+                    );
+                }
+            }
+        }
+    }
+
+    let bom = if write_bom { UTF_8_BOM } else { &[] };
+    Ok([bom, bytes.as_ref()].concat())
+}
+
+/// Writes `text` to `path`, atomically if `atomic` is set, see [`OutputFile`].
+#[anyhow_context]
+pub fn write_output(
+    path: &Path,
+    text: &str,
+    encoding: &'static Encoding,
+    write_bom: bool,
+    atomic: bool,
+) -> AssemblerResult<()> {
+    let bytes = encode_output(text, encoding, write_bom)?;
+    let mut output = OutputFile::create(path, atomic)?;
+    output.write_all(&bytes)?;
+    output.commit()
+}
+
+/// An output file being written. With atomic writes, a hidden temporary file
+/// next to it is written instead and renamed over it by [`OutputFile::commit`],
+/// so that an interrupted run never leaves the previous output truncated.
+pub struct OutputFile {
+    path: PathBuf,
+    /// The temporary file, until it is committed.
+    staged: Option<PathBuf>,
+    file: BufWriter<File>,
+}
+
+impl OutputFile {
+    #[anyhow_context]
+    pub fn create(path: &Path, atomic: bool) -> AssemblerResult<Self> {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        let staged = if atomic {
+            let name = path
+                .file_name()
+                .with_context(|| format!("`{}` is not a file", path.display()))?;
+            Some(path.with_file_name(format!(".{}.tmp", name.to_string_lossy())))
+        } else {
+            None
+        };
+        let file = File::create(staged.as_deref().unwrap_or(path))?;
+        Ok(OutputFile {
+            path: path.to_path_buf(),
+            staged,
+            file: BufWriter::new(file),
+        })
+    }
+
+    /// Flushes the file and, with atomic writes, syncs it and renames it over the
+    /// output. Where the rename crosses filesystems, e.g. onto a bind-mounted
+    /// file, the temporary file is copied over the output and synced instead.
+    #[anyhow_context]
+    pub fn commit(mut self) -> AssemblerResult<()> {
+        self.file.flush()?;
+        let Some(staged) = self.staged.take() else {
+            return Ok(());
+        };
+        self.file.get_ref().sync_all()?;
+        let result = match rename(&staged, &self.path) {
+            Err(error) if error.kind() == ErrorKind::CrossesDevices => copy(&staged, &self.path)
+                .and_then(|_| File::open(&self.path))
+                .and_then(|copied| copied.sync_all())
+                .and_then(|()| remove_file(&staged)),
+            result => result,
+        };
+        if result.is_err() {
+            let _ = remove_file(&staged);
+        }
+        result.map_err(Into::into)
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for OutputFile {
+    /// Throws away the temporary file of an output that was never committed.
+    fn drop(&mut self) {
+        if let Some(staged) = self.staged.take() {
+            let _ = remove_file(staged);
+        }
+    }
+}
+
+/// Writes the output file for `name` from its database, and one per additional
+/// language in `languages` below `<output_dir>/<language>/`.
+#[anyhow_context]
+pub async fn assemble_file(
+    name: &str,
+    config: &AssemblerConfig,
+    languages: &[String],
+) -> AssemblerResult<()> {
+    let encoding = Encoding::for_label(config.encoding.as_bytes())
+        .with_context(|| format!("Unknown output encoding `{}`", config.encoding))?;
+
+    with_db(name, |db| {
+        Box::pin(async move {
+            mark_stage_started(db.clone(), PipelineStage::Assembler).await?;
+            let outputs = [(None, config.output_dir.clone())].into_iter().chain(
+                languages
+                    .iter()
+                    .map(|lang| (Some(lang.as_str()), config.output_dir.join(lang))),
+            );
+            let streamed = count_segments(db.clone()).await? > config.stream_above;
+            for (lang, dir) in outputs {
+                profile::time_async(Phase::Assemble, async {
+                    let path = dir.join(name);
+                    if !streamed {
+                        let text = assemble(db.clone(), config, lang).await?;
+                        let (bom, atomic) = (config.write_bom, config.atomic_writes);
+                        return write_output(&path, &text, encoding, bom, atomic);
+                    }
+                    let mut output = OutputFile::create(&path, config.atomic_writes)?;
+                    assemble_streaming(db.clone(), &mut output, config, lang, encoding).await?;
+                    output.commit()
+                })
+                .await?;
+            }
+            mark_stage_done(db, PipelineStage::Assembler).await
+        })
+    })
+    .await
+}
+
+#[allow(unused)]
+pub async fn assembler_main(job: AssemblerJob, config: Data<Arc<Config>>) -> AnyResult<()> {
+    let assembled = async {
+        check_translations(&job.file_name, &config.analyzer).await?;
+        let languages = &config.translator.additional_languages;
+        assemble_file(&job.file_name, &config.assembler, languages).await
+    };
+    record_failure(&job.file_name, PipelineStage::Assembler, assembled.await).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::StorageConfig,
+        parser::parse_file,
+        storage::{WriteGate, count_segments, create_db_connection},
+        utils::test_dir,
+    };
+    use std::fs::{remove_dir_all, write};
+
+    /// Asserts that `content` assembles back to itself untranslated.
+    fn assert_round_trips(content: &str) {
+        let diff = round_trip_diff("test.sc", content, &ParserConfig::default()).unwrap();
+        assert_eq!(diff, None, "{}", diff.as_deref().unwrap_or_default());
+    }
+
+    #[test]
+    fn indentation_round_trips() {
+        assert_round_trips("  .bg 01\n\t.message 1 こんにちは\n\u{3000}; note  \n");
+    }
+
+    #[test]
+    fn full_width_message_ids_round_trip() {
+        assert_round_trips(".message １２３ こんにちは\n");
+    }
+
+    #[test]
+    fn trailing_comments_are_written_back_after_the_translation() {
+        let content =
+            ".message 1 こんにちは ;greeting\n.message 2 アリス 「ありがとう」\t; thanks\n";
+        let mut sink = VecSink::default();
+        parse_content(content, &ParserConfig::default(), &mut sink).unwrap();
+        for segment in &mut sink.segments {
+            if let TextSegment::IMessage(message) = segment {
+                message.content = format!("<{}>", message.content);
+            }
+        }
+        assert_eq!(
+            render_segments(&sink.segments),
+            ".message 1 <こんにちは> ;greeting\n.message 2 アリス 「<ありがとう>」\t; thanks\n"
+        );
+        assert_round_trips(content);
+    }
+
+    #[test]
+    fn blank_lines_round_trip() {
+        assert_round_trips(".message 1 こんにちは\n\n\n.bg 01\n\n.message 2 さようなら\n");
+    }
+
+    #[test]
+    fn translations_replace_only_the_name_and_content_of_the_source_line() {
+        let content = ".message  ０１２   アリス\t「こんにちは」\n";
+        let mut sink = VecSink::default();
+        parse_content(content, &ParserConfig::default(), &mut sink).unwrap();
+        let TextSegment::IMessage(message) = &mut sink.segments[0] else {
+            panic!("not a message: {:?}", sink.segments[0]);
+        };
+        assert_eq!(message.raw, content.trim_end());
+        let (start, end) = message.name_span.unwrap();
+        assert_eq!(&message.raw[start..end], "アリス");
+        let (start, end) = message.content_span.unwrap();
+        assert_eq!(&message.raw[start..end], "こんにちは");
+
+        message.name = "Alice".into();
+        message.content = "Hello".into();
+        assert_eq!(
+            render_segments(&sink.segments),
+            ".message  ０１２   Alice\t「Hello」\n"
+        );
+    }
+
+    #[test]
+    fn messages_quoted_over_several_lines_round_trip() {
+        assert_round_trips(".message 1 アリス 「一行目\n二行目\n三行目」\n.message 2 またね\n");
+    }
+
+    #[test]
+    fn messages_mixing_quoted_and_unquoted_parts_round_trip() {
+        assert_round_trips(".message 5 アリス 「中」 後\n.message 6 前 \"中; 続き\" 後 ;note\n");
+        let mut sink = VecSink::default();
+        parse_content(
+            ".message 5 アリス 「中」 後\n",
+            &ParserConfig::default(),
+            &mut sink,
+        )
+        .unwrap();
+        let TextSegment::IMessage(mut message) = sink.segments.remove(0) else {
+            panic!("not a message");
+        };
+        message.raw.clear();
+        message.content = "「Middle」 after".into();
+        assert_eq!(
+            render(&TextSegment::IMessage(message)),
+            ".message 5 アリス 「Middle」 after"
+        );
+    }
+
+    #[tokio::test]
+    async fn included_segments_are_written_as_their_directive() {
+        let dir = test_dir("assemble-includes");
+        let content = ".message 1 前\n#include b.sc\n.message 2 後\n";
+        write(dir.join("a.sc"), content).unwrap();
+        write(dir.join("b.sc"), ".message 1 中\n").unwrap();
+        let name = "assemble-includes/a.sc".to_string();
+        let db = create_db_connection(&name).await.unwrap();
+        let gate = WriteGate::new(&StorageConfig::default());
+        parse_file(dir.join("a.sc"), name, &ParserConfig::default(), &gate)
+            .await
+            .unwrap();
+
+        assert_eq!(count_segments(db.clone()).await.unwrap(), 4);
+        let assembled = assemble(db, &AssemblerConfig::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(assembled, content);
+        remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,543 @@
+use crate::{
+    analyzer::{analyze_file, check_translations, include_graph},
+    assembler::{assemble_file, round_trip_diff},
+    config::{Config, TranslatorConfig},
+    jobs::list_failures,
+    parser::{collect_sources, format_ast, parse_source, read_source},
+    storage::{
+        Line, PipelineStage, SEGMENTS_DB_NAME, TextSegment, WriteGate, cache_path, clear_localized,
+        clear_segments, clear_stage, clear_translations, clear_variables, create_db_connection,
+        database_path, list_file_meta, list_files, list_tachie, open_segments_db,
+        preload_cache_from, search_segments, set_file_meta, set_locked, slowest_segments,
+    },
+    translator::{
+        Translator, build_file_translators, build_localized_translators, build_translator,
+        context_providers, file_translator, fixup_file, fixup_rules, name_glossary, preview_sample,
+        skipped_language, stratified_sample, translate_file, translate_file_into,
+        translation_cache,
+    },
+};
+use anyhow::{Context, Result as AnyResult, bail};
+use auto_context::auto_context as anyhow_context;
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
+use sea_orm::Iterable;
+use std::{
+    collections::BTreeMap,
+    fs::read_to_string,
+    io::{Write, stdin, stdout},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+#[derive(Clone, Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Enqueue source files in a random order instead of by path.
+    #[arg(long)]
+    pub shuffle: bool,
+    /// Seed for `--shuffle`; a random one is picked and printed if omitted.
+    #[arg(long, requires = "shuffle")]
+    pub seed: Option<u64>,
+    /// Skip the files the last checkpoint recorded as done, and don't reparse
+    /// files that were already parsed. Requires `storage.database_dir`.
+    #[arg(long)]
+    pub resume: bool,
+    /// Only enqueue source files modified after `<SINCE>`: an RFC 3339 timestamp
+    /// such as `2024-08-05T12:00:00+09:00`, or `last` for the start of the
+    /// previous run. Requires `storage.database_dir`.
+    #[arg(long, value_parser = parse_since)]
+    pub since: Option<Since>,
+    /// Attempt sources again that the backend refused on an earlier run.
+    #[arg(long, global = true)]
+    pub retry_failed: bool,
+    /// Run only this stage (`parser`, `analyzer`, `translator` or `assembler`) on
+    /// the databases the source files already have. Requires `storage.database_dir`
+    /// unless it is `parser`.
+    #[arg(long, value_parser = parse_stage)]
+    pub only_stage: Option<PipelineStage>,
+    /// Stop after this long, e.g. `6h` or `1h30m`, letting the jobs in flight
+    /// finish and saving a checkpoint to continue from with `--resume`.
+    #[arg(long, value_parser = parse_duration)]
+    pub max_runtime: Option<Duration>,
+    /// Print the configuration in effect, with secrets redacted, and exit.
+    #[arg(long)]
+    pub dump_config: bool,
+    /// Print how the time was split between parsing, inserting, translating and
+    /// assembling at the end.
+    #[arg(long, global = true)]
+    pub profile: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Since {
+    LastRun,
+    Time(DateTime<Utc>),
+}
+
+fn parse_since(value: &str) -> Result<Since, String> {
+    if value == "last" {
+        return Ok(Since::LastRun);
+    }
+    DateTime::parse_from_rfc3339(value)
+        .map(|time| Since::Time(time.with_timezone(&Utc)))
+        .map_err(|error| format!("expected `last` or an RFC 3339 timestamp: {error}"))
+}
+
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("expected a duration like `6h` or `1h30m`, got `{value}`");
+    let (mut total, mut rest) = (0, value.trim());
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let amount = rest[..digits].parse::<u64>().map_err(|_| invalid())?;
+        let unit = rest[digits..].chars().next().ok_or_else(invalid)?;
+        let seconds = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        total += amount * seconds;
+        rest = &rest[digits + unit.len_utf8()..];
+    }
+    Ok(Duration::from_secs(total))
+}
+
+fn parse_key_value(value: &str) -> Result<(String, String), String> {
+    value
+        .split_once('=')
+        .map(|(key, value)| (key.trim().into(), value.into()))
+        .ok_or_else(|| format!("expected `key=value`, got `{value}`"))
+}
+
+fn parse_stage(value: &str) -> Result<PipelineStage, String> {
+    PipelineStage::iter()
+        .find(|stage| format!("{stage:?}").eq_ignore_ascii_case(value))
+        .ok_or_else(|| "expected parser, analyzer, translator or assembler".into())
+}
+
+/// Runs a single stage against the existing segment database instead of the whole
+/// pipeline. Requires `storage.database_dir`.
+#[derive(Clone, Debug, Subcommand)]
+pub enum Command {
+    /// Parse source files (names relative to `parser.source_dir`) into their databases.
+    Parse { files: Vec<String> },
+    /// Re-run the analyzer, and the checks of their translations, on already
+    /// parsed files.
+    Analyze { files: Vec<String> },
+    /// Translate the messages still missing a translation.
+    Translate {
+        files: Vec<String>,
+        /// Only translate messages enclosed in `;region=<REGION>` ... `;endregion`.
+        #[arg(long, conflicts_with = "sample")]
+        region: Option<String>,
+        /// Translate a sample of this many messages, spread across the files and
+        /// their speakers, to spot-check the configuration. Requires `--dry-run`.
+        #[arg(long, requires = "dry_run")]
+        sample: Option<usize>,
+        /// Print the translations instead of recording them.
+        #[arg(long, requires = "sample")]
+        dry_run: bool,
+    },
+    /// Translate a single text with the configured backend and print it, without
+    /// any files or databases.
+    TranslateText {
+        source: String,
+        /// Translate into this language instead of `translator.target_language`.
+        #[arg(long)]
+        target: Option<String>,
+    },
+    /// Write output files from the databases as they are.
+    Assemble { files: Vec<String> },
+    /// Apply the `translator.fixup_rules` to the existing translations, except
+    /// locked ones.
+    Fixup { files: Vec<String> },
+    /// Print the metadata of a file, or set keys of it with `--set key=value`.
+    Meta {
+        file: String,
+        #[arg(long, value_parser = parse_key_value)]
+        set: Vec<(String, String)>,
+    },
+    /// Parse and assemble every source file below `dir` (default `parser.source_dir`)
+    /// without translating, and show a diff of each file that does not round-trip.
+    Verify { dir: Option<PathBuf> },
+    /// Protect hand-reviewed messages from automated translation runs.
+    Lock {
+        file: String,
+        /// Source line numbers of the messages, e.g. `--lines 3,4,10`.
+        #[arg(long, value_delimiter = ',', required = true)]
+        lines: Vec<i32>,
+        /// Unlock the messages instead.
+        #[arg(long)]
+        unlock: bool,
+    },
+    /// Throw away translations (`--translations`) or everything (`--all`) of the
+    /// given files, asking for confirmation first.
+    Clean {
+        files: Vec<String>,
+        /// Drop the translations of every segment that isn't locked, keeping the
+        /// parsed segments.
+        #[arg(long, required_unless_present = "all", conflicts_with = "all")]
+        translations: bool,
+        /// Drop the segments themselves, locked ones included, and all stage state.
+        #[arg(long)]
+        all: bool,
+        /// Don't ask for confirmation.
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Triage the files of the last runs.
+    Failures {
+        #[command(subcommand)]
+        command: FailuresCommand,
+    },
+    /// Print the messages the backend took longest to translate.
+    Slowest {
+        files: Vec<String>,
+        /// How many messages to print per file.
+        #[arg(long, default_value_t = 10)]
+        count: u64,
+    },
+    /// Print every line of every file containing `query`, ignoring case and
+    /// full-width forms.
+    Search {
+        query: String,
+        /// Search the translations instead of the source.
+        #[arg(long)]
+        translated: bool,
+    },
+    /// Print every portrait (`tachie`) the messages of all files use, with how
+    /// many messages use it, to check against the art assets.
+    Tachie,
+    /// Print which files include which, one `file -> included` edge per line.
+    Includes { files: Vec<String> },
+    /// Print the parse tree of a file, for debugging the grammar.
+    Ast { file: PathBuf },
+    /// Serve the JSON API for review tools over the on-disk databases.
+    #[cfg(feature = "server")]
+    Serve {
+        #[arg(long, default_value = "127.0.0.1:3000")]
+        addr: String,
+    },
+    /// Seed the translation cache from a previous project's `translation_cache.db`.
+    PreloadCache { from: PathBuf },
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum FailuresCommand {
+    /// Every file that didn't finish all stages or has messages that failed to
+    /// translate, with the stage it stopped at and why.
+    List {
+        #[arg(long, value_enum, default_value_t = FailureOrder::Stage)]
+        sort: FailureOrder,
+        /// Print a JSON array instead, e.g. to re-run just these files.
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum FailureOrder {
+    /// By the stage the file stopped at, then by name.
+    Stage,
+    Name,
+}
+
+impl Command {
+    #[anyhow_context]
+    pub async fn run(self, config: Arc<Config>, retry_failed: bool) -> AnyResult<()> {
+        let gate = WriteGate::new(&config.storage);
+        match self {
+            Command::Parse { files } => {
+                for name in files {
+                    open_database(&name, false).await?;
+                    let path = config.parser.source_dir.join(&name);
+                    parse_source(path, name, &config.parser, &gate).await?;
+                }
+            }
+            Command::Analyze { files } => {
+                for name in files {
+                    open_database(&name, true).await?;
+                    analyze_file(&name, &config.analyzer).await?;
+                    check_translations(&name, &config.analyzer).await?;
+                }
+            }
+            Command::Translate {
+                files,
+                sample: Some(size),
+                ..
+            } => {
+                let translator = build_translator(&config.translator, retry_failed).await?;
+                for name in &files {
+                    open_database(name, true).await?;
+                }
+                let sample = stratified_sample(&files, size).await?;
+                let examples = &config.translator.few_shot;
+                preview_sample(&sample, translator.as_ref(), examples).await?;
+            }
+            Command::TranslateText { source, target } => {
+                let config = TranslatorConfig {
+                    target_language: target
+                        .unwrap_or_else(|| config.translator.target_language.clone()),
+                    ..config.translator.clone()
+                };
+                let translator = build_translator(&config, retry_failed).await?;
+                println!("{}", translator.translate(&source).await?);
+            }
+            Command::Translate { files, region, .. } => {
+                let translator = build_translator(&config.translator, retry_failed).await?;
+                let overrides = build_file_translators(&config.translator, retry_failed).await?;
+                let localized =
+                    build_localized_translators(&config.translator, retry_failed).await?;
+                let cache = translation_cache(&config.translator).await?;
+                let glossary = name_glossary(&config.glossary).await?;
+                let context = context_providers(&config.translator, &cache);
+                let skipped = skipped_language(&config.translator)?;
+                for name in files {
+                    open_database(&name, true).await?;
+                    let (translator, cache) = match file_translator(&overrides, &name) {
+                        Some(translator) => (translator, None),
+                        None => (translator.clone(), cache.clone()),
+                    };
+                    translate_file(
+                        &name,
+                        translator.as_ref(),
+                        &gate,
+                        cache,
+                        glossary.clone(),
+                        &context,
+                        &config.translator.few_shot,
+                        skipped,
+                        config.translator.min_source_chars,
+                        region.as_deref(),
+                        "translate",
+                    )
+                    .await?;
+                    for (lang, translator) in localized.iter() {
+                        let region = region.as_deref();
+                        translate_file_into(&name, lang, translator.as_ref(), &gate, region)
+                            .await?;
+                    }
+                }
+            }
+            Command::Fixup { files } => {
+                let rules = fixup_rules(&config.translator)?;
+                if rules.is_empty() {
+                    bail!("`translator.fixup_rules` is not set or has no rules");
+                }
+                for name in files {
+                    open_database(&name, true).await?;
+                    let changed = fixup_file(&name, &rules, &gate, "fixup").await?;
+                    println!("Fixed up {changed} translation(s) of `{name}`");
+                }
+            }
+            Command::Meta { file, set } => {
+                open_database(&file, true).await?;
+                let db = create_db_connection(&file).await?;
+                if set.is_empty() {
+                    for (key, value) in list_file_meta(db).await? {
+                        println!("{key} = {value}");
+                    }
+                } else {
+                    for (key, value) in &set {
+                        gate.write(|| set_file_meta(db.clone(), key, value)).await?;
+                    }
+                    println!("Set {} key(s) of `{file}`", set.len());
+                }
+            }
+            Command::Verify { dir } => {
+                let dir = dir.unwrap_or_else(|| config.parser.source_dir.clone());
+                let sources = collect_sources(&dir)?;
+                let mut failed = 0;
+                for entry in &sources {
+                    let name = entry.path().display().to_string();
+                    let content = read_source(entry.path(), &name, &config.parser)?;
+                    match round_trip_diff(&name, &content, &config.parser) {
+                        Ok(None) => {}
+                        Ok(Some(diff)) => {
+                            failed += 1;
+                            print!("{diff}");
+                        }
+                        Err(error) => {
+                            failed += 1;
+                            println!("{name}: {error:#}");
+                        }
+                    }
+                }
+                if failed > 0 {
+                    bail!("{failed} of {} file(s) do not round-trip", sources.len());
+                }
+                println!("All {} file(s) round-trip", sources.len());
+            }
+            Command::Lock {
+                file,
+                lines,
+                unlock,
+            } => {
+                open_database(&file, true).await?;
+                let lines = lines.into_iter().map(Line).collect::<Vec<_>>();
+                let changed =
+                    set_locked(create_db_connection(&file).await?, &lines, !unlock).await?;
+                let action = if unlock { "Unlocked" } else { "Locked" };
+                println!("{action} {changed} segment(s) of `{file}`");
+            }
+            Command::Clean {
+                files,
+                translations,
+                all: _,
+                yes,
+            } => {
+                let what = if translations {
+                    "the unlocked translations"
+                } else {
+                    "all segments and stage state"
+                };
+                if !yes && !confirm(&format!("Discard {what} of {} file(s)?", files.len()))? {
+                    bail!("Aborted, nothing was discarded");
+                }
+                for name in files {
+                    open_database(&name, true).await?;
+                    let db = create_db_connection(&name).await?;
+                    clear_localized(db.clone(), translations).await?;
+                    let cleared = if translations {
+                        clear_translations(db.clone()).await?
+                    } else {
+                        clear_variables(db.clone()).await?;
+                        clear_segments(db.clone()).await?
+                    };
+                    let stages = if translations {
+                        vec![PipelineStage::Translator, PipelineStage::Assembler]
+                    } else {
+                        PipelineStage::iter().collect()
+                    };
+                    for stage in stages {
+                        clear_stage(db.clone(), stage).await?;
+                    }
+                    println!("Cleared {cleared} segment(s) of `{name}`");
+                }
+            }
+            Command::Failures {
+                command: FailuresCommand::List { sort, json },
+            } => {
+                let files = list_files(open_segments_db().await?).await?;
+                let mut failures = list_failures(&files).await?;
+                match sort {
+                    FailureOrder::Stage => failures
+                        .sort_by(|a, b| (a.stage as i32, &a.name).cmp(&(b.stage as i32, &b.name))),
+                    FailureOrder::Name => failures.sort_by(|a, b| a.name.cmp(&b.name)),
+                }
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&failures)?);
+                } else if failures.is_empty() {
+                    println!("Every file finished all stages");
+                } else {
+                    for failure in &failures {
+                        println!("{}: {:?}, {}", failure.name, failure.stage, failure.reason);
+                    }
+                }
+            }
+            Command::Slowest { files, count } => {
+                for name in files {
+                    open_database(&name, true).await?;
+                    let db = create_db_connection(&name).await?;
+                    for (message, millis) in slowest_segments(db, count).await? {
+                        println!(
+                            "[{name}] line {}: {millis} ms, {}",
+                            message.line, message.content
+                        );
+                    }
+                }
+            }
+            Command::Search { query, translated } => {
+                for name in list_files(open_segments_db().await?).await? {
+                    let db = create_db_connection(&name).await?;
+                    for (model, translation) in search_segments(db, &query, translated).await? {
+                        let segment = TextSegment::try_from(model)?;
+                        let content = match translation {
+                            Some(translation) if translated => translation.content,
+                            _ => segment.content().to_owned(),
+                        };
+                        println!("{name}:{}: {content}", segment.line());
+                    }
+                }
+            }
+            Command::Tachie => {
+                let mut usage = BTreeMap::<String, i64>::new();
+                for name in list_files(open_segments_db().await?).await? {
+                    let db = create_db_connection(&name).await?;
+                    for (tachie, count) in list_tachie(db).await? {
+                        *usage.entry(tachie).or_default() += count;
+                    }
+                }
+                for (tachie, count) in usage {
+                    println!("{tachie}: {count}");
+                }
+            }
+            Command::Includes { files } => {
+                for name in files {
+                    open_database(&name, true).await?;
+                    let db = create_db_connection(&name).await?;
+                    for (source, include) in include_graph(db).await? {
+                        println!("{source} -> {include}");
+                    }
+                }
+            }
+            Command::Ast { file } => print!("{}", format_ast(&read_to_string(file)?)?),
+            #[cfg(feature = "server")]
+            Command::Serve { addr } => crate::server::serve(&addr, config).await?,
+            Command::PreloadCache { from } => {
+                if cache_path().is_none() {
+                    bail!(
+                        "Preloading the cache requires `storage.database_dir` or \
+                         `storage.translation_cache` to be set"
+                    );
+                }
+                let added = preload_cache_from(&from).await?;
+                println!(
+                    "Preloaded {added} cached translation(s) from `{}`",
+                    from.display()
+                );
+            }
+            Command::Assemble { files } => {
+                for name in files {
+                    open_database(&name, true).await?;
+                    let languages = &config.translator.additional_languages;
+                    assemble_file(&name, &config.assembler, languages).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Asks `question` on stdin, true if the answer is `y` or `yes`.
+#[anyhow_context]
+fn confirm(question: &str) -> AnyResult<bool> {
+    print!("{question} [y/N] ");
+    stdout().flush()?;
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Opens the on-disk segment database, which must hold `name` if `existing`.
+#[anyhow_context]
+async fn open_database(name: &str, existing: bool) -> AnyResult<()> {
+    let Some(path) = database_path(SEGMENTS_DB_NAME) else {
+        bail!("Running a single stage requires `storage.database_dir` to be set");
+    };
+    let db = open_segments_db().await?;
+    if existing && !list_files(db).await?.iter().any(|file| file == name) {
+        bail!("No segments of `{name}` in `{}`", path.display());
+    }
+    Ok(())
+}
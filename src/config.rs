@@ -0,0 +1,533 @@
+use anyhow::{Context, Result as AnyResult, bail};
+use auto_context::auto_context as anyhow_context;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
+
+use crate::utils::{CJ_CHARACTERS, glob_matches};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub parser: ParserConfig,
+    pub translator: TranslatorConfig,
+    pub assembler: AssemblerConfig,
+    pub glossary: GlossaryConfig,
+    pub analyzer: AnalyzerConfig,
+    pub dispatcher: DispatcherConfig,
+    pub storage: StorageConfig,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ParserConfig {
+    /// Every file below this directory is parsed.
+    pub source_dir: PathBuf,
+    /// Any WHATWG encoding label, e.g. `Shift_JIS`, to read every source file
+    /// with. Detected per file if omitted, see `detect_encoding`.
+    pub encoding: Option<String>,
+    /// Fail files that don't decode cleanly in their encoding instead of
+    /// replacing the bytes that don't decode.
+    pub strict_decoding: bool,
+    /// Parse each Musica file in this many parallel chunks, see `MusicaFileParser`.
+    pub chunks: Option<usize>,
+    /// The script format of files by extension (without the dot). Files with an
+    /// extension not listed here are parsed as Musica.
+    pub formats: BTreeMap<String, ScriptFormat>,
+    /// Characters splitting a tachie into portrait and variant, e.g. the `@` in
+    /// `abc-01-02@smile`. Leave empty to keep tachie ids whole.
+    pub tachie_delimiters: String,
+    /// Store each run of consecutive non-message lines (e.g. a comment block) as
+    /// one segment instead of one per line.
+    pub merge_non_messages: bool,
+    /// Print every top-level rule that produced no segment, with its line and
+    /// the text it matched, to find input the grammar silently drops. Such rules
+    /// are kept as verbatim non-messages either way.
+    pub warn_on_empty_rules: bool,
+    /// How many files are parsed at once.
+    pub workers: usize,
+    /// How many segments are inserted per statement.
+    pub insert_batch_size: usize,
+    /// Leave out the lines of a file that don't parse, reporting each, instead
+    /// of failing the whole file on the first.
+    pub lenient: bool,
+    /// Splice the segments of each `#include`d file in after the directive, to
+    /// analyze and translate the file as the engine runs it. Only the directive
+    /// is written back: the included file, below `source_dir` too, is written
+    /// from its own segments.
+    pub expand_includes: bool,
+}
+
+impl Default for ParserConfig {
+    fn default() -> Self {
+        ParserConfig {
+            source_dir: "./assets/sc".into(),
+            encoding: None,
+            strict_decoding: false,
+            chunks: None,
+            formats: BTreeMap::from([("sc".into(), ScriptFormat::Musica)]),
+            tachie_delimiters: "@".into(),
+            merge_non_messages: false,
+            warn_on_empty_rules: false,
+            workers: 4,
+            insert_batch_size: 500,
+            lenient: false,
+            expand_includes: true,
+        }
+    }
+}
+
+/// A format `parser.formats` can route files to, see `ParserRegistry`.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptFormat {
+    /// Scripts of the Musica (Minori) engine.
+    #[default]
+    Musica,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TranslatorConfig {
+    pub api_base: String,
+    pub api_key: String,
+    pub model: String,
+    pub target_language: String,
+    /// More languages to translate every message into, each assembled into
+    /// `<output_dir>/<language>/`. The cache, glossary, context window and
+    /// few-shot examples only apply to `target_language`.
+    pub additional_languages: Vec<String>,
+    /// Sources not matching this regex are passed through untranslated.
+    /// The default requires at least one CJ character; an empty regex matches everything.
+    pub translate_if: String,
+    /// Literals such as brand names or UI tokens that are kept as they are: a
+    /// source that is one of them is never sent to the backend, and they are
+    /// masked out of the sources they appear in.
+    pub do_not_translate: Vec<String>,
+    /// Ask for and enforce the same number of `\n` breaks as the source, for
+    /// engines whose text boxes are laid out by hand.
+    pub preserve_line_breaks: bool,
+    /// Reuse the translation of identical sources across all files, see
+    /// `preload_cache_from` to seed it from a previous project.
+    pub cache: bool,
+    /// Width of a text box line in half-width columns, full-width characters
+    /// counting as two.
+    pub max_width: Option<usize>,
+    /// How often to ask for a more concise translation while one is wider than
+    /// `max_width`, keeping the narrowest.
+    pub max_length_retries: usize,
+    /// Longest source, in characters, sent to the backend in one request.
+    /// Longer ones are split or skipped, see `split_long_inputs`.
+    pub max_input_chars: Option<usize>,
+    /// Translate sources longer than `max_input_chars` in chunks of whole lines
+    /// instead of skipping them as too long.
+    pub split_long_inputs: bool,
+    /// How many lines or sentences (see `context_unit`) before a message the
+    /// backend is shown for context, 0 to translate every message on its own.
+    pub context_size: usize,
+    pub context_unit: ContextUnit,
+    /// Tell the backend the `;region=` scene of a message, with its summary from
+    /// the `scene.<region>` file metadata if there is one.
+    pub scene_context: bool,
+    /// Show the backend the cached translation of the most similar source when
+    /// it is at least this similar, from 0 to 1, e.g. `0.75`. Requires `cache`.
+    pub fuzzy_match_threshold: Option<f32>,
+    /// Keep messages detected as already being in `target_language` (e.g. lines
+    /// copied from an official partial translation) as they are, with a note.
+    pub skip_target_language: bool,
+    /// Keep messages shorter than this many characters, whitespace aside, as
+    /// they are instead of sending them to the backend, e.g. `...` or `？`.
+    /// Characters are counted, so a single kanji counts as one: short words
+    /// like `猫` are kept untranslated too unless this stays low. 0 to
+    /// translate everything.
+    pub min_source_chars: usize,
+    /// Strip what backends wrap translations in: quotes and markdown fences
+    /// around the whole translation, and `chatter_patterns`.
+    pub sanitize_output: bool,
+    /// Regexes removed from every translation when `sanitize_output` is set,
+    /// e.g. preambles like `Sure, here's the translation:`. Provider specific,
+    /// anchor them with `^` so they cannot match inside the translation.
+    pub chatter_patterns: Vec<String>,
+    /// A TOML file of `[[rule]]` tables, each a `pattern` regex and the
+    /// `replacement` for it, applied to every translation for house style.
+    pub fixup_rules: Option<PathBuf>,
+    /// Example translations shown to the backend before the source, to teach
+    /// it the voice of the translation.
+    pub few_shot: FewShotExamples,
+    /// Appended to the system prompt, e.g. on the tone or setting of the script.
+    pub instructions: String,
+    /// Other settings for the files whose name matches a glob, e.g. songs
+    /// translated by another model or with other `instructions`. Only the most
+    /// specific matching override applies: the one with the most characters
+    /// that aren't wildcards, the first listed on a tie. The settings it leaves
+    /// unset are the ones above. Files with an override skip the translation
+    /// cache, which is shared by every file.
+    pub file_overrides: Vec<FileOverride>,
+    /// How many files are translated at once, each sending one request at a time.
+    pub workers: usize,
+}
+
+/// Settings of `translator.file_overrides`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FileOverride {
+    /// Glob over file names, `*` matching any run of characters and `?` any
+    /// one character, e.g. `song_*.txt`.
+    pub files: String,
+    pub api_base: Option<String>,
+    pub model: Option<String>,
+    pub target_language: Option<String>,
+    pub instructions: Option<String>,
+}
+
+impl FileOverride {
+    pub fn matches(&self, name: &str) -> bool {
+        glob_matches(&self.files, name)
+    }
+
+    /// How many characters of the glob aren't wildcards.
+    pub fn specificity(&self) -> usize {
+        self.files
+            .chars()
+            .filter(|ch| !matches!(ch, '*' | '?'))
+            .count()
+    }
+
+    /// `config` with the settings of this override over it.
+    pub fn apply(&self, config: &TranslatorConfig) -> TranslatorConfig {
+        let mut config = config.clone();
+        if let Some(api_base) = &self.api_base {
+            config.api_base = api_base.clone();
+        }
+        if let Some(model) = &self.model {
+            config.model = model.clone();
+        }
+        if let Some(target_language) = &self.target_language {
+            config.target_language = target_language.clone();
+        }
+        if let Some(instructions) = &self.instructions {
+            config.instructions = instructions.clone();
+        }
+        config
+    }
+}
+
+/// Source to target example pairs for `translator.few_shot`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FewShotExamples {
+    /// Shown for every message.
+    pub global: Vec<FewShotExample>,
+    /// Shown for the messages of a speaker, by source name, after `global`.
+    pub speakers: BTreeMap<String, Vec<FewShotExample>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FewShotExample {
+    pub source: String,
+    pub target: String,
+}
+
+impl FewShotExamples {
+    /// The examples for a message of `speaker`, empty for narration.
+    pub fn for_speaker(&self, speaker: &str) -> Vec<FewShotExample> {
+        let mut examples = self.global.clone();
+        if let Some(voiced) = self.speakers.get(speaker) {
+            examples.extend(voiced.iter().cloned());
+        }
+        examples
+    }
+}
+
+/// What `translator.context_size` counts.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextUnit {
+    /// Whole messages, for dialogue.
+    #[default]
+    Lines,
+    /// Sentences, for narration whose lines are long paragraphs.
+    Sentences,
+}
+
+impl Default for TranslatorConfig {
+    fn default() -> Self {
+        TranslatorConfig {
+            api_base: "https://api.openai.com/v1".into(),
+            api_key: String::new(),
+            model: "gpt-4o-mini".into(),
+            target_language: "English".into(),
+            additional_languages: Vec::new(),
+            translate_if: CJ_CHARACTERS.into(),
+            do_not_translate: Vec::new(),
+            preserve_line_breaks: false,
+            cache: true,
+            max_width: None,
+            max_length_retries: 0,
+            max_input_chars: None,
+            split_long_inputs: true,
+            context_size: 0,
+            context_unit: ContextUnit::Lines,
+            scene_context: false,
+            fuzzy_match_threshold: None,
+            skip_target_language: false,
+            min_source_chars: 0,
+            sanitize_output: true,
+            chatter_patterns: vec![
+                r"(?i)^(sure|certainly|of course|okay)\b[^\n]*?translat[^\n]*?:\s*".into(),
+                r"(?i)^here(?:'s| is) (?:the |your |my )?translation[^\n]*?:\s*".into(),
+                r"(?i)^translation:\s*".into(),
+            ],
+            fixup_rules: None,
+            few_shot: FewShotExamples::default(),
+            instructions: String::new(),
+            file_overrides: Vec::new(),
+            workers: 4,
+        }
+    }
+}
+
+impl TranslatorConfig {
+    /// The language the file `name` is translated into: that of the most
+    /// specific override matching it, like `file_translator` picks, if it sets one.
+    pub fn target_language_of(&self, name: &str) -> &str {
+        let mut best: Option<&FileOverride> = None;
+        for candidate in self.file_overrides.iter().filter(|o| o.matches(name)) {
+            if best.is_none_or(|best| candidate.specificity() > best.specificity()) {
+                best = Some(candidate);
+            }
+        }
+        best.and_then(|best| best.target_language.as_deref())
+            .unwrap_or(&self.target_language)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AssemblerConfig {
+    pub output_dir: PathBuf,
+    /// Any WHATWG encoding label, e.g. `UTF-8` or `Shift_JIS`.
+    pub encoding: String,
+    /// Prefix the output with a BOM, only valid for UTF-8.
+    pub write_bom: bool,
+    /// Emit the glossary name of speakers that have one instead of the source name.
+    pub translated_names: bool,
+    /// Precede each translated message with a `;;` comment naming the backend
+    /// that translated it and when, and whether it is locked after review.
+    pub provenance_comments: bool,
+    /// Write each output to a temporary file next to it first and rename that
+    /// over the previous output, so an interrupted run never leaves it truncated.
+    pub atomic_writes: bool,
+    /// Assemble files with more segments than this a page at a time, writing
+    /// each segment as it is read instead of rendering the whole file first.
+    pub stream_above: u64,
+    /// How many files are assembled at once.
+    pub workers: usize,
+}
+
+impl Default for AssemblerConfig {
+    fn default() -> Self {
+        AssemblerConfig {
+            output_dir: "./output".into(),
+            encoding: "UTF-8".into(),
+            write_bom: false,
+            translated_names: true,
+            provenance_comments: false,
+            atomic_writes: true,
+            stream_above: 50_000,
+            workers: 2,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GlossaryConfig {
+    /// Translate speaker names too. Each name is translated once and kept in the
+    /// project glossary, which every later occurrence takes its name from.
+    pub translate_names: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AnalyzerConfig {
+    /// Regexes matching engine variables that must survive translation verbatim.
+    pub variable_patterns: Vec<String>,
+    /// How many files are analyzed at once.
+    pub workers: usize,
+    /// Translate a file only once the analyzer finished it, instead of both
+    /// running at once, so that its reports come before any translation work.
+    pub before_translation: bool,
+}
+
+impl Default for AnalyzerConfig {
+    fn default() -> Self {
+        AnalyzerConfig {
+            variable_patterns: vec![
+                r"%[A-Za-z_][A-Za-z0-9_]*%".into(),
+                r"\\[A-Za-z]+\[\d+\]".into(),
+            ],
+            workers: 2,
+            before_translation: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DispatcherConfig {
+    /// How many parsed files are handed on to the later stages at once.
+    pub workers: usize,
+}
+
+impl Default for DispatcherConfig {
+    fn default() -> Self {
+        DispatcherConfig { workers: 2 }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// Keep the databases here instead of in memory, so that single stages
+    /// can be re-run against them later.
+    pub database_dir: Option<PathBuf>,
+    /// Where the translation cache shared by every file lives, e.g. to share it
+    /// between projects. Defaults to `translation_cache.db` in `database_dir`.
+    pub translation_cache: Option<PathBuf>,
+    /// Where the job queues live, so that jobs queued but not yet processed
+    /// survive a restart. Kept in memory if unset, apart from `database_dir`.
+    pub job_storage: Option<PathBuf>,
+    /// Seconds between progress checkpoints of on-disk runs, see `--resume`.
+    pub checkpoint_interval: u64,
+    /// How many segment writes may run at once across all workers. SQLite
+    /// serializes writes anyway, more writers only run into `SQLITE_BUSY`.
+    pub max_concurrent_writes: usize,
+    /// How often a write failing with `SQLITE_BUSY` or `SQLITE_LOCKED` is retried,
+    /// with backoff, see `retry_on_busy`.
+    pub busy_retries: u32,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            database_dir: None,
+            translation_cache: None,
+            job_storage: None,
+            checkpoint_interval: 10,
+            max_concurrent_writes: 1,
+            busy_retries: 5,
+        }
+    }
+}
+
+/// Environment variables [`Config::load`] applies over the file, with the
+/// setting each one overrides.
+const ENV_OVERRIDES: &[(&str, &str)] = &[
+    ("OPENAI_API_BASE", "translator.api_base"),
+    ("OPENAI_API_KEY", "translator.api_key"),
+];
+
+/// Shown by [`Config::dump`] instead of secrets.
+const REDACTED: &str = "<redacted>";
+
+/// The file [`Config::load`] reads: `$MUSICA_CONFIG`, default `musica.toml`.
+fn config_path() -> String {
+    env::var("MUSICA_CONFIG").unwrap_or_else(|_| "musica.toml".into())
+}
+
+impl Config {
+    /// Reads `$MUSICA_CONFIG` (default `musica.toml`) if it exists, then applies
+    /// overrides from the environment and `.env`.
+    #[anyhow_context]
+    pub fn load() -> AnyResult<Self> {
+        dotenv::dotenv().ok();
+
+        let path = config_path();
+        let mut config: Config = if Path::new(&path).exists() {
+            toml::from_str(&read_to_string(&path)?)?
+        } else {
+            Config::default()
+        };
+
+        if let Ok(api_base) = env::var("OPENAI_API_BASE") {
+            config.translator.api_base = api_base;
+        }
+        if let Ok(api_key) = env::var("OPENAI_API_KEY") {
+            config.translator.api_key = api_key;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Fails on settings no run could work with, e.g. a stage without workers,
+    /// which would leave its queue filling up forever.
+    pub fn validate(&self) -> AnyResult<()> {
+        for (setting, workers) in [
+            ("parser.workers", self.parser.workers),
+            ("dispatcher.workers", self.dispatcher.workers),
+            ("analyzer.workers", self.analyzer.workers),
+            ("translator.workers", self.translator.workers),
+            ("assembler.workers", self.assembler.workers),
+        ] {
+            if workers == 0 {
+                bail!("`{setting}` must be at least 1");
+            }
+        }
+        Ok(())
+    }
+
+    /// The configuration as TOML with secrets redacted, preceded by comments on
+    /// where it was loaded from, see `--dump-config`.
+    #[anyhow_context]
+    pub fn dump(&self) -> AnyResult<String> {
+        let mut redacted = self.clone();
+        if !redacted.translator.api_key.is_empty() {
+            redacted.translator.api_key = REDACTED.into();
+        }
+
+        let path = config_path();
+        let mut dump = if Path::new(&path).exists() {
+            format!("# Read from `{path}`, defaults for everything it leaves out\n")
+        } else {
+            format!("# `{path}` not found, defaults for everything\n")
+        };
+        for (var, setting) in ENV_OVERRIDES {
+            if env::var(var).is_ok() {
+                dump.push_str(&format!("# `{setting}` overridden by ${var}\n"));
+            }
+        }
+        dump.push('\n');
+        dump.push_str(&toml::to_string_pretty(&redacted)?);
+        Ok(dump)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_accepts_the_defaults() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_a_stage_without_workers() {
+        let mut config = Config::default();
+        config.dispatcher.workers = 0;
+        let error = config.validate().unwrap_err();
+        assert_eq!(error.to_string(), "`dispatcher.workers` must be at least 1");
+    }
+
+    #[test]
+    fn dispatcher_workers_are_read_from_the_file() {
+        let config: Config = toml::from_str("[dispatcher]\nworkers = 5\n").unwrap();
+        assert_eq!(config.dispatcher.workers, 5);
+        assert_eq!(config.parser.workers, ParserConfig::default().workers);
+    }
+}
@@ -1,14 +1,27 @@
-use anyhow::Result as AnyResult;
+use anyhow::{Context, Result as AnyResult, bail};
 use apalis::prelude::{Data, Storage};
-use apalis_sql::sqlite::SqliteStorage;
-use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use apalis_sql::sqlite::{SqlitePool, SqliteStorage};
+use auto_context::auto_context as anyhow_context;
+use chrono::{DateTime, Utc};
+use sea_orm::Iterable;
 use serde::{Deserialize, Serialize};
-use std::{path::PathBuf, sync::Arc};
-use tokio::sync::RwLock;
+use std::{
+    any::type_name,
+    collections::{BTreeMap, BTreeSet},
+    fs::{create_dir_all, write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{sync::RwLock, time::interval};
 
-use crate::storage::{
-    TextSegmentColumn, TextSegmentEntity,
-    text_segment::{TextSegmentType, create_db_connection},
+use crate::{
+    config::Config,
+    storage::{
+        PipelineStage, count_failed, count_messages, count_segments, count_translated,
+        create_db_connection, flush_wal, is_stage_done, list_file_meta, mark_stage_failed,
+        open_project_db, open_segments_db, save_checkpoint, stage_states, translation_backends,
+    },
 };
 
 pub trait Job {
@@ -75,30 +88,363 @@ impl Job for DispatchJob {
 
 pub type DispatchJobQueue = SqliteStorage<DispatchJob>;
 
+/// Whether a job of type `T` for `file_path` is pending or running in the queues
+/// of `pool`, so that seeding persisted queues again doesn't process a file
+/// twice. Failed jobs are never picked up again, see [`record_failure`], so
+/// their files are queued anew.
+///
+/// Looks the job up in apalis' `Jobs` table, under the `type_name` namespace that
+/// `SqliteStorage::new` files jobs of `T` under.
+#[anyhow_context]
+pub async fn job_exists<T>(pool: &SqlitePool, file_path: &Path) -> AnyResult<bool> {
+    let found = sqlx::query(
+        "SELECT 1 FROM Jobs WHERE job_type = ? AND json_extract(job, '$.file_path') = ? \
+         AND status IN ('Pending', 'Running') \
+         LIMIT 1",
+    )
+    .bind(type_name::<T>())
+    .bind(file_path.to_string_lossy())
+    .fetch_optional(pool)
+    .await?;
+    Ok(found.is_some())
+}
+
+/// Connects to the job queues, in `path` so that queued jobs survive restarts,
+/// see `storage.job_storage`, or in memory, and sets their tables up.
+#[anyhow_context]
+pub async fn open_job_storage(path: Option<&Path>) -> AnyResult<SqlitePool> {
+    let url = match path {
+        Some(path) => format!("sqlite://{}?mode=rwc", path.display()),
+        None => "sqlite::memory:".to_string(),
+    };
+    let pool = SqlitePool::connect(&url).await?;
+    SqliteStorage::setup(&pool).await?;
+    Ok(pool)
+}
+
+/// A worker as registered with the monitor: the queue it consumes, by job name,
+/// and the queues it is handed as `Data` to push to.
+#[derive(Clone, Copy, Debug)]
+pub struct StageWiring {
+    pub consumes: &'static str,
+    pub feeds: &'static [&'static str],
+}
+
+/// Fails loudly on a stage that would never run or never be handed on: one whose
+/// queue neither `seeded` nor any stage reachable from it pushes to, or one that
+/// pushes to a queue no stage consumes.
+#[anyhow_context]
+pub fn validate_wiring(stages: &[StageWiring], seeded: &[&'static str]) -> AnyResult<()> {
+    let consumed = stages
+        .iter()
+        .map(|stage| stage.consumes)
+        .collect::<BTreeSet<_>>();
+    for stage in stages {
+        if let Some(queue) = stage.feeds.iter().find(|queue| !consumed.contains(*queue)) {
+            bail!(
+                "Stage `{}` pushes to `{queue}`, which no stage consumes",
+                stage.consumes
+            );
+        }
+    }
+
+    let mut reached = seeded.iter().copied().collect::<BTreeSet<_>>();
+    loop {
+        let before = reached.len();
+        for stage in stages {
+            if reached.contains(stage.consumes) {
+                reached.extend(stage.feeds);
+            }
+        }
+        if reached.len() == before {
+            break;
+        }
+    }
+    if let Some(orphan) = stages
+        .iter()
+        .find(|stage| !reached.contains(stage.consumes))
+    {
+        bail!(
+            "Stage `{}` is never enqueued: nothing seeded or upstream pushes to it",
+            orphan.consumes
+        );
+    }
+    Ok(())
+}
+
+/// Stages that must have finished before a file may be translated.
+///
+/// The translator is enqueued by the last of these stages, or right away by
+/// `dispatch_main` when there are none.
+pub fn translator_dependencies(config: &Config) -> &'static [PipelineStage] {
+    if config.analyzer.before_translation {
+        &[PipelineStage::Analyzer]
+    } else {
+        &[]
+    }
+}
+
+/// Records `result` of running `stage` on the file `name` as the stage failing
+/// if it is an error, see [`mark_stage_failed`], and passes it on. Failed jobs
+/// are never run again, the run would otherwise wait for the file forever.
+pub async fn record_failure<T>(
+    name: &str,
+    stage: PipelineStage,
+    result: AnyResult<T>,
+) -> AnyResult<T> {
+    if let Err(error) = &result {
+        eprintln!("[{name}] {stage:?} failed: {error:#}");
+        let db = create_db_connection(name).await?;
+        mark_stage_failed(db, stage, &format!("{error:#}")).await?;
+    }
+    result
+}
+
 pub async fn dispatch_main(
     job: DispatchJob,
     analyzer: Data<Arc<RwLock<AnalyzerJobQueue>>>,
     translator: Data<Arc<RwLock<TranslatorJobQueue>>>,
+    config: Data<Arc<Config>>,
 ) -> AnyResult<()> {
     let (path, name) = (job.file_path, job.file_name);
 
-    {
-        let mut analyzer = analyzer.write().await;
-        analyzer
-            .push(AnalyzerJob {
-                file_name: name.clone(),
-                file_path: path.clone(),
-            })
-            .await?;
+    let dispatched = async {
+        {
+            let mut analyzer = analyzer.write().await;
+            analyzer
+                .push(AnalyzerJob {
+                    file_name: name.clone(),
+                    file_path: path.clone(),
+                })
+                .await?;
+        }
+        if translator_dependencies(&config).is_empty() {
+            let mut translator = translator.write().await;
+            translator
+                .push(TranslatorJob {
+                    file_name: name.clone(),
+                    file_path: path,
+                })
+                .await?;
+        }
+        AnyResult::Ok(())
+    };
+    // Handing the file on is the first thing its analysis needs.
+    record_failure(&name, PipelineStage::Analyzer, dispatched.await).await
+}
+
+/// Every `every`, saves a checkpoint of `files`, see [`checkpoint`], until the
+/// future is dropped.
+pub async fn run_checkpoints(files: Vec<String>, every: Duration) -> AnyResult<()> {
+    let mut ticks = interval(every);
+    loop {
+        ticks.tick().await;
+        checkpoint(&files).await?;
     }
-    {
-        let mut translator = translator.write().await;
-        translator
-            .push(TranslatorJob {
-                file_name: name,
-                file_path: path,
+}
+
+/// Flushes the WAL of the segment database and records which files are done and
+/// how many segments are translated, for `--resume`.
+#[anyhow_context]
+pub async fn checkpoint(files: &[String]) -> AnyResult<()> {
+    let project = open_project_db().await?;
+    flush_wal(open_segments_db().await?).await?;
+    let (mut completed, mut translated) = (Vec::new(), 0);
+    for name in files {
+        let db = create_db_connection(name).await?;
+        translated += count_translated(db.clone()).await? as i64;
+        if is_stage_done(db, PipelineStage::Assembler).await? {
+            completed.push(name.clone());
+        }
+    }
+    save_checkpoint(project, &completed, translated).await
+}
+
+/// What a run did to every file, written to `manifest.json` in the output directory.
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub files: Vec<FileManifest>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileManifest {
+    pub name: String,
+    pub segments: u64,
+    pub messages: u64,
+    pub translated: u64,
+    /// Messages left without a translation: refused, locked or failed.
+    pub untranslated: u64,
+    pub backends: Vec<String>,
+    /// Set from the sidecar of the source or with the `meta` subcommand.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub meta: BTreeMap<String, String>,
+    pub stages: Vec<StageManifest>,
+    /// The assembled file, once the assembler finished it.
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StageManifest {
+    pub stage: PipelineStage,
+    pub completed: bool,
+    pub elapsed_secs: Option<f64>,
+    /// Why the stage failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Summarizes the pipeline state of `files`, whose outputs are in `out_dir`.
+#[anyhow_context]
+pub async fn build_manifest(
+    files: &[String],
+    out_dir: &Path,
+    started_at: DateTime<Utc>,
+) -> AnyResult<Manifest> {
+    let mut manifests = Vec::with_capacity(files.len());
+    for name in files {
+        let db = create_db_connection(name).await?;
+        let (messages, translated) = (
+            count_messages(db.clone()).await?,
+            count_translated(db.clone()).await?,
+        );
+        let stages = stage_states(db.clone())
+            .await?
+            .into_iter()
+            .map(|state| StageManifest {
+                stage: state.stage,
+                completed: state.completed,
+                elapsed_secs: state
+                    .elapsed()
+                    .map(|elapsed| elapsed.num_milliseconds() as f64 / 1000.0),
+                error: state.error,
             })
-            .await?;
+            .collect();
+        let assembled = is_stage_done(db.clone(), PipelineStage::Assembler).await?;
+        manifests.push(FileManifest {
+            name: name.clone(),
+            segments: count_segments(db.clone()).await?,
+            messages,
+            translated,
+            untranslated: messages.saturating_sub(translated),
+            backends: translation_backends(db.clone()).await?,
+            meta: list_file_meta(db).await?,
+            stages,
+            output: assembled.then(|| out_dir.join(name)),
+        });
+    }
+
+    Ok(Manifest {
+        started_at,
+        finished_at: Utc::now(),
+        files: manifests,
+    })
+}
+
+/// Where a file stopped, see [`list_failures`].
+#[derive(Debug, Serialize)]
+pub struct FileFailure {
+    pub name: String,
+    /// The first stage that didn't finish, or the translator if every stage
+    /// finished with messages left failed.
+    pub stage: PipelineStage,
+    pub reason: String,
+    /// Messages the backend refused or that were too long to translate.
+    pub failed_segments: u64,
+}
+
+/// Every file of `files` that didn't finish all stages or has failed messages.
+#[anyhow_context]
+pub async fn list_failures(files: &[String]) -> AnyResult<Vec<FileFailure>> {
+    let mut failures = Vec::new();
+    for name in files {
+        let db = create_db_connection(name).await?;
+        let states = stage_states(db.clone()).await?;
+        let failed_segments = count_failed(db).await?;
+        let stopped = PipelineStage::iter().find_map(|stage| {
+            match states.iter().find(|state| state.stage == stage) {
+                None => Some((stage, "never started".to_string())),
+                Some(state) if !state.completed => {
+                    let reason = match state.started_at {
+                        Some(started_at) => format!("started at {started_at}, never finished"),
+                        None => "never finished".into(),
+                    };
+                    Some((stage, reason))
+                }
+                Some(_) => None,
+            }
+        });
+        let (stage, reason) = match stopped {
+            Some(stopped) => stopped,
+            None if failed_segments > 0 => (
+                PipelineStage::Translator,
+                format!("{failed_segments} message(s) refused or too long"),
+            ),
+            None => continue,
+        };
+        failures.push(FileFailure {
+            name: name.clone(),
+            stage,
+            reason,
+            failed_segments,
+        });
+    }
+    Ok(failures)
+}
+
+/// Writes `manifest` to `out_dir/manifest.json`, returning the path written.
+#[anyhow_context]
+pub fn write_manifest(manifest: &Manifest, out_dir: &Path) -> AnyResult<PathBuf> {
+    let path = out_dir.join("manifest.json");
+    create_dir_all(out_dir)?;
+    write(&path, serde_json::to_string_pretty(manifest)?)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARSE: StageWiring = StageWiring {
+        consumes: "parse",
+        feeds: &["translate"],
+    };
+    const TRANSLATE: StageWiring = StageWiring {
+        consumes: "translate",
+        feeds: &["assemble"],
+    };
+    const ASSEMBLE: StageWiring = StageWiring {
+        consumes: "assemble",
+        feeds: &[],
+    };
+
+    #[test]
+    fn a_chain_from_the_seeded_queue_is_valid() {
+        validate_wiring(&[PARSE, TRANSLATE, ASSEMBLE], &["parse"]).unwrap();
+    }
+
+    #[test]
+    fn a_stage_nothing_enqueues_is_rejected() {
+        let translate = StageWiring {
+            feeds: &[],
+            ..TRANSLATE
+        };
+        let error = validate_wiring(&[PARSE, translate, ASSEMBLE], &["parse"]).unwrap_err();
+        assert!(format!("{error:#}").contains("Stage `assemble` is never enqueued"));
+    }
+
+    #[test]
+    fn a_push_to_a_missing_stage_is_rejected() {
+        let error = validate_wiring(&[PARSE, TRANSLATE], &["parse"]).unwrap_err();
+        assert!(format!("{error:#}").contains("pushes to `assemble`, which no stage consumes"));
+    }
+
+    #[test]
+    fn the_translator_waits_for_the_analyzer_only_when_configured() {
+        let mut config = Config::default();
+        assert!(translator_dependencies(&config).is_empty());
+        config.analyzer.before_translation = true;
+        assert_eq!(translator_dependencies(&config), [PipelineStage::Analyzer]);
     }
-    Ok(())
 }
@@ -0,0 +1,28 @@
+//! Translates scripts of the musica (Minori) engine through an LLM, file by file
+//! in stages: parser, analyzer, translator and assembler. [`Pipeline`] runs all
+//! of them; the `musica_aitranslator` binary is a command line over it.
+
+pub mod analyzer;
+pub mod assembler;
+pub mod cli;
+pub mod config;
+pub mod jobs;
+pub mod parser;
+pub mod pipeline;
+pub mod profile;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod storage;
+pub mod translator;
+pub mod utils;
+
+pub use crate::{
+    config::Config,
+    jobs::Manifest,
+    pipeline::{Pipeline, RunSummary},
+};
+
+// `#[pest_parser]` resolves the types it generates per grammar rule at the
+// crate root.
+#[doc(hidden)]
+pub use crate::parser::*;
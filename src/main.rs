@@ -1,80 +1,192 @@
-use anyhow::Result as AnyResult;
-use apalis::{
-    layers::WorkerBuilderExt,
-    prelude::{Monitor, Storage, WorkerBuilder, WorkerFactoryFn},
-};
-use apalis_sql::sqlite::{SqlitePool, SqliteStorage};
-use lazy_static::lazy_static;
-use sea_orm::DatabaseConnection;
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use walkdir::WalkDir;
-
-mod analyzer;
-mod assembler;
-mod jobs;
-mod parser;
-mod storage;
-mod translator;
-mod utils;
-
-use crate::{
-    jobs::{
-        AnalyzerJobQueue, AssemblerJobQueue, DispatchJob, DispatchJobQueue, Job, ParserJob,
-        ParserJobQueue, TranslatorJobQueue, dispatch_main,
+use anyhow::{Result as AnyResult, bail};
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use futures::future::select;
+use musica_aitranslator::{
+    Config, Pipeline,
+    cli::{Cli, Since},
+    parser::{collect_sources, source_name},
+    profile,
+    storage::{
+        PipelineStage, last_run, load_checkpoint, open_project_db, set_cache_path,
+        set_database_dir, set_target_languages,
     },
-    parser::*,
-    storage::create_db_connection,
 };
-
-lazy_static! {
-    // To push connections to the keep-alive list in an `async` context,
-    // we need to use an extra `RwLock` to allow concurrent access.
-    static ref KEEP_ALIVE: Arc<RwLock<Vec<Arc<DatabaseConnection>>>> =
-        Arc::new(RwLock::new(Vec::new()));
-}
+use rand::{SeedableRng, seq::SliceRandom};
+use rand_chacha::ChaCha8Rng;
+use std::{pin::pin, sync::Arc};
+use tokio::signal::ctrl_c;
+use tokio_util::sync::CancellationToken;
 
 #[tokio::main]
 async fn main() -> AnyResult<()> {
-    let pool = SqlitePool::connect("sqlite::memory:").await?;
-    SqliteStorage::setup(&pool).await?;
+    let cli = Cli::parse();
+    let config = Arc::new(Config::load()?);
+    if cli.dump_config {
+        print!("{}", config.dump()?);
+        return Ok(());
+    }
+    if let Some(dir) = &config.storage.database_dir {
+        set_database_dir(dir)?;
+    }
+    if let Some(path) = &config.storage.translation_cache {
+        set_cache_path(path)?;
+    }
+    set_target_languages(&config.translator)?;
+    if cli.profile {
+        profile::enable();
+    }
+    if let Some(command) = cli.command {
+        command.run(config, cli.retry_failed).await?;
+        print_profile();
+        return Ok(());
+    }
+
+    let mut sources = collect_sources(&config.parser.source_dir)?;
+    if cli.shuffle {
+        let seed = cli.seed.unwrap_or_else(rand::random);
+        println!("Shuffling source files with --seed {seed}");
+        sources.shuffle(&mut ChaCha8Rng::seed_from_u64(seed));
+    }
 
-    let mut parser_jobs = ParserJobQueue::new(pool.clone());
-    let assembler_jobs = AssemblerJobQueue::new(pool.clone());
-    let analyzer_jobs = AnalyzerJobQueue::new(pool.clone());
-    let translator_jobs = TranslatorJobQueue::new(pool.clone());
-    let dispatch_jobs = DispatchJobQueue::new(pool.clone());
+    let completed = if cli.resume {
+        if config.storage.database_dir.is_none() {
+            bail!("--resume requires `storage.database_dir` to be set");
+        }
+        match load_checkpoint(open_project_db().await?).await? {
+            Some(checkpoint) => checkpoint.completed_files()?,
+            None => bail!("There is no checkpoint to resume from"),
+        }
+    } else {
+        Vec::new()
+    };
 
-    let mut keep_alive = KEEP_ALIVE.write().await;
-    for entry in WalkDir::new("./assets/sc")
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.path().is_file())
+    let since = match cli.since {
+        Some(_) if config.storage.database_dir.is_none() => {
+            bail!("--since requires `storage.database_dir` to be set")
+        }
+        Some(Since::LastRun) => {
+            let last = last_run(open_project_db().await?).await?;
+            if last.is_none() {
+                println!("No earlier run recorded, enqueueing every source file");
+            }
+            last
+        }
+        Some(Since::Time(time)) => Some(time),
+        None => None,
+    };
+    let mut paths = Vec::new();
+    for entry in sources {
+        let file_name = source_name(&config.parser.source_dir, entry.path())?;
+        if completed.contains(&file_name) {
+            continue;
+        }
+        if let Some(since) = since
+            && DateTime::<Utc>::from(entry.metadata()?.modified()?) <= since
+        {
+            continue;
+        }
+        paths.push(entry.path().to_path_buf());
+    }
+
+    if let Some(stage) = cli.only_stage
+        && stage != PipelineStage::Parser
+        && config.storage.database_dir.is_none()
     {
-        let job = ParserJob {
-            file_path: entry.path().to_path_buf(),
-            file_name: entry.file_name().to_string_lossy().to_string(),
-        };
-        keep_alive.push(create_db_connection(&job.file_name).await?);
-        parser_jobs.push(job).await?;
+        bail!("--only-stage {stage:?} requires `storage.database_dir` to be set");
+    }
+    let cancel = CancellationToken::new();
+    tokio::spawn(cancel_on(shutdown_signal(), cancel.clone()));
+    // In-memory databases go with the process, there is nothing to resume.
+    let resume_hint = match config.storage.database_dir {
+        Some(_) => "continue with --resume",
+        None => "progress was lost as `storage.database_dir` is unset",
+    };
+    let summary = Pipeline::new(config)
+        .retry_failed(cli.retry_failed)
+        .skip_parsed(cli.resume)
+        .only_stage(cli.only_stage)
+        .max_runtime(cli.max_runtime)
+        .cancel_token(cancel)
+        .run(paths)
+        .await?;
+    println!("Wrote {}", summary.manifest_path.display());
+    if summary.timed_out {
+        println!("Stopped at --max-runtime, {resume_hint}");
+    }
+    if summary.cancelled {
+        println!("Interrupted, {resume_hint}");
     }
+    print_profile();
+    if !summary.failed.is_empty() {
+        bail!(
+            "{} file(s) failed, see {}",
+            summary.failed.len(),
+            summary.manifest_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM on Unix. SIGTERM is handled from the
+/// call on, not only once the future is polled.
+fn shutdown_signal() -> impl Future<Output = ()> {
+    #[cfg(unix)]
+    let terminate = {
+        use tokio::signal::unix::{SignalKind, signal};
+        signal(SignalKind::terminate()).ok()
+    };
+    async move {
+        #[cfg(unix)]
+        if let Some(mut terminate) = terminate {
+            select(pin!(ctrl_c()), pin!(terminate.recv())).await;
+            return;
+        }
+        let _ = ctrl_c().await;
+    }
+}
 
-    let monitor = Monitor::new()
-        .register({
-            WorkerBuilder::new(ParserJob::NAME)
-                .data(Arc::new(RwLock::new(dispatch_jobs.clone())))
-                .concurrency(4)
-                .backend(parser_jobs)
-                .build_fn(parser_main)
-        })
-        .register({
-            WorkerBuilder::new(DispatchJob::NAME)
-                .data(Arc::new(RwLock::new(analyzer_jobs.clone())))
-                .data(Arc::new(RwLock::new(translator_jobs.clone())))
-                .concurrency(2)
-                .backend(dispatch_jobs)
-                .build_fn(dispatch_main)
-        });
+/// Cancels the run once `signal` resolves.
+async fn cancel_on(signal: impl Future<Output = ()>, cancel: CancellationToken) {
+    signal.await;
+    cancel.cancel();
+}
 
-    Ok(())
+/// Prints the `--profile` breakdown, if anything was timed.
+fn print_profile() {
+    if let Some(breakdown) = profile::breakdown() {
+        println!("Time spent: {breakdown}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{future::ready, time::Duration};
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn the_signal_cancels_the_run() {
+        let cancel = CancellationToken::new();
+        cancel_on(ready(()), cancel.clone()).await;
+        assert!(cancel.is_cancelled());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sigterm_resolves_the_shutdown_signal() {
+        let shutdown = shutdown_signal();
+        let cancel = CancellationToken::new();
+        let cancelling = tokio::spawn(cancel_on(shutdown, cancel.clone()));
+        let status = std::process::Command::new("kill")
+            .args(["-TERM", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        timeout(Duration::from_secs(5), cancelling)
+            .await
+            .expect("SIGTERM was not handled")
+            .unwrap();
+        assert!(cancel.is_cancelled());
+    }
 }
@@ -1,21 +1,39 @@
 use crate::{
-    jobs::{DispatchJob, DispatchJobQueue, ParserJob},
-    storage::{create_db_connection, create_table, TextSegment, TextSegmentBuilder},
-    utils::IntoAnyResult,
+    analyzer::detect_mojibake,
+    config::{Config, ParserConfig, ScriptFormat},
+    jobs::{DispatchJob, DispatchJobQueue, ParserJob, record_failure},
+    profile::{self, Phase},
+    storage::{
+        Line, MessageId, PipelineStage, SOURCE_FILE_KEY, TachieVariant, TextSegment,
+        TextSegmentBuilder, WriteGate, create_db_connection, insert_segments, mark_stage_done,
+        mark_stage_started, set_file_meta, with_db,
+    },
+    utils::{IntoAnyResult, normalize_digits},
 };
 use anyhow::{Context, Result as AnyResult, bail};
 use apalis::prelude::{Data, Storage};
 use auto_context::auto_context as anyhow_context;
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_8};
 use enum_dispatch::enum_dispatch;
 use enum_dispatch_pest_parser::pest_parser;
-use futures::executor::block_on;
+use if_chain::if_chain;
 use pest::{
     Parser,
+    error::{Error as PestError, ErrorVariant, LineColLocation},
     iterators::{Pair, Pairs},
 };
-use sea_orm::{ActiveModelTrait, DatabaseConnection, IntoActiveModel};
-use std::{fs::read_to_string, path::PathBuf, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fmt::{self, Display, Formatter},
+    fs::{read, read_to_string},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
 use tokio::sync::RwLock;
+use walkdir::{DirEntry, WalkDir};
 
 #[pest_parser(grammar = "./src/pest/musica.pest", interface = "MusicaParse")]
 pub struct MusicaParser;
@@ -31,14 +49,237 @@ type StaticParserAst = Pairs<'static, Rule>;
 #[allow(unused)]
 type StaticParserAstNode = Pair<'static, Rule>;
 
+/// Where a file stops matching the grammar.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// Empty until the error reaches [`parse_string`].
+    pub file: String,
+    /// 1-based, like the position editors show.
+    pub line: usize,
+    pub col: usize,
+    /// The rule expected there, if pest tells.
+    pub rule: Option<String>,
+}
+
+impl ParseError {
+    fn from_pest(error: PestError<Rule>) -> Self {
+        let (LineColLocation::Pos((line, col)) | LineColLocation::Span((line, col), _)) =
+            error.line_col;
+        let rule = match &error.variant {
+            ErrorVariant::ParsingError { positives, .. } => positives.first().map(|rule| {
+                // `Rule` variants wrap a unit struct of the same name, `IMessage(IMessage)`
+                let name = format!("{rule:?}");
+                name.split('(').next().unwrap_or(&name).to_string()
+            }),
+            ErrorVariant::CustomError { .. } => None,
+        };
+        ParseError {
+            file: String::new(),
+            line,
+            col,
+            rule,
+        }
+    }
+
+    /// Applies `f` to the `ParseError` behind `error`, if it is one.
+    fn map(mut error: anyhow::Error, f: impl FnOnce(&mut ParseError)) -> anyhow::Error {
+        if let Some(parse_error) = error.downcast_mut::<ParseError>() {
+            f(parse_error);
+        }
+        error
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` does not parse at {}:{}",
+            self.file, self.line, self.col
+        )?;
+        if let Some(rule) = &self.rule {
+            write!(f, ", expected {rule}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ParseError {}
+
+/// Receives every segment the parser produces, in source order.
+pub trait SegmentSink {
+    fn accept(&mut self, segment: TextSegment) -> ParserResult<()>;
+}
+
+/// Collects segments in memory.
+#[derive(Debug, Default)]
+pub struct VecSink {
+    pub segments: Vec<TextSegment>,
+}
+
+impl SegmentSink for VecSink {
+    fn accept(&mut self, segment: TextSegment) -> ParserResult<()> {
+        self.segments.push(segment);
+        Ok(())
+    }
+}
+
+/// Tags segments enclosed in `;region=<name>` ... `;endregion` comments with
+/// their region, markers included, before passing them on.
+pub struct RegionSink<'a> {
+    inner: &'a mut dyn SegmentSink,
+    open: Option<(String, Line)>,
+}
+
+impl<'a> RegionSink<'a> {
+    pub fn new(inner: &'a mut dyn SegmentSink) -> Self {
+        RegionSink { inner, open: None }
+    }
+
+    /// Fails if a region is still open at the end of the file.
+    pub fn finish(self) -> ParserResult<()> {
+        if let Some((name, line)) = self.open {
+            bail!("Region `{name}` opened at line {line} is never closed");
+        }
+        Ok(())
+    }
+}
+
+enum RegionMarker<'a> {
+    Start(&'a str),
+    End,
+}
+
+fn region_marker(comment: &str) -> Option<RegionMarker<'_>> {
+    let marker = comment.trim().strip_prefix(';')?.trim();
+    if marker == "endregion" {
+        return Some(RegionMarker::End);
+    }
+    marker
+        .strip_prefix("region=")
+        .map(|name| RegionMarker::Start(name.trim()))
+}
+
+impl SegmentSink for RegionSink<'_> {
+    fn accept(&mut self, mut segment: TextSegment) -> ParserResult<()> {
+        let line = segment.line();
+        let marker = match &segment {
+            TextSegment::INonMessage(comment) => region_marker(&comment.content),
+            TextSegment::IMessage(_) => None,
+        };
+        let closes = matches!(marker, Some(RegionMarker::End));
+        match (marker, &self.open) {
+            (Some(RegionMarker::Start("")), _) => bail!("Region at line {line} has no name"),
+            (Some(RegionMarker::Start(name)), None) => self.open = Some((name.into(), line)),
+            (Some(RegionMarker::Start(name)), Some((open, at))) => {
+                bail!("Region `{name}` at line {line} starts inside region `{open}` from line {at}")
+            }
+            (Some(RegionMarker::End), None) => {
+                bail!("`;endregion` at line {line} closes no region")
+            }
+            _ => {}
+        }
+
+        segment.set_region(self.open.as_ref().map(|(name, _)| name.clone()));
+        if closes {
+            self.open = None;
+        }
+        self.inner.accept(segment)
+    }
+}
+
+/// Merges runs of consecutive non-messages into one segment, their contents
+/// joined by newlines, before passing them on. A message or a change of region
+/// ends a run.
+pub struct NonMessageMerging<'a> {
+    inner: &'a mut dyn SegmentSink,
+    pending: Option<TextSegment>,
+}
+
+impl<'a> NonMessageMerging<'a> {
+    pub fn new(inner: &'a mut dyn SegmentSink) -> Self {
+        NonMessageMerging {
+            inner,
+            pending: None,
+        }
+    }
+
+    /// Passes on the last run, if the file ends in one.
+    pub fn finish(self) -> ParserResult<()> {
+        match self.pending {
+            Some(pending) => self.inner.accept(pending),
+            None => Ok(()),
+        }
+    }
+}
+
+impl SegmentSink for NonMessageMerging<'_> {
+    fn accept(&mut self, segment: TextSegment) -> ParserResult<()> {
+        if let (Some(TextSegment::INonMessage(pending)), TextSegment::INonMessage(next)) =
+            (&mut self.pending, &segment)
+            && pending.region == next.region
+        {
+            pending.content.push('\n');
+            pending.content.push_str(&next.content);
+            return Ok(());
+        }
+        if let Some(pending) = self.pending.take() {
+            self.inner.accept(pending)?;
+        }
+        if matches!(segment, TextSegment::INonMessage(_)) {
+            self.pending = Some(segment);
+            return Ok(());
+        }
+        self.inner.accept(segment)
+    }
+}
+
+/// Only counts segments, for dry runs.
+#[allow(unused)]
+#[derive(Debug, Default)]
+pub struct CountingSink {
+    pub messages: usize,
+    pub non_messages: usize,
+}
+
+impl SegmentSink for CountingSink {
+    fn accept(&mut self, segment: TextSegment) -> ParserResult<()> {
+        match segment {
+            TextSegment::IMessage(_) => self.messages += 1,
+            TextSegment::INonMessage(_) => self.non_messages += 1,
+        }
+        Ok(())
+    }
+}
+
+/// Forwards to `inner`, counting the segments, to tell which top-level rules
+/// produced none, see `Musica::parse` and `parser.warn_on_empty_rules`.
+struct TallyingSink<'a> {
+    inner: &'a mut dyn SegmentSink,
+    accepted: usize,
+}
+
+impl SegmentSink for TallyingSink<'_> {
+    fn accept(&mut self, segment: TextSegment) -> ParserResult<()> {
+        self.accepted += 1;
+        self.inner.accept(segment)
+    }
+}
+
+/// Everything a node is parsed with besides its source.
+pub struct ParseContext<'a> {
+    pub sink: &'a mut dyn SegmentSink,
+    pub config: &'a ParserConfig,
+}
+
 #[allow(unused)]
 #[enum_dispatch]
 pub trait MusicaParse {
     fn parse(
         &self,
         node: ParserAstNode,
-        line: i32,
-        db: Arc<DatabaseConnection>,
+        line: Line,
+        ctx: &mut ParseContext,
     ) -> ParserResult<Option<TextSegmentBuilder>>;
 }
 
@@ -48,18 +289,14 @@ macro_rules! non_message_node {
             fn parse(
                 &self,
                 node: ParserAstNode,
-                line: i32,
-                db: Arc<DatabaseConnection>,
+                line: Line,
+                ctx: &mut ParseContext,
             ) -> ParserResult<Option<TextSegmentBuilder>> {
                 let model = TextSegmentBuilder::new_non_message()
                     .line(line)
                     .content(node.as_str())
                     .build()?;
-                block_on(
-                    TextSegment::INonMessage(model)
-                        .into_active_model()
-                        .insert(db.as_ref()),
-                )?;
+                ctx.sink.accept(TextSegment::INonMessage(model))?;
                 Ok(None)
             }
         }
@@ -72,8 +309,8 @@ macro_rules! silent_node {
             fn parse(
                 &self,
                 _: ParserAstNode,
-                _: i32,
-                _: Arc<DatabaseConnection>,
+                _: Line,
+                _: &mut ParseContext,
             ) -> ParserResult<Option<TextSegmentBuilder>> {
                 Ok(None)
             }
@@ -87,63 +324,140 @@ silent_node!(ASCII_PRINTABLE);
 silent_node!(CJ_CHARACTERS);
 silent_node!(CJ_PUNCTUATION);
 silent_node!(CJ_HALF_FULL_WIDTH);
+silent_node!(CJ_FULL_WIDTH_DIGIT);
 silent_node!(CJ_SEPARATOR);
 silent_node!(CJ_LEFT_CORNER_BRACKET);
 silent_node!(CJ_RIGHT_CORNER_BRACKET);
 silent_node!(CJ_PUNCTUATION_WITHOUT_CORNER_BRACKET);
+silent_node!(TACHIE_DELIMITER);
+silent_node!(MESSAGE_COMMENT_START);
 
 // silent Musica keywords rules
 silent_node!(MUSICA_COMMAND);
 silent_node!(MUSICA_PREPROC);
 silent_node!(MUSICA_COMMENT);
+silent_node!(MUSICA_INDENT);
 
 // silent Musica rules
 silent_node!(IMusicaScript);
+silent_node!(MessageBody);
+silent_node!(MessageTail);
 
 // ;comment rule
 non_message_node!(IComment);
 
 // #include rule
-non_message_node!(IInclude);
+impl MusicaParse for IInclude {
+    fn parse(
+        &self,
+        node: ParserAstNode,
+        line: Line,
+        ctx: &mut ParseContext,
+    ) -> ParserResult<Option<TextSegmentBuilder>> {
+        let include = node
+            .clone()
+            .into_inner()
+            .find(|inner| matches!(inner.as_rule(), Rule::IncludePath(_)))
+            .map(|path| path.as_str().trim_matches('"').to_string());
+        let model = TextSegmentBuilder::new_non_message()
+            .line(line)
+            .content(node.as_str())
+            .include(include)
+            .build()?;
+        ctx.sink.accept(TextSegment::INonMessage(model))?;
+        Ok(None)
+    }
+}
+silent_node!(IncludePath);
 
 // .message rule
 impl MusicaParse for IMessage {
     fn parse(
         &self,
         node: ParserAstNode,
-        line: i32,
-        db: Arc<DatabaseConnection>,
+        line: Line,
+        ctx: &mut ParseContext,
     ) -> ParserResult<Option<TextSegmentBuilder>> {
-        // IMessage ONLY contains ONE IMessageNamed or IMessageUnnamed
-        if let Some(node) = node.into_inner().next() {
+        // IMessage contains the header atoms followed by ONE IMessageNamed or IMessageUnnamed
+        let indent = node.as_str().split('.').next().unwrap_or_default();
+        let mut builder = TextSegmentBuilder::new_message()
+            .line(line)
+            .indent(indent)
+            .raw(node.as_str());
+        // Relative to the line, for the assembler to replace only these.
+        let start = node.as_span().start();
+        let span =
+            |atom: &ParserAstNode| (atom.as_span().start() - start, atom.as_span().end() - start);
+        let mut content_span: Option<(usize, usize)> = None;
+        let mut number_at = node.line_col();
+        for atom in node.clone().into_inner().flatten() {
+            match atom.as_rule() {
+                Rule::MessageNumber(_) => number_at = atom.line_col(),
+                Rule::MessageSpeakerName(_) => builder = builder.name_span(span(&atom)),
+                Rule::MessageContentQuoted(_)
+                | Rule::MessageContentUnquoted(_)
+                | Rule::MessageContentInline(_) => {
+                    let (from, to) = span(&atom);
+                    content_span = Some(match content_span {
+                        Some((first, _)) => (first, to),
+                        None => (from, to),
+                    });
+                }
+                _ => {}
+            }
+        }
+        if let Some(content_span) = content_span {
+            builder = builder.content_span(content_span);
+        }
+        for node in node.into_inner() {
             let rule = node.as_rule();
-            let builder = rule.parse(node, line, db.clone())?.into_any_result()?;
-            if let TextSegmentBuilder::IMessage(builder) = builder {
-                let message = builder.build()?;
-                block_on(
-                    TextSegment::IMessage(message)
-                        .into_active_model()
-                        .insert(db.as_ref()),
-                )?;
-            } else {
-                bail!("Expected IMessageBuilder, found INonMessageBuilder");
+            let segment = rule.parse(node, line, ctx)?.into_any_result()?;
+            builder = builder.combine(segment)?;
+        }
+        // Would otherwise only fail with a bare builder error, without the file.
+        if let (_, None) = builder.header() {
+            let (line, col) = number_at;
+            return Err(ParseError {
+                file: String::new(),
+                line,
+                col,
+                rule: Some("MessageNumber".into()),
             }
+            .into());
         }
+        ctx.sink.accept(TextSegment::IMessage(builder.build()?))?;
         Ok(None)
     }
 }
 
+/// The corner brackets written around the content of a named message by default.
+pub const CORNER_BRACKETS: &str = "「」";
+
 impl MusicaParse for IMessageNamed {
     fn parse(
         &self,
         node: ParserAstNode,
-        line: i32,
-        db: Arc<DatabaseConnection>,
+        line: Line,
+        ctx: &mut ParseContext,
     ) -> ParserResult<Option<TextSegmentBuilder>> {
-        let mut builder = TextSegmentBuilder::new_message().line(line);
+        let (start, text) = (node.as_span().start(), node.as_str());
+        let mut builder = TextSegmentBuilder::new_message();
         for node in node.into_inner() {
             let rule = node.as_rule();
-            let segment = rule.parse(node, line, db.clone())?.into_any_result()?;
+            if matches!(rule, Rule::MessageContentQuoted(_)) {
+                // The brackets are silent, they are right around the content.
+                let open = text[..node.as_span().start() - start].chars().last();
+                let close = text.chars().last();
+                let brackets = open.into_iter().chain(close).collect::<String>();
+                if brackets != CORNER_BRACKETS {
+                    builder = builder.brackets(brackets);
+                }
+            }
+            if matches!(rule, Rule::MessageContentInline(_)) {
+                // Followed by more of the body, the brackets stay in the content.
+                builder = builder.brackets("");
+            }
+            let segment = rule.parse(node, line, ctx)?.into_any_result()?;
             builder = builder.combine(segment)?;
         }
 
@@ -155,13 +469,13 @@ impl MusicaParse for IMessageUnnamed {
     fn parse(
         &self,
         node: ParserAstNode,
-        line: i32,
-        db: Arc<DatabaseConnection>,
+        line: Line,
+        ctx: &mut ParseContext,
     ) -> ParserResult<Option<TextSegmentBuilder>> {
-        let mut builder = TextSegmentBuilder::new_message().line(line);
+        let mut builder = TextSegmentBuilder::new_message();
         for node in node.into_inner() {
             let rule = node.as_rule();
-            let segment = rule.parse(node, line, db.clone())?.into_any_result()?;
+            let segment = rule.parse(node, line, ctx)?.into_any_result()?;
             builder = builder.combine(segment)?;
         }
 
@@ -174,14 +488,16 @@ impl MusicaParse for MessageNumber {
     fn parse(
         &self,
         node: ParserAstNode,
-        _line: i32,
-        _db: Arc<DatabaseConnection>,
+        _line: Line,
+        _ctx: &mut ParseContext,
     ) -> ParserResult<Option<TextSegmentBuilder>> {
-        Ok(Some(
-            TextSegmentBuilder::new_message()
-                .id(node.as_str().parse::<i32>()?)
-                .into(),
-        ))
+        // An id too large for `MessageId` is left out, for `IMessage` to report it.
+        let builder = TextSegmentBuilder::new_message();
+        let builder = match normalize_digits(node.as_str()).parse() {
+            Ok(id) => builder.id(MessageId(id)),
+            Err(_) => builder,
+        };
+        Ok(Some(builder.into()))
     }
 }
 
@@ -189,8 +505,8 @@ impl MusicaParse for MessageSpeakerName {
     fn parse(
         &self,
         node: ParserAstNode,
-        _line: i32,
-        _db: Arc<DatabaseConnection>,
+        _line: Line,
+        _ctx: &mut ParseContext,
     ) -> ParserResult<Option<TextSegmentBuilder>> {
         Ok(Some(
             TextSegmentBuilder::new_message().name(node.as_str()).into(),
@@ -202,23 +518,45 @@ impl MusicaParse for MessageSpeakerTachie {
     fn parse(
         &self,
         node: ParserAstNode,
-        _line: i32,
-        _db: Arc<DatabaseConnection>,
+        _line: Line,
+        ctx: &mut ParseContext,
+    ) -> ParserResult<Option<TextSegmentBuilder>> {
+        let tachie = node.as_str();
+        let delimiters = &ctx.config.tachie_delimiters;
+        let builder = match tachie.char_indices().find(|(_, c)| delimiters.contains(*c)) {
+            Some((at, delimiter)) => TextSegmentBuilder::new_message()
+                .tachie(&tachie[..at])
+                .tachie_variant(TachieVariant {
+                    delimiter,
+                    name: tachie[at + delimiter.len_utf8()..].into(),
+                }),
+            None => TextSegmentBuilder::new_message().tachie(tachie),
+        };
+        Ok(Some(builder.into()))
+    }
+}
+
+impl MusicaParse for MessageContentQuoted {
+    fn parse(
+        &self,
+        node: ParserAstNode,
+        _line: Line,
+        _ctx: &mut ParseContext,
     ) -> ParserResult<Option<TextSegmentBuilder>> {
         Ok(Some(
             TextSegmentBuilder::new_message()
-                .tachie(node.as_str())
+                .content(node.as_str())
                 .into(),
         ))
     }
 }
 
-impl MusicaParse for MessageContentQuoted {
+impl MusicaParse for MessageContentInline {
     fn parse(
         &self,
         node: ParserAstNode,
-        _line: i32,
-        _db: Arc<DatabaseConnection>,
+        _line: Line,
+        _ctx: &mut ParseContext,
     ) -> ParserResult<Option<TextSegmentBuilder>> {
         Ok(Some(
             TextSegmentBuilder::new_message()
@@ -232,8 +570,8 @@ impl MusicaParse for MessageContentUnquoted {
     fn parse(
         &self,
         node: ParserAstNode,
-        _line: i32,
-        _db: Arc<DatabaseConnection>,
+        _line: Line,
+        _ctx: &mut ParseContext,
     ) -> ParserResult<Option<TextSegmentBuilder>> {
         Ok(Some(
             TextSegmentBuilder::new_message()
@@ -243,52 +581,1134 @@ impl MusicaParse for MessageContentUnquoted {
     }
 }
 
+impl MusicaParse for MessageComment {
+    fn parse(
+        &self,
+        node: ParserAstNode,
+        _line: Line,
+        _ctx: &mut ParseContext,
+    ) -> ParserResult<Option<TextSegmentBuilder>> {
+        Ok(Some(
+            TextSegmentBuilder::new_message()
+                .comment(node.as_str())
+                .into(),
+        ))
+    }
+}
+
 // non .message rule for text extraction
 non_message_node!(INonMessage);
 
+// blank line rule, an empty non-message that renders back to the blank line
+impl MusicaParse for IBlankLine {
+    fn parse(
+        &self,
+        _node: ParserAstNode,
+        line: Line,
+        ctx: &mut ParseContext,
+    ) -> ParserResult<Option<TextSegmentBuilder>> {
+        let model = TextSegmentBuilder::new_non_message()
+            .line(line)
+            .content("")
+            .build()?;
+        ctx.sink.accept(TextSegment::INonMessage(model))?;
+        Ok(None)
+    }
+}
+
 // main rule for Musica
 impl MusicaParse for Musica {
     fn parse(
         &self,
         node: ParserAstNode,
-        line: i32,
-        db: Arc<DatabaseConnection>,
+        line: Line,
+        ctx: &mut ParseContext,
     ) -> ParserResult<Option<TextSegmentBuilder>> {
-        let mut line = line;
         for node in node.into_inner() {
             let rule = node.as_rule();
-            let _ = rule.parse(node, line, db.clone())?.into_any_result()?;
-            line += 1;
+            if matches!(rule, Rule::EOI(_)) {
+                continue;
+            }
+            // Taken from the span rather than counted, so that a node spanning
+            // several lines doesn't shift the ones after it. `line` is the number
+            // of lines before this part of the file, for chunks parsed on their own.
+            let line = Line(line.0 + node.line_col().0 as i32);
+            let span = node.as_span();
+            let (start, end, text) = (span.start(), span.end(), span.as_str());
+            let mut tally = TallyingSink {
+                inner: &mut *ctx.sink,
+                accepted: 0,
+            };
+            let mut tallied = ParseContext {
+                sink: &mut tally,
+                config: ctx.config,
+            };
+            let _ = rule.parse(node, line, &mut tallied)?;
+            if tally.accepted == 0 {
+                // A rule without a segment of its own, e.g. one added to the grammar
+                // with a `silent_node!`, is written back verbatim rather than lost.
+                if ctx.config.warn_on_empty_rules {
+                    // `Rule` variants wrap a unit struct of the same name, `IMessage(IMessage)`
+                    let name = format!("{rule:?}");
+                    let name = name.split('(').next().unwrap_or(&name);
+                    eprintln!(
+                        "Rule {name} at line {}, bytes {start}..{end}, produced no segment, kept \
+                         verbatim: {text:?}",
+                        line.0
+                    );
+                }
+                let model = TextSegmentBuilder::new_non_message()
+                    .line(line)
+                    .content(text)
+                    .build()?;
+                ctx.sink.accept(TextSegment::INonMessage(model))?;
+            }
         }
         Ok(None)
     }
 }
+
 #[anyhow_context]
-pub fn parse_file(path: PathBuf, name: String) -> ParserResult<()> {
-    let db = block_on(create_db_connection(&name))?;
-    block_on(create_table(db.clone()))?;
+pub fn parse_content(
+    content: &str,
+    config: &ParserConfig,
+    sink: &mut dyn SegmentSink,
+) -> ParserResult<()> {
+    let mut sink = RegionSink::new(sink);
+    parse_rules(content, config, &mut sink)?;
+    sink.finish()
+}
 
-    let content = read_to_string(path)?;
-    let ast: ParserAst = MusicaParser::parse(Rule::Musica(Musica {}), &content)?;
+/// Like [`parse_content`] but without region tagging, which needs the whole file.
+#[anyhow_context]
+fn parse_rules(
+    content: &str,
+    config: &ParserConfig,
+    sink: &mut dyn SegmentSink,
+) -> ParserResult<()> {
+    let ast: ParserAst = profile::time(Phase::Parse, || {
+        MusicaParser::parse(Rule::Musica(Musica {}), content).map_err(ParseError::from_pest)
+    })?;
     let root: ParserAstNode = ast.peek().into_any_result()?;
     let rule = root.as_rule();
 
-    rule.parse(root, 0, db)?;
+    rule.parse(root, Line(0), &mut ParseContext { sink, config })?;
     Ok(())
 }
 
+/// Longest matched text shown per node by [`format_ast`].
+const AST_TEXT_LIMIT: usize = 40;
+
+/// Renders the pest parse tree of `content`, one node per line indented by depth,
+/// with its rule, byte span and (truncated) matched text.
+#[anyhow_context]
+pub fn format_ast(content: &str) -> ParserResult<String> {
+    fn format_node(node: ParserAstNode, depth: usize, output: &mut String) {
+        // `Rule` variants wrap a unit struct of the same name, `IMessage(IMessage)`
+        let rule = format!("{:?}", node.as_rule());
+        let rule = rule.split('(').next().unwrap_or(&rule);
+        let span = node.as_span();
+        let mut text = span
+            .as_str()
+            .chars()
+            .take(AST_TEXT_LIMIT)
+            .collect::<String>();
+        if text.len() < span.as_str().len() {
+            text.push_str("...");
+        }
+        output.push_str(&format!(
+            "{}{rule} {}..{} {text:?}\n",
+            "  ".repeat(depth),
+            span.start(),
+            span.end()
+        ));
+        for child in node.into_inner() {
+            format_node(child, depth + 1, output);
+        }
+    }
+
+    let ast: ParserAst = MusicaParser::parse(Rule::Musica(Musica {}), content)?;
+    let mut output = String::new();
+    for node in ast {
+        format_node(node, 0, &mut output);
+    }
+    Ok(output)
+}
+
+/// Splits `content` into at most `chunks` pieces that can be parsed independently.
+///
+/// A chunk may only start at a `.message` line directly preceded by a blank line.
+/// Every top-level rule ends at a `NEWLINE` and quoted message content never runs
+/// over a blank line, so a blank line can never be part of a rule and cutting
+/// right after it never bisects a message.
+fn split_chunks(content: &str, chunks: usize) -> Vec<&str> {
+    let target = content.len() / chunks.max(1);
+    let mut bounds = vec![0];
+    let (mut offset, mut prev_blank) = (0, false);
+    for line in content.split_inclusive('\n') {
+        if_chain! {
+            if prev_blank && line.starts_with(".message");
+            if bounds.len() < chunks;
+            if let Some(&last) = bounds.last();
+            if offset - last >= target;
+            then {
+                bounds.push(offset);
+            }
+        }
+        prev_blank = line.trim_end_matches(['\r', '\n']).is_empty();
+        offset += line.len();
+    }
+    bounds.push(content.len());
+    bounds.windows(2).map(|w| &content[w[0]..w[1]]).collect()
+}
+
+/// The suffix of metadata sidecars, see [`read_sidecar_meta`].
+const SIDECAR_SUFFIX: &str = ".meta.toml";
+
+/// All files below `dir` sorted by path, except metadata sidecars, failing loudly instead of
+/// yielding nothing when the directory is missing or empty.
+#[anyhow_context]
+pub fn collect_sources(dir: &Path) -> ParserResult<Vec<DirEntry>> {
+    if !dir.is_dir() {
+        bail!("source directory `{}` does not exist", dir.display());
+    }
+    let mut sources = Vec::new();
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry?;
+        if entry.file_type().is_file()
+            && !entry
+                .file_name()
+                .to_string_lossy()
+                .ends_with(SIDECAR_SUFFIX)
+        {
+            sources.push(entry);
+        }
+    }
+    if sources.is_empty() {
+        bail!("source directory `{}` contains no files", dir.display());
+    }
+    Ok(sources)
+}
+
+/// The name of the source file at `path`: its path relative to `source_dir`
+/// with `/` separators, so that files of the same name in different
+/// directories get segments and an output of their own. Files outside
+/// `source_dir` are named by their file name.
+#[anyhow_context]
+pub fn source_name(source_dir: &Path, path: &Path) -> ParserResult<String> {
+    let relative = match path.strip_prefix(source_dir) {
+        Ok(relative) => relative,
+        Err(_) => Path::new(
+            path.file_name()
+                .with_context(|| format!("`{}` is not a file", path.display()))?,
+        ),
+    };
+    let parts = relative
+        .components()
+        .map(|part| part.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>();
+    if parts.is_empty() {
+        bail!("`{}` is not a file", path.display());
+    }
+    Ok(parts.join("/"))
+}
+
+/// Fewer non-ASCII bytes than this are too little to tell encodings apart.
+const MIN_DETECTION_BYTES: usize = 32;
+
+/// The encoding of a source file: `parser.encoding` if set, else the one its
+/// BOM names, else UTF-8 if it is valid UTF-8, else a guess. Short files that
+/// aren't UTF-8 are read as UTF-8 too, with a warning.
+#[anyhow_context]
+fn detect_encoding(
+    bytes: &[u8],
+    name: &str,
+    config: &ParserConfig,
+) -> ParserResult<&'static Encoding> {
+    if let Some(label) = &config.encoding {
+        let encoding = Encoding::for_label(label.as_bytes())
+            .with_context(|| format!("Unknown source encoding `{label}`"))?;
+        return Ok(encoding);
+    }
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return Ok(encoding);
+    }
+    if std::str::from_utf8(bytes).is_ok() {
+        return Ok(UTF_8);
+    }
+    if bytes.iter().filter(|byte| !byte.is_ascii()).count() < MIN_DETECTION_BYTES {
+        eprintln!("[{name}] too short to detect its encoding, reading it as UTF-8");
+        return Ok(UTF_8);
+    }
+    let mut detector = EncodingDetector::new();
+    detector.feed(bytes, true);
+    let encoding = detector.guess(None, true);
+    eprintln!("[{name}] detected encoding {}", encoding.name());
+    Ok(encoding)
+}
+
+/// Reads and decodes a source file, see [`detect_encoding`], warning when it
+/// looks wrongly transcoded. Bytes the encoding can't decode are replaced, or
+/// fail the file with `config.strict_decoding`.
+#[anyhow_context]
+pub fn read_source(path: &Path, name: &str, config: &ParserConfig) -> ParserResult<String> {
+    let bytes = read(path)?;
+    let encoding = detect_encoding(&bytes, name, config)?;
+    let (content, had_errors) = encoding.decode_with_bom_removal(&bytes);
+    if had_errors && config.strict_decoding {
+        bail!("`{name}` is not valid {}", encoding.name());
+    }
+    if had_errors {
+        eprintln!(
+            "[{name}] is not valid {}, undecodable bytes were replaced",
+            encoding.name()
+        );
+    }
+    let content = content.into_owned();
+    if let Some(report) = detect_mojibake(&content) {
+        eprintln!("[{name}] {report}");
+    }
+    Ok(content)
+}
+
+/// A script format the parser stage can read into segments.
+pub trait FileParser: Send + Sync {
+    fn parse(&self, content: &str, sink: &mut dyn SegmentSink) -> ParserResult<()>;
+}
+
+/// Scripts of the musica (Minori) engine, see `pest/musica.pest`. Parsed in
+/// parallel if `parser.chunks > 1`.
+pub struct MusicaFileParser {
+    config: ParserConfig,
+}
+
+impl MusicaFileParser {
+    pub fn new(config: &ParserConfig) -> Self {
+        MusicaFileParser {
+            config: config.clone(),
+        }
+    }
+}
+
+impl FileParser for MusicaFileParser {
+    fn parse(&self, content: &str, sink: &mut dyn SegmentSink) -> ParserResult<()> {
+        match self.config.chunks {
+            Some(chunks) if chunks > 1 => {
+                parse_content_parallel(content, chunks, &self.config, sink)
+            }
+            _ => parse_content(content, &self.config, sink),
+        }
+    }
+}
+
+/// Picks the [`FileParser`] of a source file by its extension.
+pub struct ParserRegistry {
+    parsers: HashMap<String, Arc<dyn FileParser>>,
+    fallback: Arc<dyn FileParser>,
+}
+
+impl ParserRegistry {
+    /// One parser per extension in `config.formats`. Musica parses every file
+    /// whose extension has no parser registered, as all files were parsed
+    /// before there were other formats.
+    pub fn new(config: &ParserConfig) -> Self {
+        let musica: Arc<dyn FileParser> = Arc::new(MusicaFileParser::new(config));
+        let mut registry = ParserRegistry {
+            parsers: HashMap::new(),
+            fallback: musica.clone(),
+        };
+        for (extension, format) in &config.formats {
+            let parser = match format {
+                ScriptFormat::Musica => musica.clone(),
+            };
+            registry.register(extension, parser);
+        }
+        registry
+    }
+
+    /// Parses files ending in `.<extension>` (case-insensitive) with `parser`.
+    pub fn register(&mut self, extension: &str, parser: Arc<dyn FileParser>) {
+        self.parsers.insert(extension.to_lowercase(), parser);
+    }
+
+    pub fn for_path(&self, path: &Path) -> &dyn FileParser {
+        path.extension()
+            .and_then(|extension| {
+                self.parsers
+                    .get(&extension.to_string_lossy().to_lowercase())
+            })
+            .unwrap_or(&self.fallback)
+            .as_ref()
+    }
+}
+
+/// Parses one large file on up to `chunks` threads.
+///
+/// Segment lines are source lines, so a chunk's line offset is the number of
+/// source lines in the chunks before it.
+#[anyhow_context]
+fn parse_content_parallel(
+    content: &str,
+    chunks: usize,
+    config: &ParserConfig,
+    sink: &mut dyn SegmentSink,
+) -> ParserResult<()> {
+    let parsed = thread::scope(|scope| {
+        let mut first_line = 0;
+        let handles = split_chunks(content, chunks)
+            .into_iter()
+            .map(|chunk| {
+                let lines = chunk.split_inclusive('\n').count();
+                let offset = first_line;
+                first_line += lines;
+                scope.spawn(move || {
+                    let mut sink = VecSink::default();
+                    parse_rules(chunk, config, &mut sink)
+                        .map(|_| (sink.segments, lines as i32))
+                        .map_err(|error| ParseError::map(error, |error| error.line += offset))
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| match handle.join() {
+                Ok(segments) => segments,
+                Err(_) => bail!("Parser thread panicked"),
+            })
+            .collect::<ParserResult<Vec<_>>>()
+    })?;
+
+    let (mut sink, mut offset) = (RegionSink::new(sink), 0);
+    for (chunk, lines) in parsed {
+        for mut segment in chunk {
+            segment.offset_line(offset);
+            sink.accept(segment)?;
+        }
+        offset += lines;
+    }
+    sink.finish()
+}
+
+/// Parses `content` of the file `name` with the parser registered for its
+/// extension, merging consecutive non-messages if `config.merge_non_messages`
+/// is set.
+#[anyhow_context]
+pub fn parse_string(
+    content: &str,
+    name: &str,
+    config: &ParserConfig,
+) -> ParserResult<Vec<TextSegment>> {
+    let registry = ParserRegistry::new(config);
+    let parser = registry.for_path(Path::new(name));
+    let mut parsed = VecSink::default();
+    let with_file = |error| ParseError::map(error, |error| error.file = name.to_string());
+    if config.merge_non_messages {
+        let mut sink = NonMessageMerging::new(&mut parsed);
+        parser
+            .parse(content, &mut sink)
+            .map_err(with_file)
+            .with_context(|| format!("Parsing `{name}`"))?;
+        sink.finish()?;
+    } else {
+        parser
+            .parse(content, &mut parsed)
+            .map_err(with_file)
+            .with_context(|| format!("Parsing `{name}`"))?;
+    }
+    Ok(parsed.segments)
+}
+
+/// Like [`parse_string`] but collecting every line of a Musica script that
+/// doesn't parse instead of failing on the first one. Such lines are left out
+/// of the segments.
+#[anyhow_context]
+pub fn parse_string_lenient(
+    content: &str,
+    name: &str,
+    config: &ParserConfig,
+) -> ParserResult<(Vec<TextSegment>, Vec<ParseError>)> {
+    match parse_string(content, name, config) {
+        Ok(segments) => return Ok((segments, Vec::new())),
+        Err(error) if error.downcast_ref::<ParseError>().is_none() => return Err(error),
+        Err(_) => {}
+    }
+
+    // Parsed again source line by source line. Only a message quoted over several
+    // lines spans a newline, its first line is then read as an unquoted message.
+    fn parse_lines(
+        content: &str,
+        name: &str,
+        config: &ParserConfig,
+        sink: &mut dyn SegmentSink,
+    ) -> ParserResult<Vec<ParseError>> {
+        let mut sink = RegionSink::new(sink);
+        let mut errors = Vec::new();
+        for (index, line) in content.split_inclusive('\n').enumerate() {
+            let mut parsed = VecSink::default();
+            match parse_rules(line, config, &mut parsed) {
+                Ok(()) => {
+                    for mut segment in parsed.segments {
+                        segment.offset_line(index as i32);
+                        sink.accept(segment)?;
+                    }
+                }
+                Err(error) => errors.push(ParseError {
+                    file: name.to_string(),
+                    line: index + 1,
+                    ..error.downcast::<ParseError>()?
+                }),
+            }
+        }
+        sink.finish()?;
+        Ok(errors)
+    }
+
+    let mut parsed = VecSink::default();
+    let errors = if config.merge_non_messages {
+        let mut sink = NonMessageMerging::new(&mut parsed);
+        let errors = parse_lines(content, name, config, &mut sink)?;
+        sink.finish()?;
+        errors
+    } else {
+        parse_lines(content, name, config, &mut parsed)?
+    };
+    Ok((parsed.segments, errors))
+}
+
+/// Parses `content` of the file `name` with [`parse_string`], or with
+/// [`parse_string_lenient`] and `config.lenient`, reporting the lines left out.
+#[anyhow_context]
+fn parse_source_content(
+    content: &str,
+    name: &str,
+    config: &ParserConfig,
+) -> ParserResult<Vec<TextSegment>> {
+    if !config.lenient {
+        return parse_string(content, name, config);
+    }
+    let (segments, errors) = parse_string_lenient(content, name, config)?;
+    for error in errors {
+        eprintln!("{error}, line left out");
+    }
+    Ok(segments)
+}
+
+/// Parses `path` with the segments of the file each `#include` names, resolved
+/// relative to the including file, spliced in right after the directive. They
+/// keep their line in that file and have it as their `origin`, relative to the
+/// directory of `path`, while the directive stays what is written back.
+#[anyhow_context]
+pub fn parse_with_includes(
+    path: &Path,
+    name: &str,
+    config: &ParserConfig,
+) -> ParserResult<Vec<TextSegment>> {
+    fn expand(
+        path: &Path,
+        name: &str,
+        config: &ParserConfig,
+        including: &mut Vec<PathBuf>,
+    ) -> ParserResult<Vec<TextSegment>> {
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("Included file `{}`", path.display()))?;
+        if let Some(start) = including.iter().position(|file| *file == canonical) {
+            let cycle = including[start..]
+                .iter()
+                .chain([&canonical])
+                .map(|file| file.display().to_string())
+                .collect::<Vec<_>>();
+            bail!("Include cycle: {}", cycle.join(" -> "));
+        }
+        including.push(canonical);
+
+        let content = read_source(path, name, config)?;
+        let mut segments = Vec::new();
+        for segment in parse_source_content(&content, name, config)? {
+            let include = match &segment {
+                TextSegment::INonMessage(model) => model.include.clone(),
+                TextSegment::IMessage(_) => None,
+            };
+            segments.push(segment);
+            let Some(include) = include else {
+                continue;
+            };
+            let dir = path.parent().unwrap_or(Path::new(""));
+            for mut segment in expand(&dir.join(&include), &include, config, including)? {
+                // Those of nested includes are relative to the file including them.
+                let origin = match segment.origin() {
+                    Some(origin) => Path::new(&include)
+                        .parent()
+                        .unwrap_or(Path::new(""))
+                        .join(origin),
+                    None => PathBuf::from(&include),
+                };
+                segment.set_origin(Some(origin.to_string_lossy().replace('\\', "/")));
+                segments.push(segment);
+            }
+        }
+
+        including.pop();
+        Ok(segments)
+    }
+
+    expand(path, name, config, &mut Vec::new())
+}
+
+/// Parses `path` into the database of `name`, see [`parse_source_content`],
+/// with the files it includes spliced in if `config.expand_includes` is set,
+/// see [`parse_with_includes`].
+///
+/// The whole file is parsed before its segments are inserted in one
+/// transaction, so that no worker thread blocks on the database while parsing.
+#[anyhow_context]
+pub async fn parse_file(
+    path: PathBuf,
+    name: String,
+    config: &ParserConfig,
+    gate: &WriteGate,
+) -> ParserResult<()> {
+    let db = create_db_connection(&name).await?;
+
+    let segments = if config.expand_includes {
+        parse_with_includes(&path, &name, config)?
+    } else {
+        parse_source_content(&read_source(&path, &name, config)?, &name, config)?
+    };
+    let batch_size = config.insert_batch_size;
+    profile::time_async(
+        Phase::Insert,
+        gate.write(|| insert_segments(db.clone(), &segments, batch_size)),
+    )
+    .await
+}
+
+/// The metadata in `<path>.meta.toml`, a table of string values, if there is
+/// such a sidecar next to the source file.
+#[anyhow_context]
+pub fn read_sidecar_meta(path: &Path) -> ParserResult<BTreeMap<String, String>> {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(SIDECAR_SUFFIX);
+    let sidecar = PathBuf::from(sidecar);
+    if !sidecar.is_file() {
+        return Ok(BTreeMap::new());
+    }
+    let meta = toml::from_str(&read_to_string(&sidecar)?)
+        .with_context(|| format!("Metadata sidecar `{}`", sidecar.display()))?;
+    Ok(meta)
+}
+
+/// Runs the parser stage on `path`, see [`parse_file`], and records the
+/// metadata of its sidecar, see [`read_sidecar_meta`].
+#[anyhow_context]
+pub async fn parse_source(
+    path: PathBuf,
+    name: String,
+    config: &ParserConfig,
+    gate: &WriteGate,
+) -> ParserResult<()> {
+    with_db(&name.clone(), |db| {
+        Box::pin(async move {
+            mark_stage_started(db.clone(), PipelineStage::Parser).await?;
+            gate.write(|| set_file_meta(db.clone(), SOURCE_FILE_KEY, &name))
+                .await?;
+            for (key, value) in read_sidecar_meta(&path)? {
+                gate.write(|| set_file_meta(db.clone(), &key, &value))
+                    .await?;
+            }
+            parse_file(path, name, config, gate).await?;
+            mark_stage_done(db, PipelineStage::Parser).await
+        })
+    })
+    .await
+}
+
 pub async fn parser_main(
     job: ParserJob,
     dispatch: Data<Arc<RwLock<DispatchJobQueue>>>,
+    config: Data<Arc<Config>>,
+    gate: Data<WriteGate>,
 ) -> AnyResult<()> {
     let (path, name) = (job.file_path, job.file_name);
-    parse_file(path.clone(), name.clone())?;
-    let mut dispatch = dispatch.write().await;
-    dispatch
-        .push(DispatchJob {
-            file_name: name,
-            file_path: path,
-        })
-        .await?;
-    Ok(())
+    let parsed = async {
+        parse_source(path.clone(), name.clone(), &config.parser, &gate).await?;
+        let mut dispatch = dispatch.write().await;
+        dispatch
+            .push(DispatchJob {
+                file_name: name.clone(),
+                file_path: path,
+            })
+            .await?;
+        AnyResult::Ok(())
+    };
+    record_failure(&name, PipelineStage::Parser, parsed.await).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::StorageConfig,
+        storage::{
+            IMessageModel, INonMessageModel, create_db_connection, list_files, list_includes,
+            load_messages, open_segments_db,
+        },
+        utils::test_dir,
+    };
+    use std::{
+        fs::{create_dir_all, remove_dir_all, write},
+        sync::Arc,
+    };
+
+    fn parse(content: &str) -> Vec<TextSegment> {
+        parse_string(content, "test.sc", &ParserConfig::default()).unwrap()
+    }
+
+    fn message(segment: &TextSegment) -> &IMessageModel {
+        match segment {
+            TextSegment::IMessage(message) => message,
+            TextSegment::INonMessage(other) => panic!("not a message: {other:?}"),
+        }
+    }
+
+    fn non_message(segment: &TextSegment) -> &INonMessageModel {
+        match segment {
+            TextSegment::INonMessage(non_message) => non_message,
+            TextSegment::IMessage(other) => panic!("not a non-message: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn files_of_the_same_name_share_the_segment_database() {
+        let dir = test_dir("same-name");
+        for (sub, content) in [
+            ("same_name_one", ".message 1 first\n"),
+            ("same_name_two", ".message 2 second\n.message 3 third\n"),
+        ] {
+            create_dir_all(dir.join(sub)).unwrap();
+            write(dir.join(sub).join("scene.musica"), content).unwrap();
+        }
+        let names = collect_sources(&dir)
+            .unwrap()
+            .iter()
+            .map(|entry| source_name(&dir, entry.path()))
+            .collect::<ParserResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            names,
+            ["same_name_one/scene.musica", "same_name_two/scene.musica"]
+        );
+
+        let (config, gate) = (
+            ParserConfig::default(),
+            WriteGate::new(&StorageConfig::default()),
+        );
+        for name in &names {
+            parse_source(dir.join(name), name.clone(), &config, &gate)
+                .await
+                .unwrap();
+        }
+        let mut messages = Vec::new();
+        for name in &names {
+            let db = create_db_connection(name).await.unwrap();
+            let ids = load_messages(db.clone()).await.unwrap();
+            messages.push(
+                ids.into_iter()
+                    .map(|message| message.id)
+                    .collect::<Vec<_>>(),
+            );
+            assert!(Arc::ptr_eq(
+                &db.connection(),
+                &open_segments_db().await.unwrap()
+            ));
+        }
+        assert_eq!(
+            messages,
+            [vec![MessageId(1)], vec![MessageId(2), MessageId(3)]]
+        );
+        let files = list_files(open_segments_db().await.unwrap()).await.unwrap();
+        assert!(names.iter().all(|name| files.contains(name)));
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files_outside_the_source_dir_are_named_by_their_file_name() {
+        let name = source_name(Path::new("scripts"), Path::new("elsewhere/b.sc")).unwrap();
+        assert_eq!(name, "b.sc");
+    }
+
+    #[test]
+    fn statements_keep_their_indentation() {
+        let segments = parse("  .bg 01\n\t.message 1 こんにちは\n\u{3000}; note  \n");
+        assert_eq!(non_message(&segments[0]).content, "  .bg 01");
+        assert_eq!(message(&segments[1]).indent, "\t");
+        assert_eq!(message(&segments[1]).content, "こんにちは");
+        assert_eq!(non_message(&segments[2]).content, "\u{3000}; note  ");
+    }
+
+    #[test]
+    fn full_width_message_ids_are_read_as_ascii_digits() {
+        let segments = parse(".message １２３ こんにちは\n.message 4５6 さようなら\n");
+        assert_eq!(message(&segments[0]).id, MessageId(123));
+        assert_eq!(message(&segments[1]).id, MessageId(456));
+    }
+
+    #[test]
+    fn quoted_and_unquoted_parts_make_up_one_message() {
+        let segments = parse(concat!(
+            ".message 5 アリス 「中」 後\n",
+            ".message 6 前 \"中; 続き\" 後 ;note\n",
+            ".message 7 アリス 「中」 ;note\n",
+        ));
+        assert_eq!(segments.len(), 3);
+        let named = message(&segments[0]);
+        assert_eq!(
+            (named.name.as_str(), named.content.as_str()),
+            ("アリス", "「中」 後")
+        );
+        assert_eq!(named.brackets.as_deref(), Some(""));
+        let unnamed = message(&segments[1]);
+        assert_eq!(unnamed.content, "前 \"中; 続き\" 後");
+        assert_eq!(unnamed.comment.as_deref(), Some(" ;note"));
+        let quoted = message(&segments[2]);
+        assert_eq!(
+            (quoted.content.as_str(), quoted.brackets.as_deref()),
+            ("中", None)
+        );
+    }
+
+    #[test]
+    fn trailing_comments_are_split_off_messages() {
+        let segments =
+            parse(".message 1 こんにちは ;greeting\n.message 2 アリス 「ありがとう」\t; thanks\n");
+        assert_eq!(message(&segments[0]).content, "こんにちは");
+        assert_eq!(message(&segments[0]).comment.as_deref(), Some(" ;greeting"));
+        assert_eq!(message(&segments[1]).content, "ありがとう");
+        assert_eq!(message(&segments[1]).comment.as_deref(), Some("\t; thanks"));
+    }
+
+    #[test]
+    fn blank_lines_are_kept_as_empty_non_messages() {
+        let segments = parse(".message 1 こんにちは\n\n\n.message 2 さようなら\n");
+        assert_eq!(segments.len(), 4);
+        for blank in &segments[1..3] {
+            assert_eq!(non_message(blank).content, "");
+        }
+        assert_eq!(message(&segments[3]).line, Line(4));
+    }
+
+    #[test]
+    fn segments_are_on_their_source_lines() {
+        let content = "; intro\n\n.message 1 こんにちは\n.bg 01\n\n\n.message 2 さようなら\n\
+                       .message 3 アリス 「一行目\n二行目\n三行目」\n; outro\n.message 4 またね\n";
+        let segments = parse(content);
+        let lines = segments
+            .iter()
+            .map(|segment| segment.line().0)
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [1, 2, 3, 4, 5, 6, 7, 8, 11, 12]);
+        assert_eq!(message(&segments[7]).content, "一行目\n二行目\n三行目");
+    }
+
+    #[test]
+    fn chunks_parsed_in_parallel_keep_their_source_lines() {
+        let content = (1..=40)
+            .map(|id| format!(".message {id} こんにちは\n.bg {id}\n\n"))
+            .collect::<String>();
+        let config = ParserConfig::default();
+        let mut sequential = VecSink::default();
+        parse_content(&content, &config, &mut sequential).unwrap();
+        let mut parallel = VecSink::default();
+        parse_content_parallel(&content, 4, &config, &mut parallel).unwrap();
+        assert!(split_chunks(&content, 4).len() > 1);
+        assert_eq!(parallel.segments, sequential.segments);
+        assert_eq!(sequential.segments.last().unwrap().line(), Line(120));
+    }
+
+    #[tokio::test]
+    async fn parse_file_inserts_the_parsed_segments() {
+        let dir = test_dir("parse-file");
+        let path = dir.join("parse_file.sc");
+        write(
+            &path,
+            ".message 1 こんにちは\n.bg 01\n.message 2 さようなら\n",
+        )
+        .unwrap();
+        let db = create_db_connection("parse_file.sc").await.unwrap();
+        let config = ParserConfig {
+            insert_batch_size: 1,
+            ..ParserConfig::default()
+        };
+        let gate = WriteGate::new(&StorageConfig::default());
+        parse_file(path, "parse_file.sc".into(), &config, &gate)
+            .await
+            .unwrap();
+
+        let contents = load_messages(db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|message| message.content)
+            .collect::<Vec<_>>();
+        assert_eq!(contents, ["こんにちは", "さようなら"]);
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parse_string_needs_no_database() {
+        let segments = parse_string(
+            ".message 1 こんにちは\n.bg 01\n",
+            "never_stored.sc",
+            &ParserConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(message(&segments[0]).content, "こんにちは");
+        assert_eq!(non_message(&segments[1]).content, ".bg 01");
+        assert_eq!(segments.len(), 2);
+    }
+
+    #[test]
+    fn parse_string_merges_non_messages_if_configured() {
+        let config = ParserConfig {
+            merge_non_messages: true,
+            ..ParserConfig::default()
+        };
+        let segments =
+            parse_string("; a\n.bg 01\n.message 1 こんにちは\n", "t.sc", &config).unwrap();
+        assert_eq!(non_message(&segments[0]).content, "; a\n.bg 01");
+        assert_eq!(segments.len(), 2);
+    }
+
+    #[test]
+    fn parse_errors_name_the_file_line_and_column() {
+        let content = ".message 1 こんにちは\n.message oops\n";
+        let error = parse_string(content, "broken.sc", &ParserConfig::default()).unwrap_err();
+        let parse_error = error.downcast_ref::<ParseError>().unwrap();
+        assert_eq!(
+            parse_error,
+            &ParseError {
+                file: "broken.sc".into(),
+                line: 2,
+                col: 10,
+                rule: Some("MessageNumber".into()),
+            }
+        );
+        assert!(format!("{error:#}").contains("`broken.sc` does not parse at 2:10"));
+    }
+
+    #[test]
+    fn message_ids_out_of_range_name_the_file_and_line() {
+        let content = ".message 1 こんにちは\n\n.message 99999999999 さようなら\n";
+        let error = parse_string(content, "huge.sc", &ParserConfig::default()).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ParseError>().unwrap(),
+            &ParseError {
+                file: "huge.sc".into(),
+                line: 3,
+                col: 10,
+                rule: Some("MessageNumber".into()),
+            }
+        );
+        assert!(format!("{error:#}").contains("`huge.sc` does not parse at 3:10"));
+    }
+
+    #[test]
+    fn parse_errors_of_chunks_are_on_source_lines() {
+        let mut content = (1..=20)
+            .map(|id| format!(".message {id} こんにちは\n\n"))
+            .collect::<String>();
+        content.push_str(".message oops\n");
+        let config = ParserConfig {
+            chunks: Some(4),
+            ..ParserConfig::default()
+        };
+        let error = parse_string(&content, "broken.sc", &config).unwrap_err();
+        assert_eq!(error.downcast_ref::<ParseError>().unwrap().line, 41);
+    }
+
+    #[test]
+    fn lenient_parsing_collects_every_broken_line() {
+        let content = concat!(
+            ".message 1 こんにちは\n",
+            ".message oops\n",
+            ".bg 01\n",
+            ".message\n",
+            ".message 2 さようなら\n",
+            ".message 3\n",
+        );
+        let (segments, errors) =
+            parse_string_lenient(content, "broken.sc", &ParserConfig::default()).unwrap();
+        assert_eq!(
+            errors
+                .iter()
+                .map(|error| (error.file.as_str(), error.line))
+                .collect::<Vec<_>>(),
+            [("broken.sc", 2), ("broken.sc", 4), ("broken.sc", 6)]
+        );
+        let lines = segments
+            .iter()
+            .map(|segment| segment.line().0)
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [1, 3, 5]);
+    }
+
+    #[test]
+    fn lenient_parsing_of_a_valid_file_reports_nothing() {
+        let content = ".message 1 こんにちは\n.bg 01\n";
+        let (segments, errors) =
+            parse_string_lenient(content, "fine.sc", &ParserConfig::default()).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(segments, parse(content));
+    }
+
+    #[test]
+    fn sources_are_decoded_without_their_bom() {
+        let dir = test_dir("decode");
+        let config = ParserConfig::default();
+
+        let bom = dir.join("bom.sc");
+        write(
+            &bom,
+            [
+                b"\xEF\xBB\xBF".as_slice(),
+                ".message 1 こんにちは\n".as_bytes(),
+            ]
+            .concat(),
+        )
+        .unwrap();
+        assert_eq!(
+            read_source(&bom, "bom.sc", &config).unwrap(),
+            ".message 1 こんにちは\n"
+        );
+
+        let text = ".message 1 こんにちは、今日はいい天気ですね。散歩に行きましょうか。\n";
+        let (encoded, _, _) = encoding_rs::SHIFT_JIS.encode(text);
+        let sjis = dir.join("sjis.sc");
+        write(&sjis, &encoded).unwrap();
+        assert_eq!(read_source(&sjis, "sjis.sc", &config).unwrap(), text);
+        let labelled = ParserConfig {
+            encoding: Some("Shift_JIS".into()),
+            ..ParserConfig::default()
+        };
+        assert_eq!(read_source(&sjis, "sjis.sc", &labelled).unwrap(), text);
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn strict_decoding_fails_files_that_do_not_decode() {
+        let dir = test_dir("strict-decode");
+        let path = dir.join("invalid.sc");
+        write(&path, b".message 1 \xFF\xFE\n").unwrap();
+        let config = ParserConfig {
+            encoding: Some("UTF-8".into()),
+            ..ParserConfig::default()
+        };
+        assert!(
+            read_source(&path, "invalid.sc", &config)
+                .unwrap()
+                .contains('\u{FFFD}')
+        );
+        let strict = ParserConfig {
+            strict_decoding: true,
+            ..config
+        };
+        let error = read_source(&path, "invalid.sc", &strict).unwrap_err();
+        assert!(format!("{error:#}").contains("`invalid.sc` is not valid UTF-8"));
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn includes_are_expanded_relative_to_the_including_file() {
+        let dir = test_dir("includes");
+        create_dir_all(dir.join("sub")).unwrap();
+        write(
+            dir.join("main.sc"),
+            ".message 1 前\n#include \"sub/part.sc\"\n.message 3 後\n",
+        )
+        .unwrap();
+        write(
+            dir.join("sub/part.sc"),
+            "\n.message 2 中\n#include end.sc\n",
+        )
+        .unwrap();
+        write(dir.join("sub/end.sc"), ".message 4 終\n").unwrap();
+
+        let segments =
+            parse_with_includes(&dir.join("main.sc"), "main.sc", &ParserConfig::default()).unwrap();
+        let messages = segments
+            .iter()
+            .filter_map(|segment| {
+                let TextSegment::IMessage(message) = segment else {
+                    return None;
+                };
+                Some((
+                    message.origin.as_deref(),
+                    message.line.0,
+                    message.content.as_str(),
+                ))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                (None, 1, "前"),
+                (Some("sub/part.sc"), 2, "中"),
+                (Some("sub/end.sc"), 1, "終"),
+                (None, 3, "後")
+            ]
+        );
+        assert_eq!(
+            non_message(&segments[1]).include.as_deref(),
+            Some("sub/part.sc")
+        );
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn parse_file_stores_the_segments_of_included_files() {
+        let dir = test_dir("parse-file-includes");
+        write(dir.join("a.musica"), ".message 1 前\n#include b.musica\n").unwrap();
+        write(dir.join("b.musica"), ".message 1 中\n").unwrap();
+        let db = create_db_connection("parse-file-includes/a.musica")
+            .await
+            .unwrap();
+        let gate = WriteGate::new(&StorageConfig::default());
+        let name = "parse-file-includes/a.musica".to_string();
+        parse_file(dir.join("a.musica"), name, &ParserConfig::default(), &gate)
+            .await
+            .unwrap();
+
+        let messages = load_messages(db.clone())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|message| (message.origin, message.content))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                (None, "前".to_string()),
+                (Some("b.musica".to_string()), "中".to_string())
+            ]
+        );
+        assert_eq!(list_includes(db).await.unwrap(), ["b.musica"]);
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn include_cycles_are_an_error() {
+        let dir = test_dir("include-cycle");
+        write(dir.join("a.sc"), "#include b.sc\n").unwrap();
+        write(dir.join("b.sc"), "#include a.sc\n").unwrap();
+
+        let error =
+            parse_with_includes(&dir.join("a.sc"), "a.sc", &ParserConfig::default()).unwrap_err();
+        let error = format!("{error:#}");
+        assert!(error.contains("Include cycle"), "{error}");
+        assert!(
+            error.contains("a.sc -> ") && error.contains("b.sc -> "),
+            "{error}"
+        );
+        remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,566 @@
+use anyhow::{Context, Result as AnyResult};
+use apalis::{
+    layers::WorkerBuilderExt,
+    prelude::{Monitor, Storage, WorkerBuilder, WorkerFactoryFn},
+};
+use auto_context::auto_context as anyhow_context;
+use chrono::Utc;
+use std::{
+    io,
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::RwLock,
+    time::{interval, timeout},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    analyzer::analyzer_main,
+    assembler::assembler_main,
+    config::Config,
+    jobs::{
+        AnalyzerJob, AnalyzerJobQueue, AssemblerJob, AssemblerJobQueue, DispatchJob,
+        DispatchJobQueue, Job, Manifest, ParserJob, ParserJobQueue, StageWiring, TranslatorJob,
+        TranslatorJobQueue, build_manifest, checkpoint, dispatch_main, job_exists,
+        open_job_storage, run_checkpoints, validate_wiring, write_manifest,
+    },
+    parser::{parser_main, source_name},
+    storage::{
+        FileDb, PipelineStage, WriteGate, clear_stage, clear_stage_failures, create_db_connection,
+        is_stage_done, open_project_db, record_run, stage_failure,
+    },
+    translator::{
+        SharedTranslator, build_file_translators, build_localized_translators, build_translator,
+        translation_cache, translator_main,
+    },
+};
+
+/// The queues [`Pipeline::run`] pushes the source files to.
+const SEEDED: &[&str] = &[ParserJob::NAME, DispatchJob::NAME];
+
+/// The workers [`Pipeline::run`] registers, by the queues they are handed. Keep
+/// in sync with the registration, it is validated before the monitor starts.
+const WIRING: &[StageWiring] = &[
+    StageWiring {
+        consumes: ParserJob::NAME,
+        feeds: &[DispatchJob::NAME],
+    },
+    StageWiring {
+        consumes: DispatchJob::NAME,
+        feeds: &[AnalyzerJob::NAME, TranslatorJob::NAME],
+    },
+    StageWiring {
+        consumes: AnalyzerJob::NAME,
+        feeds: &[TranslatorJob::NAME],
+    },
+    StageWiring {
+        consumes: TranslatorJob::NAME,
+        feeds: &[AssemblerJob::NAME],
+    },
+    StageWiring {
+        consumes: AssemblerJob::NAME,
+        feeds: &[],
+    },
+];
+
+/// What [`Pipeline::run`] did, as also written to `manifest.json`.
+#[derive(Debug)]
+pub struct RunSummary {
+    pub manifest: Manifest,
+    /// Where the manifest was written.
+    pub manifest_path: PathBuf,
+    /// Whether the run was stopped at its maximum runtime, see `--max-runtime`.
+    pub timed_out: bool,
+    /// Whether the run was stopped through its [`Pipeline::cancel_token`].
+    pub cancelled: bool,
+    /// The files a stage failed on, with the stage and its error. The run goes
+    /// on with the other files.
+    pub failed: Vec<(String, PipelineStage, String)>,
+}
+
+/// Every stage for a set of source files: sets up the job queues, registers
+/// a worker per stage and runs them until every file is assembled.
+pub struct Pipeline {
+    config: Arc<Config>,
+    retry_failed: bool,
+    skip_parsed: bool,
+    only_stage: Option<PipelineStage>,
+    max_runtime: Option<Duration>,
+    cancel: CancellationToken,
+    translator: Option<SharedTranslator>,
+}
+
+impl Pipeline {
+    pub fn new(config: Arc<Config>) -> Self {
+        Pipeline {
+            config,
+            retry_failed: false,
+            skip_parsed: false,
+            only_stage: None,
+            max_runtime: None,
+            cancel: CancellationToken::new(),
+            translator: None,
+        }
+    }
+
+    /// Attempt sources again that the backend refused on an earlier run.
+    pub fn retry_failed(mut self, retry_failed: bool) -> Self {
+        self.retry_failed = retry_failed;
+        self
+    }
+
+    /// Don't reparse files whose parser stage is already done, see `--resume`.
+    pub fn skip_parsed(mut self, skip_parsed: bool) -> Self {
+        self.skip_parsed = skip_parsed;
+        self
+    }
+
+    /// Run only the worker of `stage`, with its queue seeded from `sources`
+    /// directly and on the databases they already have, see `--only-stage`.
+    pub fn only_stage(mut self, stage: Option<PipelineStage>) -> Self {
+        self.only_stage = stage;
+        self
+    }
+
+    /// Stop the workers once `max_runtime` passed, letting the jobs in flight
+    /// finish, and checkpoint, see `--max-runtime`.
+    pub fn max_runtime(mut self, max_runtime: Option<Duration>) -> Self {
+        self.max_runtime = max_runtime;
+        self
+    }
+
+    /// Stop the run once `cancel` is cancelled, e.g. from a GUI: translations
+    /// in flight are abandoned, the other jobs in flight finish, and the run
+    /// checkpoints like at `--max-runtime`.
+    pub fn cancel_token(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Translate with `translator` instead of the backend `translator` configures,
+    /// e.g. a local model from embedding code. `translator.file_overrides` and
+    /// the additional languages still use their configured backends.
+    pub fn translator(mut self, translator: SharedTranslator) -> Self {
+        self.translator = Some(translator);
+        self
+    }
+
+    /// Runs the pipeline on `sources`, in order, and summarizes the result into
+    /// `manifest.json` in the output directory.
+    #[anyhow_context]
+    pub async fn run(self, sources: Vec<PathBuf>) -> AnyResult<RunSummary> {
+        if self.only_stage.is_none() {
+            validate_wiring(WIRING, SEEDED)?;
+        }
+        let awaited = self.only_stage.unwrap_or(PipelineStage::Assembler);
+        let started_at = Utc::now();
+        let config = self.config;
+        if config.storage.database_dir.is_some() {
+            record_run(open_project_db().await?, started_at).await?;
+        }
+
+        let pool = open_job_storage(config.storage.job_storage.as_deref()).await?;
+
+        let mut parser_jobs = ParserJobQueue::new(pool.clone());
+        let mut assembler_jobs = AssemblerJobQueue::new(pool.clone());
+        let mut analyzer_jobs = AnalyzerJobQueue::new(pool.clone());
+        let mut translator_jobs = TranslatorJobQueue::new(pool.clone());
+        let mut dispatch_jobs = DispatchJobQueue::new(pool.clone());
+
+        let mut file_names = Vec::with_capacity(sources.len());
+        let mut file_dbs = Vec::with_capacity(sources.len());
+        for file_path in sources {
+            let file_name = source_name(&config.parser.source_dir, &file_path)?;
+            let db = create_db_connection(&file_name).await?;
+            file_names.push(file_name.clone());
+            file_dbs.push(db.clone());
+            if job_exists::<ParserJob>(&pool, &file_path).await?
+                || job_exists::<DispatchJob>(&pool, &file_path).await?
+            {
+                println!("[{file_name}] already queued, not enqueueing it again");
+                continue;
+            }
+            // Done or failed in an earlier run, which must not end this one right away.
+            clear_stage(db.clone(), awaited).await?;
+            clear_stage_failures(db.clone()).await?;
+            let parsed = self.only_stage.is_none()
+                && self.skip_parsed
+                && is_stage_done(db, PipelineStage::Parser).await?;
+            match self.only_stage {
+                None if parsed => {
+                    dispatch_jobs
+                        .push(DispatchJob {
+                            file_path,
+                            file_name,
+                        })
+                        .await?;
+                }
+                None | Some(PipelineStage::Parser) => {
+                    parser_jobs
+                        .push(ParserJob {
+                            file_path,
+                            file_name,
+                        })
+                        .await?;
+                }
+                Some(PipelineStage::Analyzer) => {
+                    analyzer_jobs
+                        .push(AnalyzerJob {
+                            file_path,
+                            file_name,
+                        })
+                        .await?;
+                }
+                Some(PipelineStage::Translator) => {
+                    translator_jobs
+                        .push(TranslatorJob {
+                            file_path,
+                            file_name,
+                        })
+                        .await?;
+                }
+                Some(PipelineStage::Assembler) => {
+                    assembler_jobs
+                        .push(AssemblerJob {
+                            file_path,
+                            file_name,
+                        })
+                        .await?;
+                }
+            }
+        }
+
+        // Stopped when `run` returns, however it does, not to outlive the run.
+        let checkpoints = self.cancel.child_token();
+        let _checkpointing = checkpoints.clone().drop_guard();
+        if config.storage.database_dir.is_some() {
+            let every = Duration::from_secs(config.storage.checkpoint_interval);
+            let files = file_names.clone();
+            tokio::spawn(async move {
+                let checkpointed = checkpoints.run_until_cancelled(run_checkpoints(files, every));
+                if let Some(Err(error)) = checkpointed.await {
+                    eprintln!("Checkpointing stopped: {error:#}");
+                }
+            });
+        }
+
+        let translator = match self.translator {
+            Some(translator) => translator,
+            None => build_translator(&config.translator, self.retry_failed).await?,
+        };
+        let overrides = build_file_translators(&config.translator, self.retry_failed).await?;
+        let localized = build_localized_translators(&config.translator, self.retry_failed).await?;
+        let cache = translation_cache(&config.translator).await?;
+        let gate = WriteGate::new(&config.storage);
+        // Workers of other stages aren't registered, their queues just fill up.
+        let only_stage = self.only_stage;
+        let runs = |stage| only_stage.is_none_or(|only| only == stage);
+        let mut monitor = Monitor::new();
+        if runs(PipelineStage::Parser) {
+            monitor = monitor.register({
+                WorkerBuilder::new(ParserJob::NAME)
+                    .data(Arc::new(RwLock::new(dispatch_jobs.clone())))
+                    .data(config.clone())
+                    .data(gate.clone())
+                    .concurrency(config.parser.workers)
+                    .backend(parser_jobs)
+                    .build_fn(parser_main)
+            });
+        }
+        if only_stage.is_none() {
+            monitor = monitor.register({
+                WorkerBuilder::new(DispatchJob::NAME)
+                    .data(Arc::new(RwLock::new(analyzer_jobs.clone())))
+                    .data(Arc::new(RwLock::new(translator_jobs.clone())))
+                    .data(config.clone())
+                    .concurrency(config.dispatcher.workers)
+                    .backend(dispatch_jobs)
+                    .build_fn(dispatch_main)
+            });
+        }
+        if runs(PipelineStage::Analyzer) {
+            monitor = monitor.register({
+                WorkerBuilder::new(AnalyzerJob::NAME)
+                    .data(Arc::new(RwLock::new(translator_jobs.clone())))
+                    .data(config.clone())
+                    .concurrency(config.analyzer.workers)
+                    .backend(analyzer_jobs)
+                    .build_fn(analyzer_main)
+            });
+        }
+        if runs(PipelineStage::Translator) {
+            monitor = monitor.register({
+                WorkerBuilder::new(TranslatorJob::NAME)
+                    .data(translator)
+                    .data(overrides)
+                    .data(localized)
+                    .data(Arc::new(RwLock::new(assembler_jobs.clone())))
+                    .data(config.clone())
+                    .data(gate)
+                    .data(cache)
+                    .data(self.cancel.clone())
+                    .concurrency(config.translator.workers)
+                    .backend(translator_jobs)
+                    .build_fn(translator_main)
+            });
+        }
+        if runs(PipelineStage::Assembler) {
+            monitor = monitor.register({
+                WorkerBuilder::new(AssemblerJob::NAME)
+                    .data(config.clone())
+                    .concurrency(config.assembler.workers)
+                    .backend(assembler_jobs)
+                    .build_fn(assembler_main)
+            });
+        }
+        let max_runtime = self.max_runtime;
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let stopped = timed_out.clone();
+        let cancel = self.cancel.clone();
+        let dbs = file_dbs.clone();
+        monitor
+            .run_with_signal(async move {
+                let done = async {
+                    let done = wait_until_done(&dbs, awaited);
+                    let Some(max_runtime) = max_runtime else {
+                        return done.await.map_err(io::Error::other);
+                    };
+                    match timeout(max_runtime, done).await {
+                        Ok(done) => done.map_err(io::Error::other),
+                        Err(_) => {
+                            eprintln!("Ran for {max_runtime:?}, stopping after the jobs in flight");
+                            stopped.store(true, Ordering::Relaxed);
+                            Ok(())
+                        }
+                    }
+                };
+                match cancel.run_until_cancelled(done).await {
+                    Some(done) => done,
+                    None => {
+                        eprintln!("Cancelled, stopping after the jobs in flight");
+                        Ok(())
+                    }
+                }
+            })
+            .await?;
+        let timed_out = timed_out.load(Ordering::Relaxed);
+        let cancelled = self.cancel.is_cancelled();
+        if (timed_out || cancelled) && config.storage.database_dir.is_some() {
+            checkpoint(&file_names).await?;
+        }
+
+        let mut failed = Vec::new();
+        for db in file_dbs {
+            if let Some((stage, error)) = stage_failure(db.clone()).await? {
+                failed.push((db.name().to_owned(), stage, error));
+            }
+        }
+
+        let output_dir = &config.assembler.output_dir;
+        let manifest = build_manifest(&file_names, output_dir, started_at).await?;
+        let manifest_path = write_manifest(&manifest, output_dir)?;
+        Ok(RunSummary {
+            manifest,
+            manifest_path,
+            timed_out,
+            cancelled,
+            failed,
+        })
+    }
+}
+
+/// Resolves once `stage` is done for every file of `dbs`, the ones of the run,
+/// or one of its stages failed, see [`crate::jobs::record_failure`].
+#[anyhow_context]
+async fn wait_until_done(dbs: &[FileDb], stage: PipelineStage) -> AnyResult<()> {
+    let mut ticks = interval(Duration::from_secs(1));
+    'waiting: loop {
+        ticks.tick().await;
+        for db in dbs {
+            if !is_stage_done(db.clone(), stage).await?
+                && stage_failure(db.clone()).await?.is_none()
+            {
+                continue 'waiting;
+            }
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::{count_segments, lookup_term},
+        translator::Translator,
+        utils::test_dir,
+    };
+    use futures::future::BoxFuture;
+    use std::fs::{create_dir_all, read_to_string, remove_dir_all, write};
+
+    /// Translates by wrapping the source in brackets, without a network.
+    struct Bracketing;
+
+    impl Translator for Bracketing {
+        fn translate<'a>(&'a self, source: &'a str) -> BoxFuture<'a, AnyResult<String>> {
+            Box::pin(async move { Ok(format!("[{source}]")) })
+        }
+
+        fn backend(&self) -> String {
+            "test/bracketing".into()
+        }
+
+        fn prompt(&self) -> &str {
+            ""
+        }
+    }
+
+    /// Fails every source as if the backend were down.
+    struct Unreachable;
+
+    impl Translator for Unreachable {
+        fn translate<'a>(&'a self, _source: &'a str) -> BoxFuture<'a, AnyResult<String>> {
+            Box::pin(async { anyhow::bail!("Backend unreachable") })
+        }
+
+        fn backend(&self) -> String {
+            "test/unreachable".into()
+        }
+
+        fn prompt(&self) -> &str {
+            ""
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_file_goes_through_every_stage() {
+        let dir = test_dir("pipeline");
+        let (source_dir, output_dir) = (dir.join("sources"), dir.join("output"));
+        create_dir_all(&source_dir).unwrap();
+        let source = source_dir.join("pipeline_flow.sc");
+        write(
+            &source,
+            "; opening\n.message 1 こんにちは、元気ですか\n.bg 01\n\n.message 2 明日また会いましょう\n",
+        )
+        .unwrap();
+        let mut config = Config::default();
+        config.parser.source_dir = source_dir;
+        config.assembler.output_dir = output_dir.clone();
+
+        let summary = Pipeline::new(Arc::new(config))
+            .translator(Arc::new(Bracketing))
+            .run(vec![source])
+            .await
+            .unwrap();
+        assert!(!summary.timed_out && !summary.cancelled);
+        assert_eq!(
+            read_to_string(output_dir.join("pipeline_flow.sc")).unwrap(),
+            "; opening\n.message 1 [こんにちは、元気ですか]\n.bg 01\n\n.message 2 [明日また会いましょう]\n"
+        );
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_failing_stage_ends_the_run_with_the_file_failed() {
+        let dir = test_dir("pipeline-failing");
+        let (source_dir, output_dir) = (dir.join("sources"), dir.join("output"));
+        create_dir_all(&source_dir).unwrap();
+        let source = source_dir.join("pipeline_failing.sc");
+        write(&source, ".message 1 こんにちは、元気ですか\n").unwrap();
+        let mut config = Config::default();
+        config.parser.source_dir = source_dir;
+        config.assembler.output_dir = output_dir.clone();
+
+        let run = Pipeline::new(Arc::new(config))
+            .translator(Arc::new(Unreachable))
+            .run(vec![source]);
+        let summary = timeout(Duration::from_secs(30), run)
+            .await
+            .expect("the run should end")
+            .unwrap();
+        let [(name, stage, error)] = summary.failed.as_slice() else {
+            panic!("one file should have failed: {:?}", summary.failed);
+        };
+        assert_eq!(
+            (name.as_str(), *stage),
+            ("pipeline_failing.sc", PipelineStage::Translator)
+        );
+        assert!(error.contains("Backend unreachable"), "{error}");
+        let file = &summary.manifest.files[0];
+        assert!(file.output.is_none());
+        assert!(file.stages.iter().any(|stage| stage.error.is_some()));
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn running_twice_parses_the_file_again_instead_of_twice() {
+        let dir = test_dir("pipeline-twice");
+        let (source_dir, output_dir) = (dir.join("sources"), dir.join("output"));
+        create_dir_all(&source_dir).unwrap();
+        let source = source_dir.join("pipeline_twice.sc");
+        write(&source, ".message 1 こんにちは\n.bg 01\n").unwrap();
+        let mut config = Config::default();
+        config.parser.source_dir = source_dir;
+        config.assembler.output_dir = output_dir.clone();
+        let config = Arc::new(config);
+
+        let mut segments = Vec::new();
+        for _ in 0..2 {
+            Pipeline::new(config.clone())
+                .translator(Arc::new(Bracketing))
+                .run(vec![source.clone()])
+                .await
+                .unwrap();
+            let db = create_db_connection("pipeline_twice.sc").await.unwrap();
+            segments.push(count_segments(db).await.unwrap());
+        }
+        assert_eq!(segments, [2, 2]);
+        assert_eq!(
+            read_to_string(output_dir.join("pipeline_twice.sc")).unwrap(),
+            ".message 1 [こんにちは]\n.bg 01\n"
+        );
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn the_name_glossary_outlives_the_jobs_using_it() {
+        let dir = test_dir("pipeline-names");
+        let (source_dir, output_dir) = (dir.join("sources"), dir.join("output"));
+        create_dir_all(&source_dir).unwrap();
+        let sources = ["names_a.sc", "names_b.sc"].map(|name| source_dir.join(name));
+        for source in &sources {
+            write(source, ".message 1 アリス 「こんにちは」\n").unwrap();
+        }
+        let mut config = Config::default();
+        config.parser.source_dir = source_dir;
+        config.assembler.output_dir = output_dir.clone();
+        config.glossary.translate_names = true;
+
+        Pipeline::new(Arc::new(config))
+            .translator(Arc::new(Bracketing))
+            .run(sources.to_vec())
+            .await
+            .unwrap();
+        let glossary = open_project_db().await.unwrap();
+        assert_eq!(
+            lookup_term(glossary, "アリス").await.unwrap().as_deref(),
+            Some("[アリス]")
+        );
+        assert_eq!(
+            read_to_string(output_dir.join("names_b.sc")).unwrap(),
+            ".message 1 [アリス] 「[こんにちは]」\n"
+        );
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_stages_are_wired_up() {
+        validate_wiring(WIRING, SEEDED).unwrap();
+    }
+}
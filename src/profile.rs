@@ -0,0 +1,98 @@
+//! Lightweight timing of the hot paths for `--profile`, summed over every file
+//! and worker. Timing is a no-op until [`enable`] is called.
+
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// A hot path timed by `--profile`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// The pest parse of a file or chunk, without storing its segments.
+    Parse,
+    /// Inserting parsed segments into the segment database.
+    Insert,
+    /// Waiting for the translation backend.
+    Translate,
+    /// Rendering and writing an output file.
+    Assemble,
+}
+
+impl Phase {
+    const ALL: [Phase; 4] = [
+        Phase::Parse,
+        Phase::Insert,
+        Phase::Translate,
+        Phase::Assemble,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Parse => "parse",
+            Phase::Insert => "insert",
+            Phase::Translate => "translate",
+            Phase::Assemble => "assemble",
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NANOS: [AtomicU64; Phase::ALL.len()] = [const { AtomicU64::new(0) }; Phase::ALL.len()];
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+fn record(phase: Phase, elapsed: Duration) {
+    NANOS[phase as usize].fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Runs `f`, adding the time it takes to `phase`.
+pub fn time<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return f();
+    }
+    let started = Instant::now();
+    let value = f();
+    record(phase, started.elapsed());
+    value
+}
+
+/// Awaits `future`, adding the time until it completes to `phase`.
+pub async fn time_async<T>(phase: Phase, future: impl Future<Output = T>) -> T {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return future.await;
+    }
+    let started = Instant::now();
+    let value = future.await;
+    record(phase, started.elapsed());
+    value
+}
+
+/// The share of each phase in the time recorded so far, e.g.
+/// `parse 12% (1.2s), insert 31% (3.1s), translate 55% (5.5s), assemble 2% (0.2s)`.
+/// `None` if nothing was recorded.
+pub fn breakdown() -> Option<String> {
+    let nanos = NANOS
+        .iter()
+        .map(|nanos| nanos.load(Ordering::Relaxed))
+        .collect::<Vec<_>>();
+    let total = nanos.iter().sum::<u64>();
+    if total == 0 {
+        return None;
+    }
+    let shares = Phase::ALL
+        .iter()
+        .zip(nanos)
+        .map(|(phase, nanos)| {
+            format!(
+                "{} {}% ({:.1}s)",
+                phase.name(),
+                (nanos as f64 / total as f64 * 100.0).round(),
+                Duration::from_nanos(nanos).as_secs_f64()
+            )
+        })
+        .collect::<Vec<_>>();
+    Some(shares.join(", "))
+}
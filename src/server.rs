@@ -0,0 +1,235 @@
+//! A minimal JSON API over the on-disk segment database, for review tools.
+//!
+//! Every route takes the file as `?file=<name>`, the same name the subcommands use.
+//!
+//! - `GET /segments?file=a.sc[&q=text][&translated=true]` lists [`SegmentView`]s,
+//!   optionally only those whose source (or translation) contains `q`.
+//! - `GET /segments/{row_id}?file=a.sc` returns one [`SegmentView`].
+//! - `PATCH /segments/{row_id}?file=a.sc` applies a [`SegmentPatch`] and returns the
+//!   updated [`SegmentView`]. Edits are recorded in the translation audit, and
+//!   approving a segment locks it against automated translation runs.
+//!
+//! A [`SegmentView`] looks like
+//!
+//! ```json
+//! {
+//!   "row_id": 2,
+//!   "fingerprint": "5f1c…",
+//!   "locked": false,
+//!   "segment": { "type": "IMessage", "line": 1, "id": 1, "name": "", "tachie": "", "content": "い" },
+//!   "translation": { "content": "I", "notes": "check tone", "status": "needs_work" }
+//! }
+//! ```
+//!
+//! with `translation` being `null` until the segment is translated.
+
+use crate::{
+    config::Config,
+    storage::{
+        AuditEntry, FileDb, MANUAL_BACKEND, ReviewStatus, RowId, TextSegment, TextSegmentEntity,
+        Translation, create_db_connection, find_segment, list_files, lock_segment,
+        record_translation, search_segments,
+    },
+    utils::IntoAnyResult,
+};
+use anyhow::{Context, Result as AnyResult, bail};
+use auto_context::auto_context as anyhow_context;
+use axum::{
+    Json, Router,
+    extract::{Path, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+#[allow(unused)]
+type ServerResult<T> = Result<T, ServerError>;
+
+#[derive(Debug, Serialize)]
+pub struct SegmentView {
+    pub row_id: RowId,
+    pub fingerprint: String,
+    pub locked: bool,
+    pub segment: TextSegment,
+    pub translation: Option<Translation>,
+}
+
+/// Fields left out are kept as they are; `notes: null` clears the notes.
+#[derive(Debug, Deserialize)]
+pub struct SegmentPatch {
+    pub translated: Option<String>,
+    #[serde(default, with = "double_option")]
+    pub notes: Option<Option<String>>,
+    pub status: Option<ReviewStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileQuery {
+    file: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+    file: String,
+    #[serde(default)]
+    q: String,
+    #[serde(default)]
+    translated: bool,
+}
+
+/// Tells a missing `null` apart from an explicit one.
+mod double_option {
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+    where
+        T: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Option::deserialize(deserializer).map(Some)
+    }
+}
+
+struct ServerError(StatusCode, anyhow::Error);
+
+impl From<anyhow::Error> for ServerError {
+    fn from(error: anyhow::Error) -> Self {
+        ServerError(StatusCode::INTERNAL_SERVER_ERROR, error)
+    }
+}
+
+impl IntoResponse for ServerError {
+    fn into_response(self) -> Response {
+        (self.0, format!("{:#}", self.1)).into_response()
+    }
+}
+
+fn not_found(file: &str, row_id: RowId) -> ServerError {
+    ServerError(
+        StatusCode::NOT_FOUND,
+        anyhow::anyhow!("`{file}` has no segment {row_id}"),
+    )
+}
+
+#[anyhow_context]
+async fn open_file(file: &str) -> AnyResult<FileDb> {
+    let db = create_db_connection(file).await?;
+    if !list_files(db.connection())
+        .await?
+        .iter()
+        .any(|name| name == file)
+    {
+        bail!("No segments of `{file}`");
+    }
+    Ok(db)
+}
+
+#[anyhow_context]
+fn view(
+    (model, translation): (
+        <TextSegmentEntity as sea_orm::EntityTrait>::Model,
+        Option<Translation>,
+    ),
+) -> AnyResult<SegmentView> {
+    let (row_id, locked) = (model.row_id(), model.is_locked());
+    let segment = TextSegment::try_from(model)?;
+    Ok(SegmentView {
+        row_id,
+        fingerprint: segment.fingerprint(),
+        locked,
+        segment,
+        translation,
+    })
+}
+
+async fn list_segments(Query(query): Query<SearchQuery>) -> ServerResult<Json<Vec<SegmentView>>> {
+    let db = open_file(&query.file).await?;
+    let views = search_segments(db, &query.q, query.translated)
+        .await?
+        .into_iter()
+        .map(view)
+        .collect::<AnyResult<_>>()?;
+    Ok(Json(views))
+}
+
+async fn get_segment(
+    Path(row_id): Path<i32>,
+    Query(query): Query<FileQuery>,
+) -> ServerResult<Json<SegmentView>> {
+    let db = open_file(&query.file).await?;
+    let row_id = RowId(row_id);
+    match find_segment(db, row_id).await? {
+        Some(segment) => Ok(Json(view(segment)?)),
+        None => Err(not_found(&query.file, row_id)),
+    }
+}
+
+async fn patch_segment(
+    Path(row_id): Path<i32>,
+    Query(query): Query<FileQuery>,
+    Json(patch): Json<SegmentPatch>,
+) -> ServerResult<Json<SegmentView>> {
+    let db = open_file(&query.file).await?;
+    let row_id = RowId(row_id);
+    let Some((segment, translation)) = find_segment(db.clone(), row_id).await? else {
+        return Err(not_found(&query.file, row_id));
+    };
+    if segment.is_locked() {
+        return Err(ServerError(
+            StatusCode::CONFLICT,
+            anyhow::anyhow!("Segment {row_id} is locked, unlock it first"),
+        ));
+    }
+
+    let mut translation = match (translation, patch.translated) {
+        (Some(translation), None) => translation,
+        (Some(translation), Some(content)) => Translation {
+            content,
+            ..translation
+        },
+        (None, Some(content)) => Translation::new(content),
+        (None, None) => {
+            return Err(ServerError(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                anyhow::anyhow!("Segment {row_id} is not translated yet, set `translated`"),
+            ));
+        }
+    };
+    if let Some(notes) = patch.notes {
+        translation.notes = notes;
+    }
+    if let Some(status) = patch.status {
+        translation.status = status;
+    }
+
+    let audit = AuditEntry {
+        backend: MANUAL_BACKEND.into(),
+        prompt_hash: String::new(),
+        triggered_by: "server".into(),
+    };
+    let approved = translation.status == ReviewStatus::Approved;
+    record_translation(db.clone(), row_id, translation, audit).await?;
+    if approved {
+        lock_segment(db.clone(), row_id).await?;
+    }
+    let segment = find_segment(db, row_id).await?;
+    Ok(Json(view(segment.into_any_result()?)?))
+}
+
+/// Serves the API on `addr` until the process is stopped.
+#[anyhow_context]
+pub async fn serve(addr: &str, config: Arc<Config>) -> AnyResult<()> {
+    if config.storage.database_dir.is_none() {
+        bail!("The server requires `storage.database_dir` to be set");
+    }
+    let app = Router::new()
+        .route("/segments", get(list_segments))
+        .route("/segments/{row_id}", get(get_segment).patch(patch_segment));
+    let listener = TcpListener::bind(addr).await?;
+    println!("Serving on http://{}", listener.local_addr()?);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
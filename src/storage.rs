@@ -1,24 +1,257 @@
+use anyhow::{Context, Result as AnyResult};
+use auto_context::auto_context as anyhow_context;
+use futures::future::BoxFuture;
+use sea_orm::{
+    ConnectionTrait, DatabaseConnection, DbErr, EntityTrait, Iden, RuntimeErr, Schema, Statement,
+    sea_query::Table,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::Semaphore, time::sleep};
+
+use crate::config::StorageConfig;
+
+/// Bounds how many writes run at once across all workers, and retries writes
+/// that still fail because SQLite is busy. Shared by the workers as `Data`.
+#[derive(Clone, Debug)]
+pub struct WriteGate {
+    permits: Arc<Semaphore>,
+    retries: u32,
+}
+
+impl WriteGate {
+    pub fn new(config: &StorageConfig) -> Self {
+        WriteGate {
+            permits: Arc::new(Semaphore::new(config.max_concurrent_writes.max(1))),
+            retries: config.busy_retries,
+        }
+    }
+
+    /// Runs `write` once a permit is free, retrying it as [`retry_on_busy`] does.
+    pub async fn write<T, F, Fut>(&self, write: F) -> AnyResult<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = AnyResult<T>>,
+    {
+        let _permit = self.permits.acquire().await?;
+        retry_on_busy(self.retries, write).await
+    }
+}
+
+/// Runs `f`, and up to `retries` times again after a backoff of 50ms, 100ms, ...
+/// while it fails because SQLite is busy. Any other error is returned right away.
+pub async fn retry_on_busy<T, F, Fut>(retries: u32, mut f: F) -> AnyResult<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = AnyResult<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Err(error) if attempt < retries && is_busy(&error) => {
+                sleep(Duration::from_millis(50 << attempt)).await;
+                attempt += 1;
+            }
+            done => return done,
+        }
+    }
+}
+
+/// Primary result codes of SQLite giving up on a lock another connection, or
+/// another statement of the same one, holds. Extended codes keep them in the
+/// low byte, e.g. `SQLITE_BUSY_SNAPSHOT` is 517.
+const SQLITE_BUSY: i32 = 5;
+const SQLITE_LOCKED: i32 = 6;
+
+/// Whether `error` is SQLite being busy or locked, told by the result code of
+/// the database error sea-orm or sqlx failed with, not by its message.
+fn is_busy(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        let sqlx = match cause.downcast_ref::<DbErr>() {
+            Some(
+                DbErr::Conn(RuntimeErr::SqlxError(error))
+                | DbErr::Exec(RuntimeErr::SqlxError(error))
+                | DbErr::Query(RuntimeErr::SqlxError(error)),
+            ) => Some(error),
+            _ => cause.downcast_ref::<sqlx::Error>(),
+        };
+        let Some(sqlx::Error::Database(database)) = sqlx else {
+            return false;
+        };
+        database
+            .code()
+            .and_then(|code| code.parse::<i32>().ok())
+            .is_some_and(|code| matches!(code & 0xff, SQLITE_BUSY | SQLITE_LOCKED))
+    })
+}
+
+/// Runs `f` on the rows of the file `name`, in the segment database brought up
+/// to the current schema, so that no stage works on a database it forgot to
+/// migrate.
+pub async fn with_db<'f, F, R>(name: &str, f: F) -> AnyResult<R>
+where
+    F: FnOnce(FileDb) -> BoxFuture<'f, AnyResult<R>>,
+{
+    let db = create_db_connection(name).await?;
+    f(db).await
+}
+
+#[anyhow_context]
+async fn create_entity_table<C: ConnectionTrait, E: EntityTrait>(
+    db: &C,
+    entity: E,
+) -> AnyResult<()> {
+    let backend = db.get_database_backend();
+    let schema = Schema::new(backend);
+
+    let statement = backend.build(schema.create_table_from_entity(entity).if_not_exists());
+    db.execute(statement).await?;
+    Ok(())
+}
+
+/// Whether the table of `E` has `column`, e.g. in a database of an older version.
+#[anyhow_context]
+async fn column_exists<E: EntityTrait>(
+    db: &DatabaseConnection,
+    column: E::Column,
+) -> AnyResult<bool> {
+    has_column(db, E::default().table_name(), &column.to_string()).await
+}
+
+/// [`column_exists`] for columns no entity has anymore.
+#[anyhow_context]
+async fn has_column(db: &DatabaseConnection, table: &str, column: &str) -> AnyResult<bool> {
+    let exists = db
+        .query_one(Statement::from_sql_and_values(
+            db.get_database_backend(),
+            "SELECT 1 FROM pragma_table_info(?) WHERE name = ?",
+            [table.into(), column.into()],
+        ))
+        .await?
+        .is_some();
+    Ok(exists)
+}
+
+/// `ALTER TABLE ... ADD COLUMN` unless the column already exists, so that
+/// migrations adding columns stay idempotent.
+#[anyhow_context]
+async fn add_column_if_missing<E: EntityTrait>(
+    db: &DatabaseConnection,
+    column: E::Column,
+) -> AnyResult<()> {
+    let backend = db.get_database_backend();
+    if !column_exists::<E>(db, column).await? {
+        let schema = Schema::new(backend);
+        let statement = backend.build(
+            Table::alter()
+                .table(E::default())
+                .add_column(schema.get_column_def::<E>(column)),
+        );
+        db.execute(statement).await?;
+    }
+    Ok(())
+}
+
 pub mod text_segment {
     use anyhow::{Context, Result as AnyResult, bail};
     use auto_context::auto_context as anyhow_context;
+    use chrono::Utc;
     use derive_builder::Builder;
     use sea_orm::{
-        ActiveValue::Set, ConnectionTrait, Database, DatabaseConnection, IntoActiveModel, Schema,
+        ActiveValue::Set,
+        ConnectionTrait, Database, DatabaseConnection, IntoActiveModel, Iterable, QueryOrder,
+        QuerySelect, Schema, Select, TransactionTrait,
         entity::prelude::*,
+        sea_query::{Query, SelectStatement, SimpleExpr},
     };
     use serde::{Deserialize, Serialize};
     use serde_json::json;
-    use std::sync::Arc;
+    use sqlx::{Connection, SqliteConnection};
+    use std::{
+        collections::{HashMap, HashSet},
+        fmt::{self, Display, Formatter},
+        fs::create_dir_all,
+        ops::Deref,
+        path::{Path, PathBuf},
+        sync::{Arc, LazyLock, Mutex, OnceLock},
+        time::Duration,
+    };
+    use tokio::sync::OnceCell;
+
+    use super::{
+        pipeline_state,
+        schema_version::run_migrations,
+        segment_translation::{
+            self, load_localized, translated_into, translations_of, write_translation,
+        },
+        translation_audit, variable,
+    };
+    use crate::{
+        config::TranslatorConfig,
+        utils::{sha256_hex, width_and_case_glob},
+    };
+
+    /// The source line a segment starts on in its file, starting at 1.
+    #[derive(
+        Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+    )]
+    #[serde(transparent)]
+    pub struct Line(pub i32);
+
+    /// The number following `.message`, chosen by the script author.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+    #[serde(transparent)]
+    pub struct MessageId(pub i32);
+
+    /// Primary key of a row in `text_segments`.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+    #[serde(transparent)]
+    pub struct RowId(pub i32);
+
+    impl Display for Line {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    impl Display for MessageId {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    impl Display for RowId {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
 
     #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
     #[sea_orm(table_name = "text_segments")]
     pub struct Model {
-        #[sea_orm(primary_key)]
+        /// Never reused, not even after the segment is deleted: the audit trail
+        /// outlives segments and must not credit their ids to new ones.
+        #[sea_orm(primary_key, auto_increment = true)]
         pub id: i32,
-        #[sea_orm(enum_name = "text_segment_type")]
+        /// The file the segment is from, see [`FileDb`].
+        #[sea_orm(default_value = "")]
+        pub file_name: String,
         pub segment_type: TextSegmentType,
         #[sea_orm(column_type = "JsonBinary")]
         pub content: Json,
+        /// Mirrors `status` being [`SegmentStatus::Locked`] for databases of
+        /// older versions, written together with it and never read, see
+        /// [`Model::is_locked`].
+        #[sea_orm(default_value = false)]
+        pub locked: bool,
+        /// Where the segment stands, the one source of truth for whether it is
+        /// locked or approved. Only ever changed as
+        /// [`SegmentStatus::can_transition_to`] allows, see [`set_status`].
+        #[sea_orm(default_value = 0)]
+        pub status: SegmentStatus,
+        /// How long the backend took to translate the segment, in milliseconds.
+        /// Unset for cached, skipped and hand-made translations.
+        #[sea_orm(nullable)]
+        pub translate_ms: Option<i32>,
     }
 
     #[derive(
@@ -30,33 +263,188 @@ pub mod text_segment {
         INonMessage = 1,
     }
 
+    /// Where a segment stands in the pipeline.
+    #[derive(
+        Copy, Clone, Debug, PartialEq, Eq, EnumIter, Serialize, Deserialize, DeriveActiveEnum,
+    )]
+    #[sea_orm(rs_type = "i32", db_type = "Integer")]
+    #[serde(rename_all = "snake_case")]
+    pub enum SegmentStatus {
+        Untranslated = 0,
+        /// Claimed by a translator that hasn't written it back yet.
+        InProgress = 1,
+        Translated = 2,
+        Approved = 3,
+        /// The backend refused it or it is too long to translate.
+        Failed = 4,
+        Locked = 5,
+    }
+
+    impl SegmentStatus {
+        /// Whether a segment may go from this status to `next`. Approved
+        /// segments are never claimed again, only discarded, and locked ones
+        /// only leave that status when unlocked through [`set_locked`].
+        pub fn can_transition_to(&self, next: SegmentStatus) -> bool {
+            use SegmentStatus::*;
+            matches!(
+                (self, next),
+                (
+                    Untranslated,
+                    Untranslated | InProgress | Translated | Failed | Locked
+                ) | (InProgress, Untranslated | Translated | Failed)
+                    | (Translated, Untranslated | Translated | Approved | Locked)
+                    | (Approved, Untranslated | Translated | Approved | Locked)
+                    | (
+                        Failed,
+                        Untranslated | InProgress | Translated | Failed | Locked
+                    )
+                    | (Locked, Locked)
+            )
+        }
+
+        /// Every status that may go to `next`.
+        pub fn sources_of(next: SegmentStatus) -> Vec<SegmentStatus> {
+            SegmentStatus::iter()
+                .filter(|status| status.can_transition_to(next))
+                .collect()
+        }
+    }
+
     #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
     pub enum Relation {}
 
     impl ActiveModelBehavior for ActiveModel {}
 
+    impl Model {
+        pub fn row_id(&self) -> RowId {
+            RowId(self.id)
+        }
+
+        /// Reviewed by hand, automated translation runs never touch it.
+        pub fn is_locked(&self) -> bool {
+            self.status == SegmentStatus::Locked
+        }
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct Translation {
+        pub content: String,
+        /// Free-form reviewer notes.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub notes: Option<String>,
+        #[serde(default)]
+        pub status: ReviewStatus,
+        /// The speaker name from the glossary, when names are translated.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub translated_name: Option<String>,
+    }
+
+    impl Translation {
+        pub fn new(content: String) -> Self {
+            Translation {
+                content,
+                ..Default::default()
+            }
+        }
+    }
+
+    /// Where a translation stands in human review. Kept with the translation
+    /// for reviewers; the pipeline acts on the [`SegmentStatus`] that
+    /// [`record_translation`] derives from it.
+    #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ReviewStatus {
+        #[default]
+        Unreviewed,
+        Approved,
+        NeedsWork,
+    }
+
     #[derive(Builder, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
-    #[builder(pattern = "owned")]
+    #[builder(pattern = "owned", build_fn(validate = "Self::validate_header"))]
     pub struct IMessageModel {
         #[builder(setter(into))]
-        pub line: i32,
+        pub line: Line,
+        /// Whitespace before `.message`, written back as is.
+        #[builder(setter(into), default = String::new())]
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        pub indent: String,
         #[builder(setter(into))]
-        pub id: i32,
+        pub id: MessageId,
         #[builder(setter(into), default = String::new())]
         pub name: String,
         #[builder(setter(into), default = String::new())]
         pub tachie: String,
+        #[builder(setter(into, strip_option), default)]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub tachie_variant: Option<TachieVariant>,
         #[builder(setter(into))]
         pub content: String,
+        /// The corner brackets around the content of a named message, when they
+        /// aren't the full-width `「」`, e.g. the half-width `｢｣`. Empty when the
+        /// content keeps its brackets, as in `アリス 「中」 後`.
+        #[builder(setter(into, strip_option), default)]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub brackets: Option<String>,
+        /// The `;region=<name>` this message is enclosed in.
+        #[builder(setter(into), default)]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub region: Option<String>,
+        /// A `;comment` after the content, with the whitespace before it, written
+        /// back as is and never translated.
+        #[builder(setter(into, strip_option), default)]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub comment: Option<String>,
+        /// The source line as is, without its line terminator, rendered back with
+        /// only `name_span` and `content_span` replaced. Empty for messages stored
+        /// before it was recorded, which are rendered from the fields above.
+        #[builder(setter(into), default = String::new())]
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        pub raw: String,
+        /// Byte range of the speaker name in `raw`.
+        #[builder(setter(strip_option), default)]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub name_span: Option<(usize, usize)>,
+        /// Byte range of the content in `raw`.
+        #[builder(setter(strip_option), default)]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub content_span: Option<(usize, usize)>,
+        /// The file an `#include` spliced this segment in from, relative to the
+        /// directory of the file it is stored with. `None` for its own segments.
+        #[builder(setter(into), default)]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub origin: Option<String>,
+    }
+
+    /// The part of a tachie after a configured delimiter, e.g. the expression in
+    /// `abc-01-02@smile`.
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct TachieVariant {
+        pub delimiter: char,
+        pub name: String,
     }
 
     #[derive(Builder, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
     #[builder(pattern = "owned")]
     pub struct INonMessageModel {
         #[builder(setter(into))]
-        pub line: i32,
+        pub line: Line,
+        /// The whole source line without its line terminator, byte for byte:
+        /// leading indentation and trailing whitespace included.
         #[builder(setter(into))]
         pub content: String,
+        #[builder(setter(into), default)]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub region: Option<String>,
+        /// The path an `#include` directive names, without quotes, as written.
+        #[builder(setter(into), default)]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub include: Option<String>,
+        /// The file an `#include` spliced this segment in from, relative to the
+        /// directory of the file it is stored with. `None` for its own segments.
+        #[builder(setter(into), default)]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub origin: Option<String>,
     }
 
     #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -66,15 +454,91 @@ pub mod text_segment {
         INonMessage(INonMessageModel),
     }
 
-    impl Into<InsertModel> for IMessageModel {
-        fn into(self) -> InsertModel {
-            InsertModel::IMessage(self)
+    impl InsertModel {
+        pub fn line(&self) -> Line {
+            match self {
+                InsertModel::IMessage(model) => model.line,
+                InsertModel::INonMessage(model) => model.line,
+            }
+        }
+
+        pub fn content(&self) -> &str {
+            match self {
+                InsertModel::IMessage(model) => &model.content,
+                InsertModel::INonMessage(model) => &model.content,
+            }
+        }
+
+        pub fn set_region(&mut self, region: Option<String>) {
+            match self {
+                InsertModel::IMessage(model) => model.region = region,
+                InsertModel::INonMessage(model) => model.region = region,
+            }
+        }
+
+        pub fn origin(&self) -> Option<&str> {
+            match self {
+                InsertModel::IMessage(model) => model.origin.as_deref(),
+                InsertModel::INonMessage(model) => model.origin.as_deref(),
+            }
+        }
+
+        pub fn set_origin(&mut self, origin: Option<String>) {
+            match self {
+                InsertModel::IMessage(model) => model.origin = origin,
+                InsertModel::INonMessage(model) => model.origin = origin,
+            }
+        }
+
+        pub fn offset_line(&mut self, offset: i32) {
+            match self {
+                InsertModel::IMessage(model) => model.line.0 += offset,
+                InsertModel::INonMessage(model) => model.line.0 += offset,
+            }
+        }
+
+        /// The stable key of this segment's source, for anything that must
+        /// recognise the same segment across files, runs and versions.
+        ///
+        /// Lowercase hex SHA-256 over the segment type (`IMessage` or
+        /// `INonMessage`), the speaker name (messages only) and the content,
+        /// separated by `\0`, with `\r\n` folded to `\n`. Line, indentation,
+        /// message id, tachie, region and trailing comment are deliberately left
+        /// out: moving or re-tagging a line does not change what it says. Changing
+        /// this changes every key already stored, so it must stay as it is.
+        pub fn fingerprint(&self) -> String {
+            let normalize = |text: &str| text.replace("\r\n", "\n");
+            let canonical = match self {
+                InsertModel::IMessage(model) => format!(
+                    "IMessage\0{}\0{}",
+                    normalize(&model.name),
+                    normalize(&model.content)
+                ),
+                InsertModel::INonMessage(model) => {
+                    format!("INonMessage\0{}", normalize(&model.content))
+                }
+            };
+            sha256_hex(&canonical)
+        }
+    }
+
+    impl TryFrom<Model> for InsertModel {
+        type Error = serde_json::Error;
+
+        fn try_from(model: Model) -> Result<Self, Self::Error> {
+            serde_json::from_value(model.content)
+        }
+    }
+
+    impl From<IMessageModel> for InsertModel {
+        fn from(model: IMessageModel) -> Self {
+            InsertModel::IMessage(model)
         }
     }
 
-    impl Into<InsertModel> for INonMessageModel {
-        fn into(self) -> InsertModel {
-            InsertModel::INonMessage(self)
+    impl From<INonMessageModel> for InsertModel {
+        fn from(model: INonMessageModel) -> Self {
+            InsertModel::INonMessage(model)
         }
     }
 
@@ -115,25 +579,71 @@ pub mod text_segment {
                     (None, None) => Ok(None),
                 }
             }
+            /// Content atoms are all part of the body, `other` comes after `self`
+            /// in the source.
+            fn merge_concat(a: Option<String>, b: Option<String>) -> Option<String> {
+                match (a, b) {
+                    (Some(a), Some(b)) => Some(a + &b),
+                    (a, b) => a.or(b),
+                }
+            }
             match other {
                 InsertModelBuilder::INonMessage(_) => {
                     bail!("Cannot combine IMessageModelBuilder with INonMessageModelBuilder")
                 }
                 InsertModelBuilder::IMessage(other) => Ok(IMessageModelBuilder {
                     line: merge_exclusive(self.line, other.line, "line")?,
+                    indent: merge_exclusive(self.indent, other.indent, "indent")?,
                     id: merge_exclusive(self.id, other.id, "id")?,
                     name: merge_exclusive(self.name, other.name, "name")?,
                     tachie: merge_exclusive(self.tachie, other.tachie, "tachie")?,
-                    content: merge_exclusive(self.content, other.content, "content")?,
-                    ..Default::default()
+                    tachie_variant: merge_exclusive(
+                        self.tachie_variant,
+                        other.tachie_variant,
+                        "tachie_variant",
+                    )?,
+                    content: merge_concat(self.content, other.content),
+                    brackets: merge_exclusive(self.brackets, other.brackets, "brackets")?,
+                    region: merge_exclusive(self.region, other.region, "region")?,
+                    comment: merge_exclusive(self.comment, other.comment, "comment")?,
+                    raw: merge_exclusive(self.raw, other.raw, "raw")?,
+                    name_span: merge_exclusive(self.name_span, other.name_span, "name_span")?,
+                    content_span: merge_exclusive(
+                        self.content_span,
+                        other.content_span,
+                        "content_span",
+                    )?,
+                    origin: merge_exclusive(self.origin, other.origin, "origin")?,
                 }),
             }
         }
     }
 
-    impl Into<InsertModelBuilder> for IMessageModelBuilder {
-        fn into(self) -> InsertModelBuilder {
-            InsertModelBuilder::IMessage(self)
+    impl IMessageModelBuilder {
+        /// The line and message id set so far, to tell which message is incomplete.
+        pub fn header(&self) -> (Option<Line>, Option<MessageId>) {
+            (self.line, self.id)
+        }
+
+        /// The header (line and message id) must be complete before a message is
+        /// built, rather than the body ending up in a half-empty model.
+        fn validate_header(&self) -> Result<(), String> {
+            let Some(line) = self.line else {
+                return Err("Message has no line".into());
+            };
+            match (&self.id, &self.content) {
+                (None, Some(_)) => Err(format!(
+                    "`.message` at line {line} has content but no message id"
+                )),
+                (None, None) => Err(format!("`.message` at line {line} has no message id")),
+                (Some(_), _) => Ok(()),
+            }
+        }
+    }
+
+    impl From<IMessageModelBuilder> for InsertModelBuilder {
+        fn from(model: IMessageModelBuilder) -> Self {
+            InsertModelBuilder::IMessage(model)
         }
     }
 
@@ -153,18 +663,21 @@ pub mod text_segment {
                 InsertModelBuilder::INonMessage(other) => Ok(INonMessageModelBuilder {
                     line: merge_exclusive(self.line, other.line, "line")?,
                     content: merge_exclusive(self.content, other.content, "content")?,
-                    ..Default::default()
+                    region: merge_exclusive(self.region, other.region, "region")?,
+                    include: merge_exclusive(self.include, other.include, "include")?,
+                    origin: merge_exclusive(self.origin, other.origin, "origin")?,
                 }),
             }
         }
     }
 
-    impl Into<InsertModelBuilder> for INonMessageModelBuilder {
-        fn into(self) -> InsertModelBuilder {
-            InsertModelBuilder::INonMessage(self)
+    impl From<INonMessageModelBuilder> for InsertModelBuilder {
+        fn from(model: INonMessageModelBuilder) -> Self {
+            InsertModelBuilder::INonMessage(model)
         }
     }
 
+    #[allow(clippy::large_enum_variant)]
     pub enum InsertModelBuilder {
         IMessage(IMessageModelBuilder),
         INonMessage(INonMessageModelBuilder),
@@ -193,25 +706,2406 @@ pub mod text_segment {
         }
     }
 
+    static DATABASE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+    /// Keeps the databases as `<dir>/<name>.db` instead of in memory. Must be
+    /// called before the first connection is opened.
+    #[anyhow_context]
+    pub fn set_database_dir(dir: &Path) -> AnyResult<()> {
+        create_dir_all(dir)?;
+        if DATABASE_DIR.set(dir.to_path_buf()).is_err() {
+            bail!("The database directory is already set");
+        }
+        Ok(())
+    }
+
+    /// Where the database of `name` lives, `None` when databases are in memory.
+    pub fn database_path(name: &str) -> Option<PathBuf> {
+        DATABASE_DIR.get().map(|dir| dir.join(format!("{name}.db")))
+    }
+
+    /// The database holding the segments of every file, see [`FileDb`].
+    pub const SEGMENTS_DB_NAME: &str = "segments";
+
+    static SEGMENTS_DB: OnceCell<Arc<DatabaseConnection>> = OnceCell::const_new();
+
+    /// The one connection to the segment database, opened and migrated on first
+    /// use and kept for the rest of the process, in memory as on disk.
+    #[anyhow_context]
+    pub async fn open_segments_db() -> AnyResult<Arc<DatabaseConnection>> {
+        let db = SEGMENTS_DB.get_or_try_init(migrated_segments_db).await?;
+        Ok(db.clone())
+    }
+
+    #[anyhow_context]
+    async fn migrated_segments_db() -> AnyResult<Arc<DatabaseConnection>> {
+        let db = connect(SEGMENTS_DB_NAME, database_path(SEGMENTS_DB_NAME).as_deref()).await?;
+        run_migrations(db.clone()).await?;
+        Ok(db)
+    }
+
+    static TRANSLATOR_CONFIG: OnceLock<TranslatorConfig> = OnceLock::new();
+
+    /// Keys the translations of each file by its target language in `config`,
+    /// see [`FileDb::language`]. Must be called before the first file is opened.
+    #[anyhow_context]
+    pub fn set_target_languages(config: &TranslatorConfig) -> AnyResult<()> {
+        if TRANSLATOR_CONFIG.set(config.clone()).is_err() {
+            bail!("The target languages are already set");
+        }
+        Ok(())
+    }
+
+    /// The target language of the file `name`, see [`set_target_languages`].
+    pub(super) fn target_language_of(name: &str) -> String {
+        static DEFAULT: LazyLock<TranslatorConfig> = LazyLock::new(TranslatorConfig::default);
+        let config = TRANSLATOR_CONFIG.get().unwrap_or(&DEFAULT);
+        config.target_language_of(name).into()
+    }
+
+    /// The segment database, shared by every file, narrowed to the rows of one
+    /// file: every query through it is filtered by `file_name`.
+    #[derive(Clone, Debug)]
+    pub struct FileDb {
+        conn: Arc<DatabaseConnection>,
+        name: String,
+        lang: String,
+    }
+
+    impl FileDb {
+        /// The rows of the file `name`, relative to the sources, in `conn`.
+        pub fn new(conn: Arc<DatabaseConnection>, name: &str) -> Self {
+            FileDb {
+                conn,
+                name: name.into(),
+                lang: target_language_of(name),
+            }
+        }
+
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+
+        /// The target language of the file, the one its translations are in
+        /// unless another language is asked for.
+        pub fn language(&self) -> &str {
+            &self.lang
+        }
+
+        /// The connection to the whole database, for queries across files.
+        pub fn connection(&self) -> Arc<DatabaseConnection> {
+            self.conn.clone()
+        }
+    }
+
+    impl Deref for FileDb {
+        type Target = DatabaseConnection;
+
+        fn deref(&self) -> &DatabaseConnection {
+            &self.conn
+        }
+    }
+
+    impl AsRef<DatabaseConnection> for FileDb {
+        fn as_ref(&self) -> &DatabaseConnection {
+            &self.conn
+        }
+    }
+
+    /// The names of the files with rows in the segment database, sorted.
+    #[anyhow_context]
+    pub async fn list_files(db: Arc<DatabaseConnection>) -> AnyResult<Vec<String>> {
+        // Every stage records itself before the file has any segments.
+        let mut names = pipeline_state::Entity::find()
+            .select_only()
+            .column(pipeline_state::Column::FileName)
+            .distinct()
+            .into_tuple::<String>()
+            .all(db.as_ref())
+            .await?;
+        names.extend(
+            Entity::find()
+                .select_only()
+                .column(Column::FileName)
+                .distinct()
+                .into_tuple::<String>()
+                .all(db.as_ref())
+                .await?,
+        );
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    /// The rows of the file `name` in the segment database.
     #[anyhow_context]
-    pub async fn create_db_connection(name: &str) -> AnyResult<Arc<DatabaseConnection>> {
-        let url = format!("file:{name}?mode=memory&cache=shared");
+    pub async fn create_db_connection(name: &str) -> AnyResult<FileDb> {
+        Ok(FileDb::new(open_segments_db().await?, name))
+    }
+
+    /// Connects to the database file at `path`, in WAL mode, whether or not a
+    /// database directory is set, e.g. to open the database of another project.
+    #[anyhow_context]
+    pub async fn create_db_connection_at(path: &Path) -> AnyResult<Arc<DatabaseConnection>> {
+        connect(&path.to_string_lossy(), Some(path)).await
+    }
+
+    /// Connects to the database at `path`, or to the in-memory one of `name`.
+    /// A connection to each in-memory database, never closed: pooled connections
+    /// close when the runtime that last used them shuts down, e.g. between tests,
+    /// and SQLite drops an in-memory database with its last connection.
+    static IN_MEMORY: LazyLock<Mutex<HashMap<String, SqliteConnection>>> =
+        LazyLock::new(Default::default);
+
+    #[anyhow_context]
+    pub(super) async fn connect(
+        name: &str,
+        path: Option<&Path>,
+    ) -> AnyResult<Arc<DatabaseConnection>> {
+        if let Some(parent) = path.and_then(Path::parent) {
+            create_dir_all(parent)?;
+        }
+        let url = match path {
+            Some(path) => format!("sqlite://{}?mode=rwc", path.display()),
+            None => format!("sqlite:file:{name}?mode=memory&cache=shared"),
+        };
+        if path.is_none() && !IN_MEMORY.lock().unwrap().contains_key(name) {
+            let anchor = SqliteConnection::connect(&url).await?;
+            IN_MEMORY
+                .lock()
+                .unwrap()
+                .entry(name.into())
+                .or_insert(anchor);
+        }
         let db = Database::connect(url).await?;
+        if path.is_some() {
+            // Readers don't block the writer, see `WriteGate` for writers.
+            db.execute_unprepared("PRAGMA journal_mode = WAL").await?;
+        }
         Ok(Arc::new(db))
     }
 
+    /// The segments of the file of `db`.
+    fn of_file(db: &FileDb) -> Select<Entity> {
+        Entity::find().filter(Column::FileName.eq(db.name()))
+    }
+
+    /// The ids of the segments of the file of `db`, as a subquery.
+    pub(super) fn segments_of(db: &FileDb) -> SelectStatement {
+        Query::select()
+            .column(Column::Id)
+            .from(Entity)
+            .and_where(Column::FileName.eq(db.name()))
+            .to_owned()
+    }
+
+    /// Audit rows of the segments of the file of `db`.
+    fn audited_in(db: &FileDb) -> SimpleExpr {
+        translation_audit::Column::SegmentId.in_subquery(segments_of(db))
+    }
+
+    /// Every segment of the file in source order, with its translation if it has one.
     #[anyhow_context]
-    pub async fn create_table(db: Arc<DatabaseConnection>) -> AnyResult<()> {
-        let backend = db.get_database_backend();
-        let schema = Schema::new(backend);
+    pub async fn load_translated_segments(
+        db: FileDb,
+    ) -> AnyResult<Vec<(RowId, InsertModel, Option<Translation>)>> {
+        let mut translations = load_localized(db.clone(), db.language()).await?;
+        let mut segments = Vec::new();
+        for model in of_file(&db).all(db.as_ref()).await? {
+            let translation = translations.remove(&model.row_id());
+            segments.push((model.row_id(), InsertModel::try_from(model)?, translation));
+        }
+        segments.sort_by_key(|(_, segment, _)| segment.line());
+        Ok(segments)
+    }
 
-        let statement = backend.build(schema.create_table_from_entity(Entity).if_not_exists());
-        db.execute(statement).await?;
+    /// Segments per page of [`visit_translated_segments`].
+    const SEGMENT_PAGE: u64 = 1000;
+
+    /// Calls `visit` with every segment of the file in source order, with its
+    /// translation if it has one, fetching a page of segments at a time instead
+    /// of all of them like [`load_translated_segments`].
+    #[anyhow_context]
+    pub async fn visit_translated_segments<F>(db: FileDb, mut visit: F) -> AnyResult<()>
+    where
+        F: FnMut(RowId, InsertModel, Option<Translation>) -> AnyResult<()> + Send,
+    {
+        let mut pages = of_file(&db)
+            .order_by_asc(Expr::cust("json_extract(content, '$.line')"))
+            .order_by_asc(Column::Id)
+            .paginate(db.as_ref(), SEGMENT_PAGE);
+        while let Some(models) = pages.fetch_and_next().await? {
+            let ids = models.iter().map(|model| model.id).collect();
+            let mut translations = translations_of(db.as_ref(), db.language(), ids).await?;
+            for model in models {
+                let translation = translations.remove(&model.row_id());
+                visit(model.row_id(), InsertModel::try_from(model)?, translation)?;
+            }
+        }
         Ok(())
     }
-}
 
-pub use text_segment::{
-    Column as TextSegmentColumn, Entity as TextSegmentEntity, InsertModel as TextSegment,
-    InsertModelBuilder as TextSegmentBuilder, create_db_connection, create_table,
-};
+    /// Every distinct non-empty `tachie` with the number of messages using it.
+    #[anyhow_context]
+    pub async fn list_tachie(db: FileDb) -> AnyResult<Vec<(String, i64)>> {
+        let tachie = Expr::cust("json_extract(content, '$.tachie')");
+        let usage = of_file(&db)
+            .select_only()
+            .column_as(tachie.clone(), "tachie")
+            .column_as(Column::Id.count(), "count")
+            .filter(Column::SegmentType.eq(TextSegmentType::IMessage))
+            .filter(tachie.clone().ne(""))
+            .group_by(tachie.clone())
+            .order_by_asc(tachie)
+            .into_tuple()
+            .all(db.as_ref())
+            .await?;
+        Ok(usage)
+    }
+
+    /// The path of every `#include` directive of the file, in source order,
+    /// leaving out those of the files it includes.
+    #[anyhow_context]
+    pub async fn list_includes(db: FileDb) -> AnyResult<Vec<String>> {
+        let include = Expr::cust("json_extract(content, '$.include')");
+        let includes = of_file(&db)
+            .select_only()
+            .column_as(include.clone(), "include")
+            .filter(Column::SegmentType.eq(TextSegmentType::INonMessage))
+            .filter(Expr::expr(include).is_not_null())
+            .filter(Expr::expr(Expr::cust("json_extract(content, '$.origin')")).is_null())
+            .order_by_asc(Column::Id)
+            .into_tuple()
+            .all(db.as_ref())
+            .await?;
+        Ok(includes)
+    }
+
+    #[allow(unused)]
+    #[anyhow_context]
+    /// Segment `id` of the file with its translation, if it has one.
+    pub async fn find_segment(
+        db: FileDb,
+        RowId(id): RowId,
+    ) -> AnyResult<Option<(Model, Option<Translation>)>> {
+        let Some(segment) = of_file(&db)
+            .filter(Column::Id.eq(id))
+            .one(db.as_ref())
+            .await?
+        else {
+            return Ok(None);
+        };
+        let mut translation = translations_of(db.as_ref(), db.language(), vec![id]).await?;
+        Ok(Some((segment, translation.remove(&RowId(id)))))
+    }
+
+    /// Provenance of a translation, stored in `translation_audit`.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct AuditEntry {
+        pub backend: String,
+        pub prompt_hash: String,
+        pub triggered_by: String,
+    }
+
+    #[anyhow_context]
+    pub async fn count_segments(db: FileDb) -> AnyResult<u64> {
+        let segments = of_file(&db).count(db.as_ref()).await?;
+        Ok(segments)
+    }
+
+    #[anyhow_context]
+    pub async fn count_messages(db: FileDb) -> AnyResult<u64> {
+        let messages = of_file(&db)
+            .filter(Column::SegmentType.eq(TextSegmentType::IMessage))
+            .count(db.as_ref())
+            .await?;
+        Ok(messages)
+    }
+
+    /// The distinct backends that wrote a translation of the file.
+    #[anyhow_context]
+    pub async fn translation_backends(db: FileDb) -> AnyResult<Vec<String>> {
+        let backends = translation_audit::Entity::find()
+            .filter(audited_in(&db))
+            .select_only()
+            .column(translation_audit::Column::Backend)
+            .distinct()
+            .order_by_asc(translation_audit::Column::Backend)
+            .into_tuple()
+            .all(db.as_ref())
+            .await?;
+        Ok(backends)
+    }
+
+    /// Where the translation of a segment came from, see [`translation_provenance`].
+    #[derive(Clone, Debug)]
+    pub struct Provenance {
+        pub backend: String,
+        pub translated_at: DateTimeUtc,
+        /// Reviewed by hand, see [`Model::is_locked`].
+        pub locked: bool,
+    }
+
+    /// The latest audit entry of every segment that has one, by segment.
+    #[anyhow_context]
+    pub async fn translation_provenance(db: FileDb) -> AnyResult<HashMap<RowId, Provenance>> {
+        let locked = of_file(&db)
+            .select_only()
+            .column(Column::Id)
+            .filter(Column::Status.eq(SegmentStatus::Locked))
+            .into_tuple::<i32>()
+            .all(db.as_ref())
+            .await?
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let mut provenance = HashMap::new();
+        for audit in translation_audit::Entity::find()
+            .filter(audited_in(&db))
+            .order_by_asc(translation_audit::Column::Id)
+            .all(db.as_ref())
+            .await?
+        {
+            provenance.insert(
+                RowId(audit.segment_id),
+                Provenance {
+                    backend: audit.backend,
+                    translated_at: audit.translated_at,
+                    locked: locked.contains(&audit.segment_id),
+                },
+            );
+        }
+        Ok(provenance)
+    }
+
+    /// Messages the backend refused or that were too long to translate, see
+    /// [`SegmentStatus::Failed`].
+    #[anyhow_context]
+    pub async fn count_failed(db: FileDb) -> AnyResult<u64> {
+        let failed = of_file(&db)
+            .filter(Column::Status.eq(SegmentStatus::Failed))
+            .count(db.as_ref())
+            .await?;
+        Ok(failed)
+    }
+
+    #[anyhow_context]
+    pub async fn count_translated(db: FileDb) -> AnyResult<u64> {
+        let translated = of_file(&db)
+            .filter(Column::Id.in_subquery(translated_into(db.language())))
+            .count(db.as_ref())
+            .await?;
+        Ok(translated)
+    }
+
+    /// Writes SQLite's write-ahead log back into the database file, a no-op unless
+    /// the database is on disk in WAL mode.
+    #[anyhow_context]
+    pub async fn flush_wal(db: Arc<DatabaseConnection>) -> AnyResult<()> {
+        db.execute_unprepared("PRAGMA wal_checkpoint(TRUNCATE)")
+            .await?;
+        Ok(())
+    }
+
+    /// Every message of the file, in source order.
+    #[anyhow_context]
+    pub async fn load_messages(db: FileDb) -> AnyResult<Vec<IMessageModel>> {
+        let mut messages = Vec::new();
+        for model in of_file(&db)
+            .filter(Column::SegmentType.eq(TextSegmentType::IMessage))
+            .order_by_asc(Expr::cust("json_extract(content, '$.line')"))
+            .order_by_asc(Column::Id)
+            .all(db.as_ref())
+            .await?
+        {
+            if let InsertModel::IMessage(message) = InsertModel::try_from(model)? {
+                messages.push(message);
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Every non-message of the file, in source order.
+    #[anyhow_context]
+    pub async fn load_non_messages(db: FileDb) -> AnyResult<Vec<INonMessageModel>> {
+        let mut non_messages = Vec::new();
+        for model in of_file(&db)
+            .filter(Column::SegmentType.eq(TextSegmentType::INonMessage))
+            .order_by_asc(Expr::cust("json_extract(content, '$.line')"))
+            .order_by_asc(Column::Id)
+            .all(db.as_ref())
+            .await?
+        {
+            if let InsertModel::INonMessage(non_message) = InsertModel::try_from(model)? {
+                non_messages.push(non_message);
+            }
+        }
+        Ok(non_messages)
+    }
+
+    /// Up to `limit` messages on lines before `line`, oldest first.
+    #[anyhow_context]
+    pub async fn load_preceding_messages(
+        db: FileDb,
+        Line(line): Line,
+        limit: u64,
+    ) -> AnyResult<Vec<IMessageModel>> {
+        let position = || Expr::cust("json_extract(content, '$.line')");
+        let mut messages = Vec::new();
+        for model in of_file(&db)
+            .filter(Column::SegmentType.eq(TextSegmentType::IMessage))
+            .filter(Expr::expr(position()).lt(line))
+            .order_by_desc(position())
+            .limit(limit)
+            .all(db.as_ref())
+            .await?
+        {
+            if let InsertModel::IMessage(message) = InsertModel::try_from(model)? {
+                messages.push(message);
+            }
+        }
+        messages.reverse();
+        Ok(messages)
+    }
+
+    /// Messages with no translation yet, in insertion order, optionally only those
+    /// enclosed in `region`.
+    #[anyhow_context]
+    pub async fn load_untranslated(db: FileDb, region: Option<&str>) -> AnyResult<Vec<Model>> {
+        let mut query = of_file(&db)
+            .filter(Column::SegmentType.eq(TextSegmentType::IMessage))
+            .filter(Column::Id.not_in_subquery(translated_into(db.language())))
+            .filter(Column::Status.ne(SegmentStatus::Locked));
+        if let Some(region) = region {
+            query = query.filter(Expr::cust("json_extract(content, '$.region')").eq(region));
+        }
+        let untranslated = query.order_by_asc(Column::Id).all(db.as_ref()).await?;
+        Ok(untranslated)
+    }
+
+    /// Drops the target-language translation of every segment that isn't locked,
+    /// returning how many were dropped. The translation audit keeps them.
+    #[anyhow_context]
+    pub async fn clear_translations(db: FileDb) -> AnyResult<u64> {
+        let txn = db.begin().await?;
+        let cleared = of_file(&db)
+            .select_only()
+            .column(Column::Id)
+            .filter(Column::Id.in_subquery(translated_into(db.language())))
+            .filter(Column::Status.ne(SegmentStatus::Locked))
+            .filter(Column::Status.is_in(SegmentStatus::sources_of(SegmentStatus::Untranslated)))
+            .into_tuple::<i32>()
+            .all(&txn)
+            .await?;
+        Entity::update_many()
+            .col_expr(Column::Status, Expr::value(SegmentStatus::Untranslated))
+            .col_expr(Column::TranslateMs, Expr::value(Option::<i32>::None))
+            .filter(Column::Id.is_in(cleared.clone()))
+            .exec(&txn)
+            .await?;
+        segment_translation::Entity::delete_many()
+            .filter(segment_translation::Column::Lang.eq(db.language()))
+            .filter(segment_translation::Column::SegmentId.is_in(cleared.clone()))
+            .exec(&txn)
+            .await?;
+        txn.commit().await?;
+        Ok(cleared.len() as u64)
+    }
+
+    /// Replaces the segments of the file with `segments`, in source order, in one
+    /// transaction, `batch_size` rows per statement. What was derived from the
+    /// segments replaced, their variables and translations, goes with them, so that
+    /// parsing a file again doesn't duplicate it.
+    #[anyhow_context]
+    pub async fn insert_segments(
+        db: FileDb,
+        segments: &[InsertModel],
+        batch_size: usize,
+    ) -> AnyResult<()> {
+        let txn = db.begin().await?;
+        segment_translation::Entity::delete_many()
+            .filter(segment_translation::Column::SegmentId.in_subquery(segments_of(&db)))
+            .exec(&txn)
+            .await?;
+        Entity::delete_many()
+            .filter(Column::FileName.eq(db.name()))
+            .exec(&txn)
+            .await?;
+        variable::Entity::delete_many()
+            .filter(variable::Column::FileName.eq(db.name()))
+            .exec(&txn)
+            .await?;
+        for batch in segments.chunks(batch_size.max(1)) {
+            let models = batch.iter().map(|segment| ActiveModel {
+                file_name: Set(db.name().into()),
+                ..segment.clone().into_active_model()
+            });
+            Entity::insert_many(models)
+                .exec_without_returning(&txn)
+                .await?;
+        }
+        txn.commit().await?;
+        Ok(())
+    }
+
+    /// Deletes every segment, locked or not, returning how many were deleted.
+    #[anyhow_context]
+    pub async fn clear_segments(db: FileDb) -> AnyResult<u64> {
+        let deleted = Entity::delete_many()
+            .filter(Column::FileName.eq(db.name()))
+            .exec(db.as_ref())
+            .await?;
+        Ok(deleted.rows_affected)
+    }
+
+    /// Locks or unlocks the segments on the given source `lines`, returning how
+    /// many segments were changed.
+    #[anyhow_context]
+    pub async fn set_locked(db: FileDb, lines: &[Line], locked: bool) -> AnyResult<u64> {
+        let lines = lines.iter().map(|line| line.0).collect::<Vec<_>>();
+        let mut changed = 0;
+        // Unlocked segments go back to whatever their translation says they are.
+        for (translated, status) in [
+            (None, SegmentStatus::Locked),
+            (Some(false), SegmentStatus::Untranslated),
+            (Some(true), SegmentStatus::Translated),
+        ] {
+            if locked != translated.is_none() {
+                continue;
+            }
+            let mut query = Entity::update_many()
+                .filter(Column::FileName.eq(db.name()))
+                .col_expr(Column::Locked, Expr::value(locked))
+                .col_expr(Column::Status, Expr::value(status))
+                .filter(
+                    Expr::expr(Expr::cust("json_extract(content, '$.line')")).is_in(lines.clone()),
+                )
+                .filter(match locked {
+                    true => Column::Status
+                        .ne(SegmentStatus::Locked)
+                        .and(Column::Status.is_in(SegmentStatus::sources_of(status))),
+                    // The one way out of `Locked`, which no transition allows.
+                    false => Column::Status.eq(SegmentStatus::Locked),
+                });
+            let translated_ids = translated_into(db.language());
+            query = match translated {
+                Some(true) => query.filter(Column::Id.in_subquery(translated_ids)),
+                Some(false) => query.filter(Column::Id.not_in_subquery(translated_ids)),
+                None => query,
+            };
+            changed += query.exec(db.as_ref()).await?.rows_affected;
+        }
+        Ok(changed)
+    }
+
+    #[allow(unused)]
+    #[anyhow_context]
+    pub async fn lock_segment(db: FileDb, id: RowId) -> AnyResult<()> {
+        set_status(db, id, SegmentStatus::Locked).await
+    }
+
+    /// Fails unless segment `id` may go to `next`, see
+    /// [`SegmentStatus::can_transition_to`].
+    async fn guard_status<C: ConnectionTrait>(
+        conn: &C,
+        RowId(id): RowId,
+        next: SegmentStatus,
+    ) -> AnyResult<()> {
+        let Some(segment) = Entity::find_by_id(id).one(conn).await? else {
+            bail!("No segment {id}");
+        };
+        if !segment.status.can_transition_to(next) {
+            bail!(
+                "Segment {id} cannot go from {:?} to {next:?}",
+                segment.status
+            );
+        }
+        Ok(())
+    }
+
+    /// Moves segment `id` to `next`, failing if its status doesn't allow it.
+    #[anyhow_context]
+    pub async fn set_status(db: FileDb, id: RowId, next: SegmentStatus) -> AnyResult<()> {
+        let txn = db.begin().await?;
+        guard_status(&txn, id, next).await?;
+        ActiveModel {
+            id: Set(id.0),
+            locked: Set(next == SegmentStatus::Locked),
+            status: Set(next),
+            ..Default::default()
+        }
+        .update(&txn)
+        .await?;
+        txn.commit().await?;
+        Ok(())
+    }
+
+    /// Writes `translation` to segment `id` together with its audit row, atomically.
+    #[anyhow_context]
+    pub async fn record_translation(
+        db: FileDb,
+        row_id: RowId,
+        translation: Translation,
+        audit: AuditEntry,
+    ) -> AnyResult<()> {
+        let id = row_id.0;
+        let status = match translation.status {
+            ReviewStatus::Approved => SegmentStatus::Approved,
+            ReviewStatus::Unreviewed | ReviewStatus::NeedsWork => SegmentStatus::Translated,
+        };
+        let translation = json!(translation);
+        let txn = db.begin().await?;
+        guard_status(&txn, row_id, status).await?;
+        ActiveModel {
+            id: Set(id),
+            status: Set(status),
+            ..Default::default()
+        }
+        .update(&txn)
+        .await?;
+        write_translation(&txn, row_id, db.language(), translation.clone()).await?;
+        translation_audit::ActiveModel {
+            segment_id: Set(id),
+            translated_at: Set(Utc::now()),
+            translation: Set(translation),
+            backend: Set(audit.backend),
+            prompt_hash: Set(audit.prompt_hash),
+            triggered_by: Set(audit.triggered_by),
+            ..Default::default()
+        }
+        .insert(&txn)
+        .await?;
+        txn.commit().await?;
+        Ok(())
+    }
+
+    /// Recorded as the backend of translations written by hand.
+    pub const MANUAL_BACKEND: &str = "manual";
+
+    /// Writes `translated` to segment `id` as an unreviewed translation made by
+    /// hand, see [`record_translation`].
+    #[anyhow_context]
+    pub async fn set_translation(db: FileDb, id: RowId, translated: String) -> AnyResult<()> {
+        let audit = AuditEntry {
+            backend: MANUAL_BACKEND.into(),
+            prompt_hash: String::new(),
+            triggered_by: "set_translation".into(),
+        };
+        record_translation(db, id, Translation::new(translated), audit).await
+    }
+
+    /// Records how long the backend took to translate the segment at `row_id`.
+    #[anyhow_context]
+    pub async fn record_translate_time(
+        db: FileDb,
+        row_id: RowId,
+        elapsed: Duration,
+    ) -> AnyResult<()> {
+        let millis = i32::try_from(elapsed.as_millis()).unwrap_or(i32::MAX);
+        ActiveModel {
+            id: Set(row_id.0),
+            translate_ms: Set(Some(millis)),
+            ..Default::default()
+        }
+        .update(db.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    /// The `n` messages the backend took longest to translate, slowest first,
+    /// with the milliseconds it took.
+    #[anyhow_context]
+    pub async fn slowest_segments(db: FileDb, n: u64) -> AnyResult<Vec<(IMessageModel, i32)>> {
+        let mut slowest = Vec::new();
+        for model in of_file(&db)
+            .filter(Column::TranslateMs.is_not_null())
+            .order_by_desc(Column::TranslateMs)
+            .limit(n)
+            .all(db.as_ref())
+            .await?
+        {
+            let millis = model.translate_ms.unwrap_or_default();
+            if let InsertModel::IMessage(message) = InsertModel::try_from(model)? {
+                slowest.push((message, millis));
+            }
+        }
+        Ok(slowest)
+    }
+
+    /// Segments whose source (or translated) content contains `query`.
+    ///
+    /// Matching folds case and full-width ASCII forms, so `ＡＢＣ` finds `abc`; kana and
+    /// kanji are compared as-is (no hiragana/katakana folding).
+    #[anyhow_context]
+    pub async fn search_segments(
+        db: FileDb,
+        query: &str,
+        in_translated: bool,
+    ) -> AnyResult<Vec<(Model, Option<Translation>)>> {
+        let pattern = width_and_case_glob(query);
+        let mut found = of_file(&db).order_by_asc(Column::Id);
+        if in_translated {
+            let mut matching = translated_into(db.language());
+            matching.and_where(Expr::cust_with_values(
+                "json_extract(translation, '$.content') GLOB ?",
+                [pattern],
+            ));
+            found = found.filter(Column::Id.in_subquery(matching));
+        } else {
+            found = found.filter(Expr::cust_with_values(
+                "json_extract(content, '$.content') GLOB ?",
+                [pattern],
+            ));
+        }
+        let found = found.all(db.as_ref()).await?;
+        let ids = found.iter().map(|model| model.id).collect();
+        let mut translations = translations_of(db.as_ref(), db.language(), ids).await?;
+        Ok(found
+            .into_iter()
+            .map(|model| {
+                let translation = translations.remove(&model.row_id());
+                (model, translation)
+            })
+            .collect())
+    }
+
+    /// Derives the status of segments stored before there was one. Approved
+    /// translations are locked, so the review status needs no backfill.
+    pub(super) const BACKFILL_STATUS: &str = "
+        UPDATE text_segments SET status = CASE
+            WHEN locked THEN 5
+            WHEN translated_content IS NOT NULL THEN 2
+            ELSE 0
+        END WHERE status = 0";
+
+    #[anyhow_context]
+    pub async fn create_table(db: Arc<DatabaseConnection>) -> AnyResult<()> {
+        let backend = db.get_database_backend();
+        let schema = Schema::new(backend);
+
+        let statement = backend.build(schema.create_table_from_entity(Entity).if_not_exists());
+        db.execute(statement).await?;
+        Ok(())
+    }
+}
+
+pub mod pipeline_state {
+    use anyhow::{Context, Result as AnyResult};
+    use auto_context::auto_context as anyhow_context;
+    use chrono::Utc;
+    use sea_orm::{ActiveValue::Set, QueryOrder, entity::prelude::*, sea_query::OnConflict};
+    use serde::{Deserialize, Serialize};
+
+    use super::text_segment::FileDb;
+
+    /// One row per file and stage that already ran on it.
+    #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+    #[sea_orm(table_name = "pipeline_state")]
+    pub struct Model {
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub file_name: String,
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub stage: PipelineStage,
+        pub completed: bool,
+        #[sea_orm(nullable)]
+        pub started_at: Option<DateTimeUtc>,
+        #[sea_orm(nullable)]
+        pub finished_at: Option<DateTimeUtc>,
+        /// Why the last run of the stage failed, see [`mark_stage_failed`].
+        #[sea_orm(nullable)]
+        pub error: Option<String>,
+    }
+
+    impl Model {
+        /// How long the last run of the stage took, if it finished.
+        pub fn elapsed(&self) -> Option<chrono::Duration> {
+            Some(self.finished_at? - self.started_at?)
+        }
+    }
+
+    #[derive(
+        Copy, Clone, Debug, PartialEq, Eq, EnumIter, Serialize, Deserialize, DeriveActiveEnum,
+    )]
+    #[sea_orm(rs_type = "i32", db_type = "Integer")]
+    pub enum PipelineStage {
+        Parser = 0,
+        Analyzer = 1,
+        Translator = 2,
+        Assembler = 3,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+
+    #[anyhow_context]
+    pub async fn mark_stage_started(db: FileDb, stage: PipelineStage) -> AnyResult<()> {
+        Entity::insert(ActiveModel {
+            file_name: Set(db.name().into()),
+            stage: Set(stage),
+            completed: Set(false),
+            started_at: Set(Some(Utc::now())),
+            finished_at: Set(None),
+            error: Set(None),
+        })
+        .on_conflict(
+            OnConflict::columns([Column::FileName, Column::Stage])
+                .update_columns([Column::StartedAt, Column::Error])
+                .to_owned(),
+        )
+        .exec(db.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    #[anyhow_context]
+    pub async fn mark_stage_done(db: FileDb, stage: PipelineStage) -> AnyResult<()> {
+        Entity::insert(ActiveModel {
+            file_name: Set(db.name().into()),
+            stage: Set(stage),
+            completed: Set(true),
+            started_at: Set(None),
+            finished_at: Set(Some(Utc::now())),
+            error: Set(None),
+        })
+        .on_conflict(
+            OnConflict::columns([Column::FileName, Column::Stage])
+                .update_columns([Column::Completed, Column::FinishedAt, Column::Error])
+                .to_owned(),
+        )
+        .exec(db.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    /// Records that `stage` failed with `error`, for the run to stop waiting for
+    /// the file, see [`stage_failure`].
+    #[anyhow_context]
+    pub async fn mark_stage_failed(db: FileDb, stage: PipelineStage, error: &str) -> AnyResult<()> {
+        Entity::insert(ActiveModel {
+            file_name: Set(db.name().into()),
+            stage: Set(stage),
+            completed: Set(false),
+            started_at: Set(None),
+            finished_at: Set(Some(Utc::now())),
+            error: Set(Some(error.into())),
+        })
+        .on_conflict(
+            OnConflict::columns([Column::FileName, Column::Stage])
+                .update_columns([Column::Completed, Column::FinishedAt, Column::Error])
+                .to_owned(),
+        )
+        .exec(db.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    /// The first stage that failed on the file, with its error.
+    #[anyhow_context]
+    pub async fn stage_failure(db: FileDb) -> AnyResult<Option<(PipelineStage, String)>> {
+        let failed = Entity::find()
+            .filter(Column::FileName.eq(db.name()))
+            .filter(Column::Error.is_not_null())
+            .order_by_asc(Column::Stage)
+            .one(db.as_ref())
+            .await?;
+        Ok(failed.and_then(|state| state.error.map(|error| (state.stage, error))))
+    }
+
+    /// Forgets the failures of earlier runs, see [`mark_stage_failed`].
+    #[anyhow_context]
+    pub async fn clear_stage_failures(db: FileDb) -> AnyResult<()> {
+        Entity::update_many()
+            .col_expr(Column::Error, Expr::value(Option::<String>::None))
+            .filter(Column::FileName.eq(db.name()))
+            .exec(db.as_ref())
+            .await?;
+        Ok(())
+    }
+
+    /// Forgets that `stage` ran, so that it runs again.
+    #[anyhow_context]
+    pub async fn clear_stage(db: FileDb, stage: PipelineStage) -> AnyResult<()> {
+        Entity::delete_by_id((db.name().to_owned(), stage))
+            .exec(db.as_ref())
+            .await?;
+        Ok(())
+    }
+
+    #[anyhow_context]
+    pub async fn is_stage_done(db: FileDb, stage: PipelineStage) -> AnyResult<bool> {
+        let state = Entity::find_by_id((db.name().to_owned(), stage))
+            .one(db.as_ref())
+            .await?;
+        Ok(state.is_some_and(|state| state.completed))
+    }
+
+    /// The state of every stage that ran on the file, in pipeline order.
+    #[anyhow_context]
+    pub async fn stage_states(db: FileDb) -> AnyResult<Vec<Model>> {
+        let states = Entity::find()
+            .filter(Column::FileName.eq(db.name()))
+            .order_by_asc(Column::Stage)
+            .all(db.as_ref())
+            .await?;
+        Ok(states)
+    }
+}
+
+pub mod variable {
+    use anyhow::{Context, Result as AnyResult};
+    use auto_context::auto_context as anyhow_context;
+    use sea_orm::{ActiveValue::Set, entity::prelude::*, sea_query::OnConflict};
+    use std::collections::BTreeMap;
+
+    use super::text_segment::FileDb;
+
+    /// Every distinct engine variable token (`%PLAYER_NAME%`, `\v[3]`, ...) of a file.
+    #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+    #[sea_orm(table_name = "variables")]
+    pub struct Model {
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub file_name: String,
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub token: String,
+        pub occurrences: i32,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+
+    #[anyhow_context]
+    pub async fn save_variables(db: FileDb, variables: BTreeMap<String, i32>) -> AnyResult<()> {
+        if variables.is_empty() {
+            return Ok(());
+        }
+        let models = variables
+            .into_iter()
+            .map(|(token, occurrences)| ActiveModel {
+                file_name: Set(db.name().into()),
+                token: Set(token),
+                occurrences: Set(occurrences),
+            });
+        Entity::insert_many(models)
+            .on_conflict(
+                OnConflict::columns([Column::FileName, Column::Token])
+                    .update_column(Column::Occurrences)
+                    .to_owned(),
+            )
+            .exec(db.as_ref())
+            .await?;
+        Ok(())
+    }
+
+    #[anyhow_context]
+    pub async fn clear_variables(db: FileDb) -> AnyResult<()> {
+        Entity::delete_many()
+            .filter(Column::FileName.eq(db.name()))
+            .exec(db.as_ref())
+            .await?;
+        Ok(())
+    }
+}
+
+pub mod translation_cache {
+    use anyhow::{Context, Result as AnyResult, bail};
+    use auto_context::auto_context as anyhow_context;
+    use sea_orm::{
+        ActiveValue::Set, Database, DatabaseConnection, QuerySelect, entity::prelude::*,
+        sea_query::OnConflict,
+    };
+    use similar::TextDiff;
+    use std::{
+        fs::create_dir_all,
+        path::{Path, PathBuf},
+        sync::{Arc, OnceLock},
+    };
+
+    use super::{
+        add_column_if_missing, column_exists, create_entity_table,
+        text_segment::{connect, database_path},
+    };
+
+    /// The project-wide database holding the cache, stored next to the segment
+    /// database unless [`set_cache_path`] moved it.
+    pub const CACHE_DB_NAME: &str = "translation_cache";
+
+    static CACHE_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+    /// Keeps the cache at `path` instead of with the other databases, e.g. to share
+    /// it between projects. Must be called before the cache is first opened.
+    #[anyhow_context]
+    pub fn set_cache_path(path: &Path) -> AnyResult<()> {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        if CACHE_PATH.set(path.to_path_buf()).is_err() {
+            bail!("The translation cache path is already set");
+        }
+        Ok(())
+    }
+
+    /// Where the cache lives, `None` when it is in memory.
+    pub fn cache_path() -> Option<PathBuf> {
+        CACHE_PATH
+            .get()
+            .cloned()
+            .or_else(|| database_path(CACHE_DB_NAME))
+    }
+
+    /// Rows copied per statement by [`preload_cache_from`].
+    const PRELOAD_BATCH: usize = 500;
+
+    /// Translations keyed by the [`fingerprint`] of their source segment,
+    /// shared by every file.
+    ///
+    /// [`fingerprint`]: super::text_segment::InsertModel::fingerprint
+    #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+    #[sea_orm(table_name = "translation_cache")]
+    pub struct Model {
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub source_hash: String,
+        pub translation: String,
+        /// The source text, for [`fuzzy_match`]. Unset in caches of older versions.
+        #[sea_orm(nullable)]
+        pub source: Option<String>,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+
+    #[anyhow_context]
+    pub async fn open_translation_cache() -> AnyResult<Arc<DatabaseConnection>> {
+        let db = connect(CACHE_DB_NAME, cache_path().as_deref()).await?;
+        create_entity_table(db.as_ref(), Entity).await?;
+        add_column_if_missing::<Entity>(db.as_ref(), Column::Source).await?;
+        Ok(db)
+    }
+
+    #[anyhow_context]
+    pub async fn lookup_translation(
+        db: Arc<DatabaseConnection>,
+        fingerprint: &str,
+    ) -> AnyResult<Option<String>> {
+        let cached = Entity::find_by_id(fingerprint)
+            .one(db.as_ref())
+            .await?
+            .map(|model| model.translation);
+        Ok(cached)
+    }
+
+    #[anyhow_context]
+    pub async fn cache_translation(
+        db: Arc<DatabaseConnection>,
+        fingerprint: &str,
+        source: &str,
+        translation: &str,
+    ) -> AnyResult<()> {
+        Entity::insert(ActiveModel {
+            source_hash: Set(fingerprint.into()),
+            translation: Set(translation.into()),
+            source: Set(Some(source.into())),
+        })
+        .on_conflict(
+            OnConflict::column(Column::SourceHash)
+                .update_columns([Column::Translation, Column::Source])
+                .to_owned(),
+        )
+        .exec(db.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    /// The cached source most similar to `source` with its translation, if it is
+    /// at least `threshold` similar, from 0 (nothing in common) to 1 (identical).
+    ///
+    /// Similarity is the ratio of characters two sources have in common, so only
+    /// sources whose length is close enough to that of `source` are compared.
+    #[anyhow_context]
+    pub async fn fuzzy_match(
+        source: &str,
+        db: Arc<DatabaseConnection>,
+        threshold: f32,
+    ) -> AnyResult<Option<(String, String)>> {
+        let threshold = threshold.clamp(f32::EPSILON, 1.0);
+        let length = source.chars().count() as f32;
+        let (shortest, longest) = (
+            length * threshold / (2.0 - threshold),
+            length * (2.0 - threshold) / threshold,
+        );
+        let candidates = Entity::find()
+            .filter(Column::Source.is_not_null())
+            .filter(Expr::cust_with_values(
+                "length(source) BETWEEN ? AND ?",
+                [shortest.floor() as i64, longest.ceil() as i64],
+            ))
+            .all(db.as_ref())
+            .await?;
+        let best = candidates
+            .into_iter()
+            .filter_map(|model| {
+                model.source.map(|cached| {
+                    let similarity = TextDiff::from_chars(source, cached.as_str()).ratio();
+                    (similarity, cached, model.translation)
+                })
+            })
+            .filter(|(similarity, _, _)| *similarity >= threshold)
+            .max_by(|(a, _, _), (b, _, _)| a.total_cmp(b))
+            .map(|(_, cached, translation)| (cached, translation));
+        Ok(best)
+    }
+
+    /// Seeds the cache with the entries of a previous project's cache database,
+    /// e.g. the prequel's, keeping entries that are already cached.
+    ///
+    /// Returns the number of entries added.
+    #[anyhow_context]
+    pub async fn preload_cache_from(db_path: &Path) -> AnyResult<usize> {
+        let previous = Database::connect(format!("sqlite://{}?mode=ro", db_path.display())).await?;
+        let entries = if column_exists::<Entity>(&previous, Column::Source).await? {
+            Entity::find().all(&previous).await?
+        } else {
+            Entity::find()
+                .select_only()
+                .columns([Column::SourceHash, Column::Translation])
+                .into_tuple::<(String, String)>()
+                .all(&previous)
+                .await?
+                .into_iter()
+                .map(|(source_hash, translation)| Model {
+                    source_hash,
+                    translation,
+                    source: None,
+                })
+                .collect()
+        };
+
+        let db = open_translation_cache().await?;
+        let mut added = 0;
+        for batch in entries.chunks(PRELOAD_BATCH) {
+            let models = batch.iter().map(|model| ActiveModel {
+                source_hash: Set(model.source_hash.clone()),
+                translation: Set(model.translation.clone()),
+                source: Set(model.source.clone()),
+            });
+            added += Entity::insert_many(models)
+                .on_conflict(
+                    OnConflict::column(Column::SourceHash)
+                        .do_nothing()
+                        .to_owned(),
+                )
+                .exec_without_returning(db.as_ref())
+                .await?;
+        }
+        Ok(added as usize)
+    }
+}
+
+pub mod checkpoint {
+    use anyhow::{Context, Result as AnyResult};
+    use auto_context::auto_context as anyhow_context;
+    use chrono::Utc;
+    use sea_orm::{
+        ActiveValue::Set, DatabaseConnection, entity::prelude::*, sea_query::OnConflict,
+    };
+    use serde_json::json;
+    use std::sync::Arc;
+
+    use super::{
+        create_entity_table, glossary, refusal, run_history,
+        text_segment::{connect, database_path},
+    };
+
+    /// The project-wide database holding run state, stored next to the segment database.
+    pub const PROJECT_DB_NAME: &str = "project";
+
+    /// The single row the checkpoint is kept in.
+    const CHECKPOINT_ID: i32 = 1;
+
+    /// Progress of the whole run, periodically overwritten so that `--resume` can
+    /// skip what a crashed run already finished.
+    #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+    #[sea_orm(table_name = "checkpoint")]
+    pub struct Model {
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub id: i32,
+        pub saved_at: DateTimeUtc,
+        /// Names of the files that went through every stage, as a JSON array.
+        #[sea_orm(column_type = "JsonBinary")]
+        pub files_completed: Json,
+        pub segments_translated: i64,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+
+    impl Model {
+        pub fn completed_files(&self) -> AnyResult<Vec<String>> {
+            Ok(serde_json::from_value(self.files_completed.clone())?)
+        }
+    }
+
+    #[anyhow_context]
+    pub async fn open_project_db() -> AnyResult<Arc<DatabaseConnection>> {
+        let db = connect(PROJECT_DB_NAME, database_path(PROJECT_DB_NAME).as_deref()).await?;
+        create_entity_table(db.as_ref(), Entity).await?;
+        create_entity_table(db.as_ref(), refusal::Entity).await?;
+        create_entity_table(db.as_ref(), glossary::Entity).await?;
+        create_entity_table(db.as_ref(), run_history::Entity).await?;
+        Ok(db)
+    }
+
+    #[anyhow_context]
+    pub async fn save_checkpoint(
+        db: Arc<DatabaseConnection>,
+        files_completed: &[String],
+        segments_translated: i64,
+    ) -> AnyResult<()> {
+        Entity::insert(ActiveModel {
+            id: Set(CHECKPOINT_ID),
+            saved_at: Set(Utc::now()),
+            files_completed: Set(json!(files_completed)),
+            segments_translated: Set(segments_translated),
+        })
+        .on_conflict(
+            OnConflict::column(Column::Id)
+                .update_columns([
+                    Column::SavedAt,
+                    Column::FilesCompleted,
+                    Column::SegmentsTranslated,
+                ])
+                .to_owned(),
+        )
+        .exec(db.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    #[anyhow_context]
+    pub async fn load_checkpoint(db: Arc<DatabaseConnection>) -> AnyResult<Option<Model>> {
+        let checkpoint = Entity::find_by_id(CHECKPOINT_ID).one(db.as_ref()).await?;
+        Ok(checkpoint)
+    }
+}
+
+pub mod run_history {
+    use anyhow::{Context, Result as AnyResult};
+    use auto_context::auto_context as anyhow_context;
+    use sea_orm::{ActiveValue::Set, DatabaseConnection, QueryOrder, entity::prelude::*};
+    use std::sync::Arc;
+
+    /// When each full pipeline run started, for `--since last`.
+    #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+    #[sea_orm(table_name = "run_history")]
+    pub struct Model {
+        #[sea_orm(primary_key)]
+        pub id: i32,
+        pub started_at: DateTimeUtc,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+
+    #[anyhow_context]
+    pub async fn record_run(db: Arc<DatabaseConnection>, started_at: DateTimeUtc) -> AnyResult<()> {
+        ActiveModel {
+            started_at: Set(started_at),
+            ..Default::default()
+        }
+        .insert(db.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    /// When the latest recorded run started.
+    #[anyhow_context]
+    pub async fn last_run(db: Arc<DatabaseConnection>) -> AnyResult<Option<DateTimeUtc>> {
+        let last = Entity::find()
+            .order_by_desc(Column::StartedAt)
+            .one(db.as_ref())
+            .await?
+            .map(|model| model.started_at);
+        Ok(last)
+    }
+}
+
+pub mod refusal {
+    use anyhow::{Context, Result as AnyResult};
+    use auto_context::auto_context as anyhow_context;
+    use chrono::Utc;
+    use sea_orm::{
+        ActiveValue::Set, DatabaseConnection, entity::prelude::*, sea_query::OnConflict,
+    };
+    use std::sync::Arc;
+
+    use crate::utils::sha256_hex;
+
+    /// Sources a backend refused to translate, keyed by the SHA-256 of the source,
+    /// so that later runs don't pay for the same refusal again.
+    #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+    #[sea_orm(table_name = "refusals")]
+    pub struct Model {
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub source_hash: String,
+        pub backend: String,
+        pub reason: String,
+        pub refused_at: DateTimeUtc,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+
+    /// Why `source` was refused before, if it was.
+    #[anyhow_context]
+    pub async fn find_refusal(
+        db: Arc<DatabaseConnection>,
+        source: &str,
+    ) -> AnyResult<Option<String>> {
+        let refusal = Entity::find_by_id(sha256_hex(source))
+            .one(db.as_ref())
+            .await?
+            .map(|model| model.reason);
+        Ok(refusal)
+    }
+
+    #[anyhow_context]
+    pub async fn record_refusal(
+        db: Arc<DatabaseConnection>,
+        source: &str,
+        backend: String,
+        reason: String,
+    ) -> AnyResult<()> {
+        Entity::insert(ActiveModel {
+            source_hash: Set(sha256_hex(source)),
+            backend: Set(backend),
+            reason: Set(reason),
+            refused_at: Set(Utc::now()),
+        })
+        .on_conflict(
+            OnConflict::column(Column::SourceHash)
+                .update_columns([Column::Backend, Column::Reason, Column::RefusedAt])
+                .to_owned(),
+        )
+        .exec(db.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    #[anyhow_context]
+    pub async fn clear_refusal(db: Arc<DatabaseConnection>, source: &str) -> AnyResult<()> {
+        Entity::delete_by_id(sha256_hex(source))
+            .exec(db.as_ref())
+            .await?;
+        Ok(())
+    }
+}
+
+pub mod glossary {
+    use anyhow::{Context, Result as AnyResult};
+    use auto_context::auto_context as anyhow_context;
+    use sea_orm::{
+        ActiveValue::Set, DatabaseConnection, entity::prelude::*, sea_query::OnConflict,
+    };
+    use std::sync::Arc;
+
+    /// Speaker names and their translation, shared by every file so that a
+    /// character is called the same everywhere.
+    #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+    #[sea_orm(table_name = "glossary")]
+    pub struct Model {
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub source: String,
+        pub translation: String,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+
+    #[anyhow_context]
+    pub async fn lookup_term(
+        db: Arc<DatabaseConnection>,
+        source: &str,
+    ) -> AnyResult<Option<String>> {
+        let term = Entity::find_by_id(source)
+            .one(db.as_ref())
+            .await?
+            .map(|model| model.translation);
+        Ok(term)
+    }
+
+    /// Records `translation` for `source` unless the glossary already has one,
+    /// returning the translation that is in the glossary afterwards.
+    #[anyhow_context]
+    pub async fn record_term(
+        db: Arc<DatabaseConnection>,
+        source: &str,
+        translation: &str,
+    ) -> AnyResult<String> {
+        Entity::insert(ActiveModel {
+            source: Set(source.into()),
+            translation: Set(translation.into()),
+        })
+        .on_conflict(OnConflict::column(Column::Source).do_nothing().to_owned())
+        .exec_without_returning(db.as_ref())
+        .await?;
+        let term = lookup_term(db, source).await?;
+        Ok(term.unwrap_or_else(|| translation.into()))
+    }
+}
+
+pub mod file_meta {
+    use anyhow::{Context, Result as AnyResult};
+    use auto_context::auto_context as anyhow_context;
+    use sea_orm::{ActiveValue::Set, QueryOrder, entity::prelude::*, sea_query::OnConflict};
+    use std::collections::BTreeMap;
+
+    use super::text_segment::FileDb;
+
+    /// Free-form metadata of a file, e.g. its chapter, set from a `.meta.toml`
+    /// sidecar or the `meta` subcommand.
+    #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+    #[sea_orm(table_name = "file_meta")]
+    pub struct Model {
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub file_name: String,
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub key: String,
+        pub value: String,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+
+    /// The name of the source file the database was parsed from, set by the
+    /// parser stage.
+    pub const SOURCE_FILE_KEY: &str = "source_file";
+
+    /// Sets `key` to `value`, replacing the value it had.
+    #[anyhow_context]
+    pub async fn set_file_meta(db: FileDb, key: &str, value: &str) -> AnyResult<()> {
+        Entity::insert(ActiveModel {
+            file_name: Set(db.name().into()),
+            key: Set(key.into()),
+            value: Set(value.into()),
+        })
+        .on_conflict(
+            OnConflict::columns([Column::FileName, Column::Key])
+                .update_column(Column::Value)
+                .to_owned(),
+        )
+        .exec_without_returning(db.as_ref())
+        .await?;
+        Ok(())
+    }
+
+    #[anyhow_context]
+    pub async fn get_file_meta(db: FileDb, key: &str) -> AnyResult<Option<String>> {
+        let value = Entity::find_by_id((db.name().to_owned(), key.to_owned()))
+            .one(db.as_ref())
+            .await?
+            .map(|model| model.value);
+        Ok(value)
+    }
+
+    /// Every key of the file with its value.
+    #[anyhow_context]
+    pub async fn list_file_meta(db: FileDb) -> AnyResult<BTreeMap<String, String>> {
+        let meta = Entity::find()
+            .filter(Column::FileName.eq(db.name()))
+            .order_by_asc(Column::Key)
+            .all(db.as_ref())
+            .await?
+            .into_iter()
+            .map(|model| (model.key, model.value))
+            .collect();
+        Ok(meta)
+    }
+}
+
+/// The translations of the segments, into the target language of their file and
+/// into `translator.additional_languages`. The status of a segment, see
+/// [`SegmentStatus`], and its audit trail describe its target-language row.
+///
+/// [`SegmentStatus`]: super::text_segment::SegmentStatus
+pub mod segment_translation {
+    use anyhow::{Context, Result as AnyResult};
+    use auto_context::auto_context as anyhow_context;
+    use sea_orm::{
+        ActiveValue::Set,
+        ConnectionTrait, QueryOrder,
+        entity::prelude::*,
+        sea_query::{OnConflict, Query, SelectStatement},
+    };
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    use super::text_segment::{self, FileDb, RowId, SegmentStatus, TextSegmentType, Translation};
+
+    /// One row per translated segment and language.
+    #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+    #[sea_orm(table_name = "translations")]
+    pub struct Model {
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub segment_id: i32,
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub lang: String,
+        /// A serialized [`Translation`].
+        #[sea_orm(column_type = "JsonBinary")]
+        pub translation: Json,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+
+    /// Writes `translation`, serialized, as the `lang` translation of segment
+    /// `id`, replacing an earlier one.
+    pub(super) async fn write_translation<C: ConnectionTrait>(
+        conn: &C,
+        RowId(id): RowId,
+        lang: &str,
+        translation: Json,
+    ) -> AnyResult<()> {
+        Entity::insert(ActiveModel {
+            segment_id: Set(id),
+            lang: Set(lang.into()),
+            translation: Set(translation),
+        })
+        .on_conflict(
+            OnConflict::columns([Column::SegmentId, Column::Lang])
+                .update_column(Column::Translation)
+                .to_owned(),
+        )
+        .exec_without_returning(conn)
+        .await?;
+        Ok(())
+    }
+
+    /// The ids of the segments translated into `lang`, as a subquery.
+    pub(super) fn translated_into(lang: &str) -> SelectStatement {
+        Query::select()
+            .column(Column::SegmentId)
+            .from(Entity)
+            .and_where(Column::Lang.eq(lang))
+            .to_owned()
+    }
+
+    /// The `lang` translations of the segments `ids` that have one.
+    pub(super) async fn translations_of<C: ConnectionTrait>(
+        conn: &C,
+        lang: &str,
+        ids: Vec<i32>,
+    ) -> AnyResult<HashMap<RowId, Translation>> {
+        let mut translations = HashMap::new();
+        for model in Entity::find()
+            .filter(Column::Lang.eq(lang))
+            .filter(Column::SegmentId.is_in(ids))
+            .all(conn)
+            .await?
+        {
+            let translation = serde_json::from_value(model.translation)?;
+            translations.insert(RowId(model.segment_id), translation);
+        }
+        Ok(translations)
+    }
+
+    /// Writes the `lang` translation of segment `id`, an additional language,
+    /// replacing an earlier one. See [`record_translation`] for the target language.
+    ///
+    /// [`record_translation`]: text_segment::record_translation
+    #[anyhow_context]
+    pub async fn record_localized(
+        db: FileDb,
+        id: RowId,
+        lang: &str,
+        translation: Translation,
+    ) -> AnyResult<()> {
+        write_translation(db.as_ref(), id, lang, json!(translation)).await
+    }
+
+    /// Drops the translations into the additional languages of the segments that
+    /// aren't locked, or of all segments unless `keep_locked`, returning how many.
+    #[anyhow_context]
+    pub async fn clear_localized(db: FileDb, keep_locked: bool) -> AnyResult<u64> {
+        let mut segments = text_segment::segments_of(&db);
+        if keep_locked {
+            segments.and_where(text_segment::Column::Status.ne(SegmentStatus::Locked));
+        }
+        let cleared = Entity::delete_many()
+            .filter(Column::Lang.ne(db.language()))
+            .filter(Column::SegmentId.in_subquery(segments))
+            .exec(db.as_ref())
+            .await?;
+        Ok(cleared.rows_affected)
+    }
+
+    /// The `lang` translations of the file, by segment.
+    #[anyhow_context]
+    pub async fn load_localized(db: FileDb, lang: &str) -> AnyResult<HashMap<RowId, Translation>> {
+        let mut translations = HashMap::new();
+        for model in Entity::find()
+            .filter(Column::Lang.eq(lang))
+            .filter(Column::SegmentId.in_subquery(text_segment::segments_of(&db)))
+            .all(db.as_ref())
+            .await?
+        {
+            let translation = serde_json::from_value(model.translation)?;
+            translations.insert(RowId(model.segment_id), translation);
+        }
+        Ok(translations)
+    }
+
+    /// Messages with no `lang` translation yet that aren't locked, in insertion
+    /// order, optionally only those enclosed in `region`.
+    #[anyhow_context]
+    pub async fn load_untranslated_into(
+        db: FileDb,
+        lang: &str,
+        region: Option<&str>,
+    ) -> AnyResult<Vec<text_segment::Model>> {
+        let translated = load_localized(db.clone(), lang).await?;
+        let mut query = text_segment::Entity::find()
+            .filter(text_segment::Column::FileName.eq(db.name()))
+            .filter(text_segment::Column::SegmentType.eq(TextSegmentType::IMessage))
+            .filter(text_segment::Column::Status.ne(SegmentStatus::Locked));
+        if let Some(region) = region {
+            query = query.filter(Expr::cust("json_extract(content, '$.region')").eq(region));
+        }
+        let untranslated = query
+            .order_by_asc(text_segment::Column::Id)
+            .all(db.as_ref())
+            .await?
+            .into_iter()
+            .filter(|model| !translated.contains_key(&model.row_id()))
+            .collect();
+        Ok(untranslated)
+    }
+}
+
+pub mod translation_audit {
+    use sea_orm::entity::prelude::*;
+
+    /// Append-only history of every write to the target-language translations.
+    ///
+    /// Rows are only ever inserted; a trigger created by the migration aborts any
+    /// `UPDATE` or `DELETE`.
+    #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+    #[sea_orm(table_name = "translation_audit")]
+    pub struct Model {
+        #[sea_orm(primary_key)]
+        pub id: i32,
+        pub segment_id: i32,
+        pub translated_at: DateTimeUtc,
+        /// The [`super::Translation`] that was written, as JSON.
+        #[sea_orm(column_type = "JsonBinary")]
+        pub translation: Json,
+        /// e.g. `openai/gpt-4o-mini`
+        pub backend: String,
+        /// SHA-256 of the prompt the backend was driven with.
+        pub prompt_hash: String,
+        /// The stage or command that caused the write.
+        pub triggered_by: String,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+
+    pub(super) const APPEND_ONLY_TRIGGERS: &str = "
+        CREATE TRIGGER IF NOT EXISTS translation_audit_no_update
+        BEFORE UPDATE ON translation_audit
+        BEGIN SELECT RAISE(ABORT, 'translation_audit is append-only'); END;
+        CREATE TRIGGER IF NOT EXISTS translation_audit_no_delete
+        BEFORE DELETE ON translation_audit
+        BEGIN SELECT RAISE(ABORT, 'translation_audit is append-only'); END;
+    ";
+}
+
+pub mod schema_version {
+    use anyhow::{Context, Result as AnyResult};
+    use auto_context::auto_context as anyhow_context;
+    use futures::future::BoxFuture;
+    use sea_orm::{
+        ActiveValue::Set, ConnectionTrait, DatabaseConnection, QueryOrder, Statement,
+        TransactionTrait, entity::prelude::*,
+    };
+    use std::sync::Arc;
+
+    use super::{
+        add_column_if_missing, create_entity_table, file_meta, has_column, pipeline_state,
+        segment_translation::{self, write_translation},
+        text_segment::{self, RowId, create_table, target_language_of},
+        translation_audit, variable,
+    };
+
+    /// One row per applied migration, the highest `version` is the current schema.
+    #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+    #[sea_orm(table_name = "schema_version")]
+    pub struct Model {
+        #[sea_orm(primary_key, auto_increment = false)]
+        pub version: i32,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+
+    type Migration = fn(Arc<DatabaseConnection>) -> BoxFuture<'static, AnyResult<()>>;
+
+    /// Ordered migrations keyed by the version they bring the database to.
+    ///
+    /// A crash between a step and its version stamp re-runs that step on the next
+    /// start, so every step must be a no-op on an already migrated database.
+    const MIGRATIONS: &[(i32, Migration)] = &[
+        (1, |db| Box::pin(create_table(db))),
+        (2, |db| {
+            Box::pin(async move { create_entity_table(db.as_ref(), pipeline_state::Entity).await })
+        }),
+        (3, |db| {
+            Box::pin(async move {
+                // Moved to `translations` by version 13.
+                if !has_column(db.as_ref(), "text_segments", "translated_content").await? {
+                    db.execute_unprepared(
+                        "ALTER TABLE text_segments ADD COLUMN translated_content jsonb_text",
+                    )
+                    .await?;
+                }
+                Ok(())
+            })
+        }),
+        (4, |db| {
+            Box::pin(async move { create_entity_table(db.as_ref(), variable::Entity).await })
+        }),
+        (5, |db| {
+            Box::pin(async move {
+                create_entity_table(db.as_ref(), translation_audit::Entity).await?;
+                db.execute_unprepared(translation_audit::APPEND_ONLY_TRIGGERS)
+                    .await?;
+                Ok(())
+            })
+        }),
+        (6, |db| {
+            Box::pin(async move {
+                add_column_if_missing::<text_segment::Entity>(
+                    db.as_ref(),
+                    text_segment::Column::Locked,
+                )
+                .await
+            })
+        }),
+        (7, |db| {
+            Box::pin(async move {
+                for column in [
+                    pipeline_state::Column::StartedAt,
+                    pipeline_state::Column::FinishedAt,
+                ] {
+                    add_column_if_missing::<pipeline_state::Entity>(db.as_ref(), column).await?;
+                }
+                Ok(())
+            })
+        }),
+        (8, |db| {
+            Box::pin(async move {
+                add_column_if_missing::<text_segment::Entity>(
+                    db.as_ref(),
+                    text_segment::Column::Status,
+                )
+                .await?;
+                db.execute_unprepared(text_segment::BACKFILL_STATUS).await?;
+                Ok(())
+            })
+        }),
+        (9, |db| {
+            Box::pin(
+                async move { create_entity_table(db.as_ref(), segment_translation::Entity).await },
+            )
+        }),
+        (10, |db| {
+            Box::pin(async move { create_entity_table(db.as_ref(), file_meta::Entity).await })
+        }),
+        (11, |db| {
+            Box::pin(async move {
+                add_column_if_missing::<text_segment::Entity>(
+                    db.as_ref(),
+                    text_segment::Column::TranslateMs,
+                )
+                .await
+            })
+        }),
+        (12, |db| Box::pin(key_by_file(db))),
+        (13, |db| Box::pin(move_translations(db))),
+        (14, |db| {
+            Box::pin(async move {
+                add_column_if_missing::<pipeline_state::Entity>(
+                    db.as_ref(),
+                    pipeline_state::Column::Error,
+                )
+                .await
+            })
+        }),
+    ];
+
+    /// The tables keyed by file since the files share one database, with the
+    /// columns they had before, see [`key_by_file`].
+    const KEYED_BY_FILE: &[(&str, &str)] = &[
+        (
+            "pipeline_state",
+            "stage, completed, started_at, finished_at",
+        ),
+        ("variables", "token, occurrences"),
+        // Last, it records the name of the file until then.
+        ("file_meta", "key, value"),
+    ];
+
+    /// Moves the rows of a database of a single file, from before the files
+    /// shared one, to the name of that file as the parser recorded it.
+    #[anyhow_context]
+    async fn key_by_file(db: Arc<DatabaseConnection>) -> AnyResult<()> {
+        let backend = db.get_database_backend();
+        let source = db
+            .query_one(Statement::from_string(
+                backend,
+                "SELECT value FROM file_meta WHERE key = 'source_file' LIMIT 1",
+            ))
+            .await?
+            .map(|row| row.try_get_by_index::<String>(0))
+            .transpose()?
+            .unwrap_or_default();
+
+        add_column_if_missing::<text_segment::Entity>(db.as_ref(), text_segment::Column::FileName)
+            .await?;
+        db.execute(Statement::from_sql_and_values(
+            backend,
+            "UPDATE text_segments SET file_name = ? WHERE file_name = ''",
+            [source.clone().into()],
+        ))
+        .await?;
+        db.execute_unprepared(
+            "CREATE INDEX IF NOT EXISTS text_segments_file_name ON text_segments (file_name)",
+        )
+        .await?;
+
+        for (table, columns) in KEYED_BY_FILE {
+            if has_column(db.as_ref(), table, "file_name").await? {
+                continue;
+            }
+            // SQLite can't change the primary key of a table, it is copied over.
+            let txn = db.begin().await?;
+            txn.execute_unprepared(&format!("ALTER TABLE {table} RENAME TO {table}_legacy"))
+                .await?;
+            match *table {
+                "pipeline_state" => create_entity_table(&txn, pipeline_state::Entity).await?,
+                "variables" => create_entity_table(&txn, variable::Entity).await?,
+                _ => create_entity_table(&txn, file_meta::Entity).await?,
+            }
+            txn.execute(Statement::from_sql_and_values(
+                backend,
+                format!(
+                    "INSERT INTO {table} (file_name, {columns}) SELECT ?, {columns} FROM {table}_legacy"
+                ),
+                [source.clone().into()],
+            ))
+            .await?;
+            txn.execute_unprepared(&format!("DROP TABLE {table}_legacy"))
+                .await?;
+            txn.commit().await?;
+        }
+        Ok(())
+    }
+
+    /// Moves the target-language translations of the segments from their column
+    /// in `text_segments` to rows of `translations`, see [`segment_translation`].
+    #[anyhow_context]
+    async fn move_translations(db: Arc<DatabaseConnection>) -> AnyResult<()> {
+        if !has_column(db.as_ref(), "text_segments", "translated_content").await? {
+            return Ok(());
+        }
+        let backend = db.get_database_backend();
+        let txn = db.begin().await?;
+        let translated = txn
+            .query_all(Statement::from_string(
+                backend,
+                "SELECT id, file_name, translated_content FROM text_segments
+                 WHERE translated_content IS NOT NULL",
+            ))
+            .await?;
+        for row in translated {
+            let (id, file_name, translation): (i32, String, Json) = (
+                row.try_get_by_index(0)?,
+                row.try_get_by_index(1)?,
+                row.try_get_by_index(2)?,
+            );
+            let lang = target_language_of(&file_name);
+            write_translation(&txn, RowId(id), &lang, translation).await?;
+        }
+        txn.execute_unprepared("ALTER TABLE text_segments DROP COLUMN translated_content")
+            .await?;
+        txn.commit().await?;
+        Ok(())
+    }
+
+    #[anyhow_context]
+    async fn current_version(db: &DatabaseConnection) -> AnyResult<i32> {
+        create_entity_table(db, Entity).await?;
+
+        let latest = Entity::find()
+            .order_by_desc(Column::Version)
+            .one(db)
+            .await?;
+        Ok(latest.map_or(0, |model| model.version))
+    }
+
+    #[anyhow_context]
+    pub async fn run_migrations(db: Arc<DatabaseConnection>) -> AnyResult<()> {
+        let current = current_version(db.as_ref()).await?;
+        for (version, migration) in MIGRATIONS.iter().filter(|(v, _)| *v > current) {
+            migration(db.clone()).await?;
+            ActiveModel {
+                version: Set(*version),
+            }
+            .insert(db.as_ref())
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+pub use checkpoint::{load_checkpoint, open_project_db, save_checkpoint};
+pub use file_meta::{SOURCE_FILE_KEY, get_file_meta, list_file_meta, set_file_meta};
+pub use glossary::{lookup_term, record_term};
+pub use pipeline_state::{
+    PipelineStage, clear_stage, clear_stage_failures, is_stage_done, mark_stage_done,
+    mark_stage_failed, mark_stage_started, stage_failure, stage_states,
+};
+pub use refusal::{clear_refusal, find_refusal, record_refusal};
+pub use run_history::{last_run, record_run};
+pub use schema_version::run_migrations;
+pub use segment_translation::{
+    clear_localized, load_localized, load_untranslated_into, record_localized,
+};
+pub use text_segment::{
+    AuditEntry, Column as TextSegmentColumn, Entity as TextSegmentEntity, FileDb, IMessageModel,
+    INonMessageModel, InsertModel as TextSegment, InsertModelBuilder as TextSegmentBuilder, Line,
+    MANUAL_BACKEND, MessageId, Provenance, RowId, SEGMENTS_DB_NAME, SegmentStatus, TachieVariant,
+    Translation, clear_segments, clear_translations, count_failed, count_messages, count_segments,
+    count_translated, create_db_connection, create_db_connection_at, database_path, flush_wal,
+    insert_segments, list_files, list_includes, list_tachie, load_messages, load_non_messages,
+    load_preceding_messages, load_translated_segments, load_untranslated, open_segments_db,
+    record_translate_time, record_translation, search_segments, set_database_dir, set_locked,
+    set_status, set_target_languages, set_translation, slowest_segments, translation_backends,
+    translation_provenance, visit_translated_segments,
+};
+pub use translation_cache::{
+    CACHE_DB_NAME, cache_path, cache_translation, fuzzy_match, lookup_translation,
+    open_translation_cache, preload_cache_from, set_cache_path,
+};
+pub use variable::{clear_variables, save_variables};
+
+#[cfg(feature = "server")]
+pub use text_segment::{ReviewStatus, find_segment, lock_segment};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ParserConfig, parser::parse_string, utils::test_dir};
+    use sea_orm::{ActiveValue::Set, ConnectionTrait, IntoActiveModel, entity::prelude::*};
+    use std::{fs::remove_dir_all, time::Instant};
+
+    /// The rows of a file of its own holding the segments of `script`.
+    async fn database(name: &str, script: &str) -> FileDb {
+        let db = create_db_connection(&format!("storage-test-{name}"))
+            .await
+            .unwrap();
+        let segments = parse_string(script, "test.sc", &ParserConfig::default()).unwrap();
+        insert_segments(db.clone(), &segments, 500).await.unwrap();
+        db
+    }
+
+    /// The row of the first message.
+    async fn first_message(db: &FileDb) -> text_segment::Model {
+        TextSegmentEntity::find()
+            .filter(TextSegmentColumn::FileName.eq(db.name()))
+            .filter(TextSegmentColumn::SegmentType.eq(text_segment::TextSegmentType::IMessage))
+            .one(db.as_ref())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn the_locked_column_follows_the_status() {
+        let db = database("locked-status", ".message 1 hello\n").await;
+        let id = first_message(&db).await.row_id();
+
+        set_status(db.clone(), id, SegmentStatus::Locked)
+            .await
+            .unwrap();
+        let locked = first_message(&db).await;
+        assert!(locked.is_locked() && locked.locked);
+        assert!(
+            load_untranslated(db.clone(), None)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        assert_eq!(set_locked(db.clone(), &[Line(1)], false).await.unwrap(), 1);
+        let unlocked = first_message(&db).await;
+        assert_eq!(unlocked.status, SegmentStatus::Untranslated);
+        assert!(!unlocked.is_locked() && !unlocked.locked);
+        assert_eq!(set_locked(db.clone(), &[Line(1)], false).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn an_approved_review_approves_the_segment() {
+        let db = database("approved-review", ".message 1 hello\n").await;
+        let id = first_message(&db).await.row_id();
+        let audit = AuditEntry {
+            backend: "test".into(),
+            prompt_hash: String::new(),
+            triggered_by: "test".into(),
+        };
+        let translation = Translation::new("bonjour".into());
+        record_translation(db.clone(), id, translation.clone(), audit.clone())
+            .await
+            .unwrap();
+        assert_eq!(first_message(&db).await.status, SegmentStatus::Translated);
+
+        let approved = Translation {
+            status: text_segment::ReviewStatus::Approved,
+            ..translation
+        };
+        record_translation(db.clone(), id, approved, audit)
+            .await
+            .unwrap();
+        assert_eq!(first_message(&db).await.status, SegmentStatus::Approved);
+    }
+
+    #[tokio::test]
+    async fn a_translation_set_by_hand_reads_back() {
+        let db = database("set-translation", ".message 1 hello\n").await;
+        let id = first_message(&db).await.row_id();
+        set_translation(db.clone(), id, "bonjour".into())
+            .await
+            .unwrap();
+
+        let (_, segment, translation) = load_translated_segments(db.clone())
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|(row_id, _, _)| *row_id == id)
+            .unwrap();
+        assert!(matches!(segment, TextSegment::IMessage(message) if message.content == "hello"));
+        assert_eq!(translation, Some(Translation::new("bonjour".into())));
+        let provenance = translation_provenance(db).await.unwrap();
+        assert_eq!(provenance[&id].backend, MANUAL_BACKEND);
+    }
+
+    #[tokio::test]
+    async fn deleted_segments_never_lend_their_ids() {
+        let script = ".message 1 hello\n";
+        let db = database("reparsed", script).await;
+        let id = first_message(&db).await.row_id();
+        set_translation(db.clone(), id, "bonjour".into())
+            .await
+            .unwrap();
+
+        clear_segments(db.clone()).await.unwrap();
+        let segments = parse_string(script, "test.sc", &ParserConfig::default()).unwrap();
+        insert_segments(db.clone(), &segments, 500).await.unwrap();
+        assert!(first_message(&db).await.id > id.0);
+        assert!(translation_provenance(db).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn messages_and_non_messages_partition_the_segments() {
+        let script = concat!(
+            "; intro\n",
+            ".message 1 first\n",
+            ".bg 01\n",
+            "\n",
+            ".message 2 Alice 「second」\n",
+            "#include other.sc\n",
+        );
+        let db = database("partition", script).await;
+        let messages = load_messages(db.clone()).await.unwrap();
+        let non_messages = load_non_messages(db.clone()).await.unwrap();
+
+        let total = count_segments(db).await.unwrap() as usize;
+        assert_eq!(messages.len() + non_messages.len(), total);
+        let mut lines = messages
+            .iter()
+            .map(|message| message.line)
+            .chain(non_messages.iter().map(|non_message| non_message.line))
+            .collect::<Vec<_>>();
+        lines.sort();
+        lines.dedup();
+        assert_eq!(lines.len(), total);
+        assert_eq!(
+            messages
+                .iter()
+                .map(|message| message.id)
+                .collect::<Vec<_>>(),
+            [MessageId(1), MessageId(2)]
+        );
+        assert_eq!(non_messages.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn a_database_file_persists_across_connections() {
+        let dir = test_dir("db-at");
+        let path = dir.join("nested").join("file.sc.db");
+        let segments =
+            parse_string(".message 1 hello\n\n", "file.sc", &ParserConfig::default()).unwrap();
+        {
+            let db = create_db_connection_at(&path).await.unwrap();
+            run_migrations(db.clone()).await.unwrap();
+            insert_segments(FileDb::new(db, "file.sc"), &segments, 500)
+                .await
+                .unwrap();
+        }
+
+        let db = create_db_connection_at(&path).await.unwrap();
+        let file = FileDb::new(db.clone(), "file.sc");
+        assert_eq!(count_segments(file).await.unwrap(), 2);
+        let mode = db
+            .query_one(Statement::from_string(
+                db.get_database_backend(),
+                "PRAGMA journal_mode",
+            ))
+            .await
+            .unwrap()
+            .unwrap()
+            .try_get_by_index::<String>(0)
+            .unwrap();
+        assert_eq!(mode, "wal");
+        drop(db);
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_message_builds_only_with_its_line_and_id() {
+        let no_line = TextSegmentBuilder::new_message()
+            .id(MessageId(7))
+            .content("hello");
+        assert_eq!(no_line.header(), (None, Some(MessageId(7))));
+        assert!(
+            no_line
+                .build()
+                .unwrap_err()
+                .to_string()
+                .contains("Message has no line")
+        );
+
+        let no_id = TextSegmentBuilder::new_message()
+            .line(Line(3))
+            .content("hello");
+        let error = no_id.build().unwrap_err().to_string();
+        assert!(error.contains("`.message` at line 3 has content but no message id"));
+
+        let complete = TextSegmentBuilder::new_message()
+            .line(Line(3))
+            .id(MessageId(7))
+            .content("hello");
+        assert_eq!(complete.header(), (Some(Line(3)), Some(MessageId(7))));
+        assert!(complete.build().is_ok());
+    }
+
+    #[tokio::test]
+    async fn segments_are_inserted_in_batches_all_or_nothing() {
+        let script = ".message 1 a\n.bg 1\n.message 2 b\n.bg 2\n.message 3 c\n";
+        let segments = parse_string(script, "test.sc", &ParserConfig::default()).unwrap();
+
+        let db = database("batches", "").await;
+        insert_segments(db.clone(), &segments, 2).await.unwrap();
+        let stored = load_translated_segments(db.clone())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(_, segment, _)| segment)
+            .collect::<Vec<_>>();
+        assert_eq!(stored, segments);
+
+        let failing = database("batches-failing", "").await;
+        failing
+            .execute_unprepared(
+                "CREATE TRIGGER fail BEFORE INSERT ON text_segments
+                 WHEN NEW.file_name = 'storage-test-batches-failing'
+                 AND json_extract(NEW.content, '$.line') = 3
+                 BEGIN SELECT RAISE(ABORT, 'refused'); END",
+            )
+            .await
+            .unwrap();
+        assert!(
+            insert_segments(failing.clone(), &segments, 2)
+                .await
+                .is_err()
+        );
+        assert_eq!(count_segments(failing).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn every_language_is_a_row_of_its_own() {
+        let db = database("localized", ".message 1 hello\n").await;
+        let id = first_message(&db).await.row_id();
+        assert_eq!(db.language(), "English");
+
+        set_translation(db.clone(), id, "bonjour".into())
+            .await
+            .unwrap();
+        record_localized(db.clone(), id, "German", Translation::new("hallo".into()))
+            .await
+            .unwrap();
+        let english = load_localized(db.clone(), "English").await.unwrap();
+        assert_eq!(english[&id].content, "bonjour");
+        let (_, _, translation) = load_translated_segments(db.clone())
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|(row_id, _, _)| *row_id == id)
+            .unwrap();
+        assert_eq!(translation, Some(Translation::new("bonjour".into())));
+        assert!(
+            load_untranslated_into(db.clone(), "German", None)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        assert_eq!(clear_translations(db.clone()).await.unwrap(), 1);
+        assert_eq!(count_translated(db.clone()).await.unwrap(), 0);
+        let german = load_localized(db.clone(), "German").await.unwrap();
+        assert_eq!(german[&id].content, "hallo");
+        assert_eq!(clear_localized(db.clone(), true).await.unwrap(), 1);
+        assert!(load_localized(db, "German").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn locked_segments_are_only_changed_by_unlocking_them() {
+        let db = database("locked-translation", ".message 1 hello\n").await;
+        let id = first_message(&db).await.row_id();
+        let audit = AuditEntry {
+            backend: "test".into(),
+            prompt_hash: String::new(),
+            triggered_by: "test".into(),
+        };
+        let translation = Translation::new("bonjour".into());
+        record_translation(db.clone(), id, translation.clone(), audit.clone())
+            .await
+            .unwrap();
+        assert_eq!(set_locked(db.clone(), &[Line(1)], true).await.unwrap(), 1);
+
+        let approved = Translation {
+            status: text_segment::ReviewStatus::Approved,
+            ..translation.clone()
+        };
+        assert!(
+            record_translation(db.clone(), id, approved, audit.clone())
+                .await
+                .is_err()
+        );
+        assert!(
+            set_status(db.clone(), id, SegmentStatus::Untranslated)
+                .await
+                .is_err()
+        );
+        assert_eq!(first_message(&db).await.status, SegmentStatus::Locked);
+
+        assert_eq!(set_locked(db.clone(), &[Line(1)], false).await.unwrap(), 1);
+        assert_eq!(first_message(&db).await.status, SegmentStatus::Translated);
+        record_translation(db.clone(), id, translation, audit)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn search_folds_case_and_width_and_escapes_globs() {
+        let script = ".message 1 Hello ＷＯＲＬＤ\n.message 2 a*b\n.message 3 axb\n";
+        let db = database("search", script).await;
+        let lines = |found: Vec<(text_segment::Model, Option<Translation>)>| {
+            found
+                .into_iter()
+                .map(|(model, _)| TextSegment::try_from(model).unwrap().line())
+                .collect::<Vec<_>>()
+        };
+        let search =
+            |query: &'static str, in_translated| search_segments(db.clone(), query, in_translated);
+        assert_eq!(lines(search("world", false).await.unwrap()), [Line(1)]);
+        assert_eq!(
+            lines(search("ｈｅｌｌｏ\u{3000}w", false).await.unwrap()),
+            [Line(1)]
+        );
+        assert_eq!(lines(search("A*B", false).await.unwrap()), [Line(2)]);
+        assert!(search("world", true).await.unwrap().is_empty());
+
+        let audit = AuditEntry {
+            backend: "test".into(),
+            prompt_hash: String::new(),
+            triggered_by: "test".into(),
+        };
+        let id = first_message(&db).await.row_id();
+        record_translation(
+            db.clone(),
+            id,
+            Translation::new("Bonjour le monde".into()),
+            audit,
+        )
+        .await
+        .unwrap();
+        let found = search("MONDE", true).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].1.as_ref().unwrap().content, "Bonjour le monde");
+    }
+
+    /// Run with `cargo test -- --ignored` on a quiet machine; timings are noisy.
+    #[tokio::test]
+    #[ignore = "benchmark"]
+    async fn batched_inserts_beat_inserting_each_segment() {
+        let script = (1..=10_000)
+            .map(|n| format!(".message {n} line {n}\n"))
+            .collect::<String>();
+        let segments = parse_string(&script, "test.sc", &ParserConfig::default()).unwrap();
+
+        let batched = database("bench-batched", "").await;
+        let started = Instant::now();
+        insert_segments(batched.clone(), &segments, 500)
+            .await
+            .unwrap();
+        let batched_time = started.elapsed();
+
+        let per_node = database("bench-per-node", "").await;
+        let started = Instant::now();
+        for segment in &segments {
+            TextSegmentEntity::insert(text_segment::ActiveModel {
+                file_name: Set(per_node.name().into()),
+                ..segment.clone().into_active_model()
+            })
+            .exec_without_returning(per_node.as_ref())
+            .await
+            .unwrap();
+        }
+        let per_node_time = started.elapsed();
+
+        eprintln!("batched: {batched_time:?}, per node: {per_node_time:?}");
+        assert_eq!(count_segments(batched).await.unwrap(), 10_000);
+        assert_eq!(count_segments(per_node).await.unwrap(), 10_000);
+        assert!(batched_time * 2 < per_node_time);
+    }
+}
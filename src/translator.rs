@@ -0,0 +1,1828 @@
+use crate::{
+    analyzer::detect_segment_language,
+    config::{
+        Config, ContextUnit, FewShotExample, FewShotExamples, FileOverride, GlossaryConfig,
+        TranslatorConfig,
+    },
+    jobs::{AssemblerJob, AssemblerJobQueue, Job, TranslatorJob, record_failure},
+    profile::{self, Phase},
+    storage::{
+        AuditEntry, FileDb, IMessageModel, Line, PipelineStage, SegmentStatus, TextSegment,
+        TextSegmentColumn, TextSegmentEntity, Translation, WriteGate, cache_translation,
+        clear_refusal, create_db_connection, find_refusal, fuzzy_match, get_file_meta,
+        load_localized, load_preceding_messages, load_translated_segments, load_untranslated,
+        load_untranslated_into, lookup_term, lookup_translation, mark_stage_done,
+        mark_stage_started, open_project_db, open_translation_cache, record_localized,
+        record_refusal, record_term, record_translate_time, record_translation, set_status,
+    },
+    utils::{IntoAnyResult, sha256_hex},
+};
+use anyhow::{Context, Result as AnyResult, bail};
+use apalis::prelude::{Data, Storage};
+use async_openai::{Client, config::OpenAIConfig};
+use auto_context::auto_context as anyhow_context;
+use futures::future::BoxFuture;
+use rand::{SeedableRng, seq::SliceRandom};
+use rand_chacha::ChaCha8Rng;
+use regex::Regex;
+use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use static_assertions::assert_impl_all;
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    error::Error,
+    fmt::{self, Display, Formatter},
+    fs::read_to_string,
+    path::Path,
+    sync::Arc,
+    time::Instant,
+};
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+use unicode_segmentation::UnicodeSegmentation;
+use whatlang::Lang;
+
+#[allow(unused)]
+type TranslatorResult<T> = AnyResult<T>;
+
+/// A translation backend or a decorator around one.
+///
+/// Translators are shared by concurrent workers through `Data<SharedTranslator>`,
+/// hence `Send + Sync`.
+pub trait Translator: Send + Sync {
+    fn translate<'a>(&'a self, source: &'a str) -> BoxFuture<'a, TranslatorResult<String>>;
+
+    /// Translates `source` with extra guidance for the backend, see [`Hints`].
+    /// Backends without a prompt to add the hints to just translate.
+    fn translate_with_hints<'a>(
+        &'a self,
+        source: &'a str,
+        hints: &'a Hints,
+    ) -> BoxFuture<'a, TranslatorResult<String>> {
+        let _ = hints;
+        self.translate(source)
+    }
+
+    /// Identifies the backend in the translation audit log, e.g. `openai/gpt-4o-mini`.
+    fn backend(&self) -> String;
+
+    /// The prompt the backend is driven with, hashed into the audit log.
+    fn prompt(&self) -> &str;
+}
+
+pub type SharedTranslator = Arc<dyn Translator>;
+
+/// What a backend may be told besides the source itself.
+#[derive(Clone, Debug, Default)]
+pub struct Hints {
+    /// What surrounds the source, see [`ContextProvider`].
+    pub context: Vec<String>,
+    /// What was wrong with the previous attempt at translating the source.
+    pub feedback: Option<String>,
+    /// Example translations in the voice of the speaker, see `translator.few_shot`.
+    pub examples: Vec<FewShotExample>,
+}
+
+impl<T: Translator + ?Sized> Translator for Arc<T> {
+    fn translate<'a>(&'a self, source: &'a str) -> BoxFuture<'a, TranslatorResult<String>> {
+        self.as_ref().translate(source)
+    }
+
+    fn translate_with_hints<'a>(
+        &'a self,
+        source: &'a str,
+        hints: &'a Hints,
+    ) -> BoxFuture<'a, TranslatorResult<String>> {
+        self.as_ref().translate_with_hints(source, hints)
+    }
+
+    fn backend(&self) -> String {
+        self.as_ref().backend()
+    }
+
+    fn prompt(&self) -> &str {
+        self.as_ref().prompt()
+    }
+}
+
+/// The backend declined to translate a source (content filter, model refusal),
+/// as opposed to a transient failure worth retrying.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Refused {
+    pub reason: String,
+}
+
+impl Display for Refused {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Translation refused: {}", self.reason)
+    }
+}
+
+impl Error for Refused {}
+
+/// A source is longer than `translator.max_input_chars` and could not be split
+/// to fit, so it was never sent to the backend.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TooLong {
+    pub chars: usize,
+    pub max_chars: usize,
+}
+
+impl Display for TooLong {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Too long to translate: {} characters, the limit is {}",
+            self.chars, self.max_chars
+        )
+    }
+}
+
+impl Error for TooLong {}
+
+/// Whether `error` won't go away by trying again: the source was refused or is
+/// too long. Such sources are skipped and left untranslated.
+fn is_permanent(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<Refused>().is_some() || error.downcast_ref::<TooLong>().is_some()
+}
+
+/// Any OpenAI compatible chat completion endpoint (OpenAI, ollama, vLLM, ...).
+pub struct OpenAiTranslator {
+    client: Client<OpenAIConfig>,
+    model: String,
+    system_prompt: String,
+}
+
+impl OpenAiTranslator {
+    pub fn new(config: &TranslatorConfig) -> Self {
+        let openai = OpenAIConfig::new()
+            .with_api_base(&config.api_base)
+            .with_api_key(&config.api_key);
+        OpenAiTranslator {
+            client: Client::with_config(openai),
+            model: config.model.clone(),
+            system_prompt: system_prompt(config),
+        }
+    }
+}
+
+fn system_prompt(config: &TranslatorConfig) -> String {
+    let mut prompt = format!(
+        "You are translating a visual novel script. Translate the user's text into {}. \
+         Reply with the translation only.",
+        config.target_language
+    );
+    if config.preserve_line_breaks {
+        prompt.push_str(&format!(
+            " The text box layout depends on the `{LINE_BREAK}` line breaks: keep exactly as \
+             many as the source has, as the literal two characters `{LINE_BREAK}`."
+        ));
+    }
+    if !config.instructions.is_empty() {
+        prompt.push(' ');
+        prompt.push_str(&config.instructions);
+    }
+    prompt
+}
+
+impl OpenAiTranslator {
+    #[anyhow_context]
+    async fn complete(&self, source: &str, hints: Option<&Hints>) -> TranslatorResult<String> {
+        let mut messages = vec![json!({ "role": "system", "content": self.system_prompt })];
+        let hints = hints.cloned().unwrap_or_default();
+        if !hints.context.is_empty() {
+            let context = format!(
+                "For context, this is about the text to translate. \
+                 Do not translate it:\n{}",
+                hints.context.join("\n")
+            );
+            messages.push(json!({ "role": "system", "content": context }));
+        }
+        if let Some(feedback) = hints.feedback {
+            messages.push(json!({ "role": "system", "content": feedback }));
+        }
+        for example in hints.examples {
+            messages.push(json!({ "role": "user", "content": example.source }));
+            messages.push(json!({ "role": "assistant", "content": example.target }));
+        }
+        messages.push(json!({ "role": "user", "content": source }));
+        let request = json!({
+            "model": self.model,
+            "stream": false,
+            "messages": messages,
+        });
+        let response: Value =
+            profile::time_async(Phase::Translate, self.client.chat().create_byot(request)).await?;
+        let choice = &response["choices"][0];
+        if let Some(refusal) = choice["message"]["refusal"].as_str() {
+            bail!(Refused {
+                reason: refusal.into()
+            });
+        }
+        if choice["finish_reason"] == "content_filter" {
+            bail!(Refused {
+                reason: "content filter".into()
+            });
+        }
+        let content = choice["message"]["content"].as_str().into_any_result()?;
+        Ok(content.trim().to_string())
+    }
+}
+
+impl Translator for OpenAiTranslator {
+    fn translate<'a>(&'a self, source: &'a str) -> BoxFuture<'a, TranslatorResult<String>> {
+        Box::pin(self.complete(source, None))
+    }
+
+    fn translate_with_hints<'a>(
+        &'a self,
+        source: &'a str,
+        hints: &'a Hints,
+    ) -> BoxFuture<'a, TranslatorResult<String>> {
+        Box::pin(self.complete(source, Some(hints)))
+    }
+
+    fn backend(&self) -> String {
+        format!("openai/{}", self.model)
+    }
+
+    fn prompt(&self) -> &str {
+        &self.system_prompt
+    }
+}
+
+/// Only calls the inner backend for sources matching `predicate`, everything else
+/// (variable references, bare numbers, ...) is returned unchanged.
+pub struct ConditionalTranslator<T> {
+    inner: T,
+    predicate: Regex,
+}
+
+impl<T> ConditionalTranslator<T> {
+    #[anyhow_context]
+    pub fn new(inner: T, predicate: &str) -> TranslatorResult<Self> {
+        Ok(ConditionalTranslator {
+            inner,
+            predicate: Regex::new(predicate)?,
+        })
+    }
+}
+
+impl<T: Translator> Translator for ConditionalTranslator<T> {
+    fn translate<'a>(&'a self, source: &'a str) -> BoxFuture<'a, TranslatorResult<String>> {
+        if self.predicate.is_match(source) {
+            self.inner.translate(source)
+        } else {
+            Box::pin(async move { Ok(source.to_string()) })
+        }
+    }
+
+    fn translate_with_hints<'a>(
+        &'a self,
+        source: &'a str,
+        hints: &'a Hints,
+    ) -> BoxFuture<'a, TranslatorResult<String>> {
+        if self.predicate.is_match(source) {
+            self.inner.translate_with_hints(source, hints)
+        } else {
+            Box::pin(async move { Ok(source.to_string()) })
+        }
+    }
+
+    fn backend(&self) -> String {
+        self.inner.backend()
+    }
+
+    fn prompt(&self) -> &str {
+        self.inner.prompt()
+    }
+}
+
+/// Quote pairs a backend may wrap a whole translation in.
+const QUOTES: [(char, char); 5] = [
+    ('"', '"'),
+    ('\'', '\''),
+    ('\u{201C}', '\u{201D}'),
+    ('\u{300C}', '\u{300D}'),
+    ('\u{300E}', '\u{300F}'),
+];
+
+/// Strips what a backend wrapped the translation of `source` in: every match of
+/// `chatter` (preambles like `Sure, here's the translation:`), a markdown code
+/// fence, and quotes around the whole translation that the source has not.
+pub fn sanitize_translation(source: &str, raw: &str, chatter: &[Regex]) -> String {
+    let mut text = raw.trim().to_string();
+    for pattern in chatter {
+        text = pattern.replace_all(&text, "").trim().to_string();
+    }
+    if let Some(fenced) = text
+        .strip_prefix("```")
+        .and_then(|rest| rest.strip_suffix("```"))
+    {
+        // The opening fence may name a language, e.g. ```text
+        let body = match fenced.split_once('\n') {
+            Some((info, body))
+                if info
+                    .chars()
+                    .all(|ch| ch.is_ascii_alphanumeric() || ch == '-') =>
+            {
+                body
+            }
+            _ => fenced,
+        };
+        text = body.trim().to_string();
+    }
+    for (open, close) in QUOTES {
+        if let Some(inner) = text
+            .strip_prefix(open)
+            .and_then(|rest| rest.strip_suffix(close))
+            && !source.trim_start().starts_with(open)
+            && !inner.contains([open, close])
+        {
+            return inner.trim().to_string();
+        }
+    }
+    text
+}
+
+/// Cleans up every translation of the inner backend, see [`sanitize_translation`].
+pub struct SanitizeOutput<T> {
+    inner: T,
+    chatter: Vec<Regex>,
+}
+
+impl<T> SanitizeOutput<T> {
+    #[anyhow_context]
+    pub fn new(inner: T, chatter: &[String]) -> TranslatorResult<Self> {
+        Ok(SanitizeOutput {
+            inner,
+            chatter: chatter
+                .iter()
+                .map(|pattern| Regex::new(pattern))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl<T: Translator> Translator for SanitizeOutput<T> {
+    fn translate<'a>(&'a self, source: &'a str) -> BoxFuture<'a, TranslatorResult<String>> {
+        Box::pin(async move {
+            let raw = self.inner.translate(source).await?;
+            Ok(sanitize_translation(source, &raw, &self.chatter))
+        })
+    }
+
+    fn translate_with_hints<'a>(
+        &'a self,
+        source: &'a str,
+        hints: &'a Hints,
+    ) -> BoxFuture<'a, TranslatorResult<String>> {
+        Box::pin(async move {
+            let raw = self.inner.translate_with_hints(source, hints).await?;
+            Ok(sanitize_translation(source, &raw, &self.chatter))
+        })
+    }
+
+    fn backend(&self) -> String {
+        self.inner.backend()
+    }
+
+    fn prompt(&self) -> &str {
+        self.inner.prompt()
+    }
+}
+
+/// A `pattern` → `replacement` pair of the `translator.fixup_rules` file.
+#[derive(Clone, Debug)]
+pub struct FixupRule {
+    pub pattern: Regex,
+    /// May refer to groups of the pattern as `$1`, `$name`.
+    pub replacement: String,
+}
+
+#[derive(Deserialize)]
+struct FixupRulesFile {
+    #[serde(default)]
+    rule: Vec<FixupRuleEntry>,
+}
+
+#[derive(Deserialize)]
+struct FixupRuleEntry {
+    pattern: String,
+    replacement: String,
+}
+
+/// Reads a fixup rules file, failing on the first rule whose regex is invalid.
+#[anyhow_context]
+pub fn load_fixup_rules(path: &Path) -> TranslatorResult<Vec<FixupRule>> {
+    let file: FixupRulesFile = toml::from_str(&read_to_string(path)?)?;
+    file.rule
+        .into_iter()
+        .enumerate()
+        .map(|(index, rule)| -> TranslatorResult<FixupRule> {
+            let pattern = Regex::new(&rule.pattern)
+                .with_context(|| format!("Fixup rule {} of `{}`", index + 1, path.display()))?;
+            Ok(FixupRule {
+                pattern,
+                replacement: rule.replacement,
+            })
+        })
+        .collect()
+}
+
+/// The rules of `translator.fixup_rules`, none if it isn't set.
+#[anyhow_context]
+pub fn fixup_rules(config: &TranslatorConfig) -> TranslatorResult<Vec<FixupRule>> {
+    match &config.fixup_rules {
+        Some(path) => load_fixup_rules(path),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Applies every rule to `text`, in the order of the rules file.
+pub fn apply_fixups(text: &str, rules: &[FixupRule]) -> String {
+    rules.iter().fold(text.to_string(), |text, rule| {
+        rule.pattern
+            .replace_all(&text, rule.replacement.as_str())
+            .into_owned()
+    })
+}
+
+/// Applies the fixup rules to every translation of the inner backend.
+pub struct Fixups<T> {
+    inner: T,
+    rules: Vec<FixupRule>,
+}
+
+impl<T> Fixups<T> {
+    pub fn new(inner: T, rules: Vec<FixupRule>) -> Self {
+        Fixups { inner, rules }
+    }
+}
+
+impl<T: Translator> Translator for Fixups<T> {
+    fn translate<'a>(&'a self, source: &'a str) -> BoxFuture<'a, TranslatorResult<String>> {
+        Box::pin(async move {
+            let translated = self.inner.translate(source).await?;
+            Ok(apply_fixups(&translated, &self.rules))
+        })
+    }
+
+    fn translate_with_hints<'a>(
+        &'a self,
+        source: &'a str,
+        hints: &'a Hints,
+    ) -> BoxFuture<'a, TranslatorResult<String>> {
+        Box::pin(async move {
+            let translated = self.inner.translate_with_hints(source, hints).await?;
+            Ok(apply_fixups(&translated, &self.rules))
+        })
+    }
+
+    fn backend(&self) -> String {
+        self.inner.backend()
+    }
+
+    fn prompt(&self) -> &str {
+        self.inner.prompt()
+    }
+}
+
+/// The escape the engine turns into a line break inside a message.
+const LINE_BREAK: &str = "\\n";
+
+/// Makes sure a translation has exactly as many `\n` line breaks as its source.
+///
+/// Real newlines in the reply become `\n`. If the count still differs, the
+/// translation is reflowed into as many lines as the source, split near equal
+/// lengths, and a warning is printed.
+pub struct LineBreakPreserving<T> {
+    inner: T,
+}
+
+impl<T> LineBreakPreserving<T> {
+    pub fn new(inner: T) -> Self {
+        LineBreakPreserving { inner }
+    }
+}
+
+impl<T> LineBreakPreserving<T> {
+    fn restore(source: &str, translated: String) -> String {
+        let translated = translated
+            .replace("\r\n", LINE_BREAK)
+            .replace('\n', LINE_BREAK);
+        let breaks = source.matches(LINE_BREAK).count();
+        if translated.matches(LINE_BREAK).count() == breaks {
+            return translated;
+        }
+        let reflowed = reflow(&translated, breaks + 1);
+        eprintln!("Reflowed translation to keep {breaks} line break(s): {reflowed}");
+        reflowed
+    }
+}
+
+impl<T: Translator> Translator for LineBreakPreserving<T> {
+    fn translate<'a>(&'a self, source: &'a str) -> BoxFuture<'a, TranslatorResult<String>> {
+        Box::pin(async move {
+            let translated = self.inner.translate(source).await?;
+            Ok(Self::restore(source, translated))
+        })
+    }
+
+    fn translate_with_hints<'a>(
+        &'a self,
+        source: &'a str,
+        hints: &'a Hints,
+    ) -> BoxFuture<'a, TranslatorResult<String>> {
+        Box::pin(async move {
+            let translated = self.inner.translate_with_hints(source, hints).await?;
+            Ok(Self::restore(source, translated))
+        })
+    }
+
+    fn backend(&self) -> String {
+        self.inner.backend()
+    }
+
+    fn prompt(&self) -> &str {
+        self.inner.prompt()
+    }
+}
+
+/// Re-splits `text` into `lines` lines of roughly equal length, preferring to cut
+/// at spaces so that words stay whole.
+fn reflow(text: &str, lines: usize) -> String {
+    let parts = text
+        .split(LINE_BREAK)
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+    let spaced =
+        parts.iter().any(|line| line.contains(' ')) || parts.iter().all(|line| line.is_ascii());
+    let joined = parts.join(if spaced { " " } else { "" });
+    let chars = joined.chars().collect::<Vec<_>>();
+
+    let mut cuts = Vec::new();
+    for line in 1..lines {
+        let target = chars.len() * line / lines;
+        let cut = if spaced {
+            let after = (target..chars.len()).find(|&i| chars[i] == ' ');
+            let before = (0..target).rev().find(|&i| chars[i] == ' ');
+            match (before, after) {
+                (Some(b), Some(a)) if target - b <= a - target => b,
+                (_, Some(a)) => a,
+                (Some(b), None) => b,
+                (None, None) => target,
+            }
+        } else {
+            target
+        };
+        cuts.push(cut.max(cuts.last().copied().unwrap_or(0)));
+    }
+
+    let mut reflowed = Vec::with_capacity(lines);
+    let mut start = 0;
+    for cut in cuts.into_iter().chain([chars.len()]) {
+        let line = chars[start..cut].iter().collect::<String>();
+        reflowed.push(line.trim().to_string());
+        start = cut;
+    }
+    reflowed.join(LINE_BREAK)
+}
+
+/// Remembers sources the inner backend refused and fails them right away on later
+/// runs, unless `retry_failed` is set.
+pub struct RefusalCaching<T> {
+    inner: T,
+    db: Arc<DatabaseConnection>,
+    retry_failed: bool,
+}
+
+impl<T> RefusalCaching<T> {
+    pub fn new(inner: T, db: Arc<DatabaseConnection>, retry_failed: bool) -> Self {
+        RefusalCaching {
+            inner,
+            db,
+            retry_failed,
+        }
+    }
+}
+
+impl<T: Translator> RefusalCaching<T> {
+    async fn guarded(&self, source: &str, hints: Option<&Hints>) -> TranslatorResult<String> {
+        if !self.retry_failed
+            && let Some(reason) = find_refusal(self.db.clone(), source).await?
+        {
+            bail!(Refused { reason });
+        }
+        let translated = match hints {
+            Some(hints) => self.inner.translate_with_hints(source, hints).await,
+            None => self.inner.translate(source).await,
+        };
+        match translated {
+            Ok(translated) => {
+                if self.retry_failed {
+                    clear_refusal(self.db.clone(), source).await?;
+                }
+                Ok(translated)
+            }
+            Err(error) => {
+                if let Some(refused) = error.downcast_ref::<Refused>() {
+                    let (backend, reason) = (self.inner.backend(), refused.reason.clone());
+                    record_refusal(self.db.clone(), source, backend, reason).await?;
+                }
+                Err(error)
+            }
+        }
+    }
+}
+
+impl<T: Translator> Translator for RefusalCaching<T> {
+    fn translate<'a>(&'a self, source: &'a str) -> BoxFuture<'a, TranslatorResult<String>> {
+        Box::pin(self.guarded(source, None))
+    }
+
+    fn translate_with_hints<'a>(
+        &'a self,
+        source: &'a str,
+        hints: &'a Hints,
+    ) -> BoxFuture<'a, TranslatorResult<String>> {
+        Box::pin(self.guarded(source, Some(hints)))
+    }
+
+    fn backend(&self) -> String {
+        self.inner.backend()
+    }
+
+    fn prompt(&self) -> &str {
+        self.inner.prompt()
+    }
+}
+
+/// Asks the inner backend for a more concise translation, up to `retries` times,
+/// while a translation is wider than `max_width` (see [`display_width`]). The
+/// narrowest attempt is kept; if none fits a warning is printed.
+pub struct LengthRetrying<T> {
+    inner: T,
+    max_width: usize,
+    retries: usize,
+}
+
+impl<T> LengthRetrying<T> {
+    pub fn new(inner: T, max_width: usize, retries: usize) -> Self {
+        LengthRetrying {
+            inner,
+            max_width,
+            retries,
+        }
+    }
+}
+
+impl<T: Translator> LengthRetrying<T> {
+    async fn fitting(&self, source: &str, hints: Option<&Hints>) -> TranslatorResult<String> {
+        let mut shortest = match hints {
+            Some(hints) => self.inner.translate_with_hints(source, hints).await?,
+            None => self.inner.translate(source).await?,
+        };
+        let mut width = display_width(&shortest);
+        for _ in 0..self.retries {
+            if width <= self.max_width {
+                return Ok(shortest);
+            }
+            let feedback = Some(format!(
+                "Your translation `{shortest}` is {width} columns wide but a line of the text \
+                 box only fits {} (full-width characters count as 2). Translate the text again, \
+                 more concisely.",
+                self.max_width
+            ));
+            let retry = Hints {
+                feedback,
+                ..hints.cloned().unwrap_or_default()
+            };
+            let retried = self.inner.translate_with_hints(source, &retry).await?;
+            let retried_width = display_width(&retried);
+            if retried_width < width {
+                (shortest, width) = (retried, retried_width);
+            }
+        }
+        if width > self.max_width {
+            eprintln!(
+                "Translation is {width} columns wide, over the limit of {}: {shortest}",
+                self.max_width
+            );
+        }
+        Ok(shortest)
+    }
+}
+
+impl<T: Translator> Translator for LengthRetrying<T> {
+    fn translate<'a>(&'a self, source: &'a str) -> BoxFuture<'a, TranslatorResult<String>> {
+        Box::pin(self.fitting(source, None))
+    }
+
+    fn translate_with_hints<'a>(
+        &'a self,
+        source: &'a str,
+        hints: &'a Hints,
+    ) -> BoxFuture<'a, TranslatorResult<String>> {
+        Box::pin(self.fitting(source, Some(hints)))
+    }
+
+    fn backend(&self) -> String {
+        self.inner.backend()
+    }
+
+    fn prompt(&self) -> &str {
+        self.inner.prompt()
+    }
+}
+
+/// Keeps sources longer than `max_chars` characters away from the inner backend,
+/// whose context window they might not fit. With `split` such a source is
+/// translated in chunks of whole `\n` lines that fit, joined again; otherwise,
+/// or if a single line is too long, it fails with [`TooLong`].
+pub struct InputLimiting<T> {
+    inner: T,
+    max_chars: usize,
+    split: bool,
+}
+
+impl<T> InputLimiting<T> {
+    pub fn new(inner: T, max_chars: usize, split: bool) -> Self {
+        InputLimiting {
+            inner,
+            max_chars,
+            split,
+        }
+    }
+
+    /// Packs the lines of `source` into as few chunks of at most `max_chars`
+    /// characters as possible.
+    fn chunks(&self, source: &str) -> TranslatorResult<Vec<String>> {
+        let too_long = || TooLong {
+            chars: source.chars().count(),
+            max_chars: self.max_chars,
+        };
+        if !self.split {
+            bail!(too_long());
+        }
+        let mut chunks = Vec::<String>::new();
+        for line in source.split(LINE_BREAK) {
+            let chars = line.chars().count();
+            if chars > self.max_chars {
+                bail!(too_long());
+            }
+            match chunks.last_mut() {
+                Some(chunk)
+                    if chunk.chars().count() + LINE_BREAK.len() + chars <= self.max_chars =>
+                {
+                    chunk.push_str(LINE_BREAK);
+                    chunk.push_str(line);
+                }
+                _ => chunks.push(line.to_string()),
+            }
+        }
+        Ok(chunks)
+    }
+}
+
+impl<T: Translator> InputLimiting<T> {
+    async fn limited(&self, source: &str, hints: Option<&Hints>) -> TranslatorResult<String> {
+        if source.chars().count() <= self.max_chars {
+            return match hints {
+                Some(hints) => self.inner.translate_with_hints(source, hints).await,
+                None => self.inner.translate(source).await,
+            };
+        }
+        let chunks = self.chunks(source)?;
+        eprintln!(
+            "Translating a source of {} characters in {} chunks",
+            source.chars().count(),
+            chunks.len()
+        );
+        let mut translated = Vec::with_capacity(chunks.len());
+        for chunk in &chunks {
+            translated.push(match hints {
+                Some(hints) => self.inner.translate_with_hints(chunk, hints).await?,
+                None => self.inner.translate(chunk).await?,
+            });
+        }
+        Ok(translated.join(LINE_BREAK))
+    }
+}
+
+impl<T: Translator> Translator for InputLimiting<T> {
+    fn translate<'a>(&'a self, source: &'a str) -> BoxFuture<'a, TranslatorResult<String>> {
+        Box::pin(self.limited(source, None))
+    }
+
+    fn translate_with_hints<'a>(
+        &'a self,
+        source: &'a str,
+        hints: &'a Hints,
+    ) -> BoxFuture<'a, TranslatorResult<String>> {
+        Box::pin(self.limited(source, Some(hints)))
+    }
+
+    fn backend(&self) -> String {
+        self.inner.backend()
+    }
+
+    fn prompt(&self) -> &str {
+        self.inner.prompt()
+    }
+}
+
+/// Passes `translator.do_not_translate` literals through untouched: a source that
+/// is one of them is never sent to the inner backend, and occurrences inside a
+/// source are masked with placeholders while it is translated.
+pub struct ProtectedLiterals<T> {
+    inner: T,
+    /// Longest first, so that a literal containing another one is masked whole.
+    literals: Vec<String>,
+}
+
+impl<T> ProtectedLiterals<T> {
+    pub fn new(inner: T, literals: &[String]) -> Self {
+        let mut literals = literals
+            .iter()
+            .filter(|literal| !literal.is_empty())
+            .cloned()
+            .collect::<Vec<_>>();
+        literals.sort_by_key(|literal| Reverse(literal.chars().count()));
+        ProtectedLiterals { inner, literals }
+    }
+
+    fn placeholder(index: usize) -> String {
+        format!("⟦{index}⟧")
+    }
+
+    /// `source` with every literal replaced by its placeholder.
+    fn mask(&self, source: &str) -> String {
+        let mut masked = source.to_string();
+        for (index, literal) in self.literals.iter().enumerate() {
+            masked = masked.replace(literal.as_str(), &Self::placeholder(index));
+        }
+        masked
+    }
+
+    /// `translated` with the placeholders of `masked` replaced by their literals.
+    fn unmask(&self, masked: &str, translated: String) -> String {
+        let mut unmasked = translated;
+        for (index, literal) in self.literals.iter().enumerate() {
+            let placeholder = Self::placeholder(index);
+            if masked.contains(&placeholder) && !unmasked.contains(&placeholder) {
+                eprintln!("The backend dropped the do-not-translate literal `{literal}`");
+            }
+            unmasked = unmasked.replace(&placeholder, literal);
+        }
+        unmasked
+    }
+}
+
+impl<T: Translator> ProtectedLiterals<T> {
+    async fn protected(&self, source: &str, hints: Option<&Hints>) -> TranslatorResult<String> {
+        if self.literals.iter().any(|literal| literal == source.trim()) {
+            return Ok(source.to_string());
+        }
+        let masked = self.mask(source);
+        let translated = match hints {
+            Some(hints) => self.inner.translate_with_hints(&masked, hints).await?,
+            None => self.inner.translate(&masked).await?,
+        };
+        Ok(self.unmask(&masked, translated))
+    }
+}
+
+impl<T: Translator> Translator for ProtectedLiterals<T> {
+    fn translate<'a>(&'a self, source: &'a str) -> BoxFuture<'a, TranslatorResult<String>> {
+        Box::pin(self.protected(source, None))
+    }
+
+    fn translate_with_hints<'a>(
+        &'a self,
+        source: &'a str,
+        hints: &'a Hints,
+    ) -> BoxFuture<'a, TranslatorResult<String>> {
+        Box::pin(self.protected(source, Some(hints)))
+    }
+
+    fn backend(&self) -> String {
+        self.inner.backend()
+    }
+
+    fn prompt(&self) -> &str {
+        self.inner.prompt()
+    }
+}
+
+/// Whether the engine draws `ch` two columns wide: CJK, kana, hangul and the
+/// full-width forms.
+fn is_full_width(ch: char) -> bool {
+    matches!(
+        ch,
+        '\u{1100}'..='\u{115F}'
+            | '\u{2E80}'..='\u{A4CF}'
+            | '\u{AC00}'..='\u{D7A3}'
+            | '\u{F900}'..='\u{FAFF}'
+            | '\u{FE30}'..='\u{FE4F}'
+            | '\u{FF00}'..='\u{FF60}'
+            | '\u{FFE0}'..='\u{FFE6}'
+            | '\u{20000}'..='\u{3FFFD}'
+    )
+}
+
+/// The display width of the widest line of a translation, in half-width
+/// columns: full-width characters take two.
+fn display_width(text: &str) -> usize {
+    text.split(LINE_BREAK)
+        .map(|line| {
+            line.chars()
+                .map(|ch| if is_full_width(ch) { 2 } else { 1 })
+                .sum()
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+// Fails to compile, right here, if a shipped translator stops being shareable.
+assert_impl_all!(OpenAiTranslator: Translator, Send, Sync);
+assert_impl_all!(ConditionalTranslator<SharedTranslator>: Translator, Send, Sync);
+assert_impl_all!(SanitizeOutput<SharedTranslator>: Translator, Send, Sync);
+assert_impl_all!(Fixups<SharedTranslator>: Translator, Send, Sync);
+assert_impl_all!(LineBreakPreserving<SharedTranslator>: Translator, Send, Sync);
+assert_impl_all!(RefusalCaching<SharedTranslator>: Translator, Send, Sync);
+assert_impl_all!(LengthRetrying<SharedTranslator>: Translator, Send, Sync);
+assert_impl_all!(InputLimiting<SharedTranslator>: Translator, Send, Sync);
+assert_impl_all!(ProtectedLiterals<SharedTranslator>: Translator, Send, Sync);
+assert_impl_all!(SharedTranslator: Translator, Send, Sync);
+// And so must everything else the translator workers are handed as `Data`.
+assert_impl_all!(FileTranslators: Send, Sync);
+assert_impl_all!(LocalizedTranslators: Send, Sync);
+assert_impl_all!(TranslationCache: Send, Sync);
+
+/// The configured backend with its decorators. Refusals are remembered in the
+/// project database; `retry_failed` attempts previously refused sources again.
+#[anyhow_context]
+pub async fn build_translator(
+    config: &TranslatorConfig,
+    retry_failed: bool,
+) -> TranslatorResult<SharedTranslator> {
+    let mut backend: SharedTranslator = Arc::new(OpenAiTranslator::new(config));
+    if config.sanitize_output {
+        backend = Arc::new(SanitizeOutput::new(backend, &config.chatter_patterns)?);
+    }
+    let rules = fixup_rules(config)?;
+    if !rules.is_empty() {
+        backend = Arc::new(Fixups::new(backend, rules));
+    }
+    if config.preserve_line_breaks {
+        backend = Arc::new(LineBreakPreserving::new(backend));
+    }
+    if let Some(max_width) = config.max_width
+        && config.max_length_retries > 0
+    {
+        let retries = config.max_length_retries;
+        backend = Arc::new(LengthRetrying::new(backend, max_width, retries));
+    }
+    if let Some(max_chars) = config.max_input_chars {
+        let split = config.split_long_inputs;
+        backend = Arc::new(InputLimiting::new(backend, max_chars, split));
+    }
+    backend = Arc::new(RefusalCaching::new(
+        backend,
+        open_project_db().await?,
+        retry_failed,
+    ));
+    if !config.do_not_translate.is_empty() {
+        backend = Arc::new(ProtectedLiterals::new(backend, &config.do_not_translate));
+    }
+    Ok(Arc::new(ConditionalTranslator::new(
+        backend,
+        &config.translate_if,
+    )?))
+}
+
+/// A translator per `translator.file_overrides`, in the order listed.
+pub type FileTranslators = Arc<Vec<(FileOverride, SharedTranslator)>>;
+
+#[anyhow_context]
+pub async fn build_file_translators(
+    config: &TranslatorConfig,
+    retry_failed: bool,
+) -> TranslatorResult<FileTranslators> {
+    let mut translators = Vec::with_capacity(config.file_overrides.len());
+    for file_override in &config.file_overrides {
+        let overridden = file_override.apply(config);
+        translators.push((
+            file_override.clone(),
+            build_translator(&overridden, retry_failed).await?,
+        ));
+    }
+    Ok(Arc::new(translators))
+}
+
+/// The translator of the most specific override matching the file `name`, see
+/// `translator.file_overrides`.
+pub fn file_translator(translators: &FileTranslators, name: &str) -> Option<SharedTranslator> {
+    let mut best: Option<&(FileOverride, SharedTranslator)> = None;
+    for candidate in translators
+        .iter()
+        .filter(|(file_override, _)| file_override.matches(name))
+    {
+        if best.is_none_or(|(best, _)| candidate.0.specificity() > best.specificity()) {
+            best = Some(candidate);
+        }
+    }
+    best.map(|(_, translator)| translator.clone())
+}
+
+/// A translator per `translator.additional_languages`, by language.
+pub type LocalizedTranslators = Arc<Vec<(String, SharedTranslator)>>;
+
+/// [`build_translator`] for every additional language, with the fixup rules
+/// left out as they are written for the target language.
+#[anyhow_context]
+pub async fn build_localized_translators(
+    config: &TranslatorConfig,
+    retry_failed: bool,
+) -> TranslatorResult<LocalizedTranslators> {
+    let mut translators = Vec::with_capacity(config.additional_languages.len());
+    for lang in &config.additional_languages {
+        let localized = TranslatorConfig {
+            target_language: lang.clone(),
+            fixup_rules: None,
+            ..config.clone()
+        };
+        translators.push((
+            lang.clone(),
+            build_translator(&localized, retry_failed).await?,
+        ));
+    }
+    Ok(Arc::new(translators))
+}
+
+/// Recorded as the backend of translations taken from the translation cache.
+const CACHE_BACKEND: &str = "cache";
+
+/// Recorded as the backend of messages kept as they are because they already
+/// are in the target language.
+const DETECTED_BACKEND: &str = "language-detection";
+
+/// Recorded as the backend of messages kept as they are because they are shorter
+/// than `translator.min_source_chars`.
+const TRIVIAL_BACKEND: &str = "trivial";
+
+/// Whether `content` is shorter than `min_chars` characters, not counting
+/// whitespace.
+fn is_trivial(content: &str, min_chars: usize) -> bool {
+    content.chars().filter(|c| !c.is_whitespace()).count() < min_chars
+}
+
+/// The target language if `translator.skip_target_language` is enabled.
+#[anyhow_context]
+pub fn skipped_language(config: &TranslatorConfig) -> TranslatorResult<Option<Lang>> {
+    if !config.skip_target_language {
+        return Ok(None);
+    }
+    let target = &config.target_language;
+    let Some(lang) = Lang::all()
+        .iter()
+        .copied()
+        .find(|lang| lang.eng_name().eq_ignore_ascii_case(target) || lang.code() == target)
+    else {
+        bail!("`skip_target_language` cannot detect the target language `{target}`");
+    };
+    Ok(Some(lang))
+}
+
+/// Recorded as the backend of translations changed by the `fixup` subcommand.
+const FIXUP_BACKEND: &str = "fixup";
+
+/// Applies `rules` to the existing translations of `name`, except locked ones,
+/// returning how many translations changed.
+#[anyhow_context]
+pub async fn fixup_file(
+    name: &str,
+    rules: &[FixupRule],
+    gate: &WriteGate,
+    triggered_by: &str,
+) -> TranslatorResult<u64> {
+    let db = create_db_connection(name).await?;
+    let rules_text = rules
+        .iter()
+        .map(|rule| format!("{}\0{}", rule.pattern, rule.replacement))
+        .collect::<Vec<_>>();
+    let audit = AuditEntry {
+        backend: FIXUP_BACKEND.into(),
+        prompt_hash: sha256_hex(&rules_text.join("\n")),
+        triggered_by: triggered_by.into(),
+    };
+    let mut translations = load_localized(db.clone(), db.language()).await?;
+    let mut changed = 0;
+    for model in TextSegmentEntity::find()
+        .filter(TextSegmentColumn::FileName.eq(db.name()))
+        .filter(TextSegmentColumn::Status.ne(SegmentStatus::Locked))
+        .all(db.as_ref())
+        .await?
+    {
+        let Some(translation) = translations.remove(&model.row_id()) else {
+            continue;
+        };
+        let content = apply_fixups(&translation.content, rules);
+        if content == translation.content {
+            continue;
+        }
+        let translation = Translation {
+            content,
+            ..translation
+        };
+        let id = model.row_id();
+        gate.write(|| record_translation(db.clone(), id, translation.clone(), audit.clone()))
+            .await?;
+        changed += 1;
+    }
+    Ok(changed)
+}
+
+/// The translation cache shared by every file, `None` unless `translator.cache`
+/// is enabled. Opened once per run and handed to the translator workers as `Data`.
+pub type TranslationCache = Option<Arc<DatabaseConnection>>;
+
+/// The translation cache if `translator.cache` is enabled.
+#[anyhow_context]
+pub async fn translation_cache(config: &TranslatorConfig) -> TranslatorResult<TranslationCache> {
+    if !config.cache {
+        return Ok(None);
+    }
+    Ok(Some(open_translation_cache().await?))
+}
+
+/// The project glossary if `glossary.translate_names` is enabled.
+#[anyhow_context]
+pub async fn name_glossary(
+    config: &GlossaryConfig,
+) -> TranslatorResult<Option<Arc<DatabaseConnection>>> {
+    if !config.translate_names {
+        return Ok(None);
+    }
+    Ok(Some(open_project_db().await?))
+}
+
+/// The glossary translation of a speaker name, translating and recording it
+/// the first time the name is seen.
+#[anyhow_context]
+async fn translate_name(
+    glossary: Arc<DatabaseConnection>,
+    translator: &dyn Translator,
+    name: &str,
+) -> TranslatorResult<String> {
+    if let Some(term) = lookup_term(glossary.clone(), name).await? {
+        return Ok(term);
+    }
+    let translation = translator.translate(name).await?;
+    record_term(glossary, name, &translation).await
+}
+
+/// Splits text into the units a [`ContextWindow`] counts.
+pub trait SentenceSegmenter: Send + Sync {
+    fn sentences<'a>(&self, text: &'a str) -> Vec<&'a str>;
+}
+
+/// Every line is one unit, which suits short dialogue lines.
+pub struct WholeLines;
+
+impl SentenceSegmenter for WholeLines {
+    fn sentences<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        vec![text]
+    }
+}
+
+/// Unicode (UAX #29) sentence boundaries, for prose whose lines are paragraphs.
+pub struct UnicodeSentences;
+
+impl SentenceSegmenter for UnicodeSentences {
+    fn sentences<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        text.unicode_sentences()
+            .map(str::trim)
+            .filter(|sentence| !sentence.is_empty())
+            .collect()
+    }
+}
+
+/// The last `size` units of the messages before the one being translated,
+/// handed to the backend as [`Hints::context`].
+pub struct ContextWindow {
+    segmenter: Box<dyn SentenceSegmenter>,
+    size: usize,
+}
+
+impl ContextWindow {
+    pub fn new(segmenter: Box<dyn SentenceSegmenter>, size: usize) -> Self {
+        ContextWindow { segmenter, size }
+    }
+
+    /// The context for a message preceded by `preceding`, oldest first.
+    pub fn context(&self, preceding: &[&str]) -> Vec<String> {
+        let mut context = Vec::with_capacity(self.size);
+        for text in preceding.iter().rev() {
+            for sentence in self.segmenter.sentences(text).into_iter().rev() {
+                if context.len() == self.size {
+                    break;
+                }
+                context.push(sentence.to_string());
+            }
+        }
+        context.reverse();
+        context
+    }
+}
+
+/// The context window configured by `context_size` and `context_unit`, if any.
+pub fn context_window(config: &TranslatorConfig) -> Option<ContextWindow> {
+    if config.context_size == 0 {
+        return None;
+    }
+    let segmenter: Box<dyn SentenceSegmenter> = match config.context_unit {
+        ContextUnit::Lines => Box::new(WholeLines),
+        ContextUnit::Sentences => Box::new(UnicodeSentences),
+    };
+    Some(ContextWindow::new(segmenter, config.context_size))
+}
+
+/// Tells the backend about what surrounds a message, see [`Hints::context`].
+/// Implement it to draw context from elsewhere, e.g. character bios.
+pub trait ContextProvider: Send + Sync {
+    /// Lines of context for `message` of the file in `db`, oldest or broadest first.
+    fn context_for<'a>(
+        &'a self,
+        message: &'a IMessageModel,
+        db: FileDb,
+    ) -> BoxFuture<'a, TranslatorResult<Vec<String>>>;
+}
+
+pub type ContextProviders = Vec<Box<dyn ContextProvider>>;
+
+/// The messages right before the one being translated, see [`ContextWindow`].
+impl ContextProvider for ContextWindow {
+    fn context_for<'a>(
+        &'a self,
+        message: &'a IMessageModel,
+        db: FileDb,
+    ) -> BoxFuture<'a, TranslatorResult<Vec<String>>> {
+        Box::pin(async move {
+            // Every message has at least one unit, `size` messages are enough.
+            let preceding = load_preceding_messages(db, message.line, self.size as u64).await?;
+            let preceding = preceding
+                .iter()
+                .map(|message| message.content.as_str())
+                .collect::<Vec<_>>();
+            Ok(self.context(&preceding))
+        })
+    }
+}
+
+/// The `;region=<scene>` a message is in, with the summary of the scene if the
+/// file metadata has one under `scene.<scene>`, see [`read_sidecar_meta`].
+///
+/// [`read_sidecar_meta`]: crate::parser::read_sidecar_meta
+pub struct SceneContext;
+
+impl ContextProvider for SceneContext {
+    fn context_for<'a>(
+        &'a self,
+        message: &'a IMessageModel,
+        db: FileDb,
+    ) -> BoxFuture<'a, TranslatorResult<Vec<String>>> {
+        Box::pin(async move {
+            let Some(scene) = &message.region else {
+                return Ok(Vec::new());
+            };
+            let context = match get_file_meta(db, &format!("scene.{scene}")).await? {
+                Some(summary) => format!("Scene `{scene}`: {summary}"),
+                None => format!("Scene `{scene}`"),
+            };
+            Ok(vec![context])
+        })
+    }
+}
+
+/// The cached translation of the source most similar to the message, like the
+/// fuzzy matches of a translation memory, see [`fuzzy_match`].
+pub struct TranslationMemory {
+    cache: Arc<DatabaseConnection>,
+    threshold: f32,
+}
+
+impl ContextProvider for TranslationMemory {
+    fn context_for<'a>(
+        &'a self,
+        message: &'a IMessageModel,
+        _db: FileDb,
+    ) -> BoxFuture<'a, TranslatorResult<Vec<String>>> {
+        Box::pin(async move {
+            let similar = fuzzy_match(&message.content, self.cache.clone(), self.threshold).await?;
+            let context = similar
+                .map(|(source, translation)| {
+                    format!("A similar text, `{source}`, was translated as `{translation}`.")
+                })
+                .into_iter()
+                .collect();
+            Ok(context)
+        })
+    }
+}
+
+/// The context providers `config` enables, the broadest first.
+pub fn context_providers(config: &TranslatorConfig, cache: &TranslationCache) -> ContextProviders {
+    let mut providers = ContextProviders::new();
+    if config.scene_context {
+        providers.push(Box::new(SceneContext));
+    }
+    if let Some(window) = context_window(config) {
+        providers.push(Box::new(window));
+    }
+    if let Some(threshold) = config.fuzzy_match_threshold
+        && let Some(cache) = cache
+    {
+        let cache = cache.clone();
+        providers.push(Box::new(TranslationMemory { cache, threshold }));
+    }
+    providers
+}
+
+/// Translates every message of `name` (in `region` if given) that has no
+/// translation yet, reusing the cached translation of identical sources.
+/// With a `glossary`, speaker names are translated through it as well; the
+/// backend is shown what the `context` providers tell about each message. Messages
+/// detected as being in `skipped` already or shorter than `min_chars` are kept
+/// as they are, with a note. The backend is shown the `examples` for the speaker of each message.
+/// Translations are written through `gate`.
+#[allow(clippy::too_many_arguments)]
+#[anyhow_context]
+pub async fn translate_file(
+    name: &str,
+    translator: &dyn Translator,
+    gate: &WriteGate,
+    cache: Option<Arc<DatabaseConnection>>,
+    glossary: Option<Arc<DatabaseConnection>>,
+    context: &[Box<dyn ContextProvider>],
+    examples: &FewShotExamples,
+    skipped: Option<Lang>,
+    min_chars: usize,
+    region: Option<&str>,
+    triggered_by: &str,
+) -> TranslatorResult<()> {
+    let db = create_db_connection(name).await?;
+    mark_stage_started(db.clone(), PipelineStage::Translator).await?;
+    let audit = AuditEntry {
+        backend: translator.backend(),
+        prompt_hash: sha256_hex(translator.prompt()),
+        triggered_by: triggered_by.into(),
+    };
+    let cached_audit = AuditEntry {
+        backend: CACHE_BACKEND.into(),
+        ..audit.clone()
+    };
+    let detected_audit = AuditEntry {
+        backend: DETECTED_BACKEND.into(),
+        ..audit.clone()
+    };
+    let trivial_audit = AuditEntry {
+        backend: TRIVIAL_BACKEND.into(),
+        ..audit.clone()
+    };
+    for model in load_untranslated(db.clone(), region).await? {
+        let id = model.row_id();
+        let segment = TextSegment::try_from(model)?;
+        let fingerprint = segment.fingerprint();
+        let TextSegment::IMessage(message) = segment else {
+            continue;
+        };
+        let translated_name = match &glossary {
+            Some(glossary) if !message.name.is_empty() => {
+                match translate_name(glossary.clone(), translator, &message.name).await {
+                    Ok(translated) => Some(translated),
+                    Err(error) if is_permanent(&error) => {
+                        eprintln!("[{name}] segment {id}: {error:#}");
+                        gate.write(|| set_status(db.clone(), id, SegmentStatus::Failed))
+                            .await?;
+                        continue;
+                    }
+                    Err(error) => return Err(error),
+                }
+            }
+            _ => None,
+        };
+        if let Some(skipped) = skipped
+            && detect_segment_language(&message.content) == Some(skipped)
+        {
+            eprintln!(
+                "[{name}] segment {id}: already in {}, kept as is",
+                skipped.eng_name()
+            );
+            let translation = Translation {
+                notes: Some(format!("Source already in {}", skipped.eng_name())),
+                translated_name,
+                ..Translation::new(message.content.clone())
+            };
+            gate.write(|| {
+                record_translation(db.clone(), id, translation.clone(), detected_audit.clone())
+            })
+            .await?;
+            continue;
+        }
+        if is_trivial(&message.content, min_chars) {
+            let translation = Translation {
+                notes: Some(format!("Shorter than {min_chars} characters")),
+                translated_name,
+                ..Translation::new(message.content.clone())
+            };
+            gate.write(|| {
+                record_translation(db.clone(), id, translation.clone(), trivial_audit.clone())
+            })
+            .await?;
+            continue;
+        }
+        if let Some(cache) = &cache
+            && let Some(content) = lookup_translation(cache.clone(), &fingerprint).await?
+        {
+            let translation = Translation {
+                translated_name,
+                ..Translation::new(content)
+            };
+            gate.write(|| {
+                record_translation(db.clone(), id, translation.clone(), cached_audit.clone())
+            })
+            .await?;
+            continue;
+        }
+        let mut hints = Hints {
+            examples: examples.for_speaker(&message.name),
+            ..Hints::default()
+        };
+        for provider in context {
+            let provided = provider.context_for(&message, db.clone()).await?;
+            hints.context.extend(provided);
+        }
+        let started = Instant::now();
+        let content = match translator
+            .translate_with_hints(&message.content, &hints)
+            .await
+        {
+            Ok(content) => content,
+            Err(error) if is_permanent(&error) => {
+                eprintln!("[{name}] segment {id}: {error:#}");
+                gate.write(|| set_status(db.clone(), id, SegmentStatus::Failed))
+                    .await?;
+                continue;
+            }
+            Err(error) => return Err(error),
+        };
+        let elapsed = started.elapsed();
+        if let Some(cache) = &cache {
+            gate.write(|| {
+                cache_translation(cache.clone(), &fingerprint, &message.content, &content)
+            })
+            .await?;
+        }
+        let translation = Translation {
+            translated_name,
+            ..Translation::new(content)
+        };
+        gate.write(|| record_translation(db.clone(), id, translation.clone(), audit.clone()))
+            .await?;
+        gate.write(|| record_translate_time(db.clone(), id, elapsed))
+            .await?;
+    }
+
+    mark_stage_done(db, PipelineStage::Translator).await?;
+    Ok(())
+}
+
+/// Translates every message of `name` (in `region` if given) that has no
+/// translation into `lang` yet with `translator`, which must translate into
+/// `lang`. Translations are written through `gate`.
+#[anyhow_context]
+pub async fn translate_file_into(
+    name: &str,
+    lang: &str,
+    translator: &dyn Translator,
+    gate: &WriteGate,
+    region: Option<&str>,
+) -> TranslatorResult<()> {
+    let db = create_db_connection(name).await?;
+    for model in load_untranslated_into(db.clone(), lang, region).await? {
+        let id = model.row_id();
+        let TextSegment::IMessage(message) = TextSegment::try_from(model)? else {
+            continue;
+        };
+        let content = match translator.translate(&message.content).await {
+            Ok(content) => content,
+            Err(error) if is_permanent(&error) => {
+                eprintln!("[{name}] segment {id} ({lang}): {error:#}");
+                continue;
+            }
+            Err(error) => return Err(error),
+        };
+        let translation = Translation::new(content);
+        gate.write(|| record_localized(db.clone(), id, lang, translation.clone()))
+            .await?;
+    }
+    Ok(())
+}
+
+/// A message picked by [`stratified_sample`].
+#[derive(Clone, Debug)]
+pub struct SampledMessage {
+    pub file: String,
+    pub line: Line,
+    pub name: String,
+    pub content: String,
+    /// What the message is translated as now, if anything.
+    pub current: Option<String>,
+}
+
+/// Up to `size` messages of `files`, drawn at random from every file and speaker
+/// in turn, so that each speaker of each file is sampled once before any is
+/// sampled twice.
+#[anyhow_context]
+pub async fn stratified_sample(
+    files: &[String],
+    size: usize,
+) -> TranslatorResult<Vec<SampledMessage>> {
+    let mut strata = BTreeMap::<(String, String), Vec<SampledMessage>>::new();
+    for file in files {
+        let db = create_db_connection(file).await?;
+        for (_, segment, translation) in load_translated_segments(db).await? {
+            let TextSegment::IMessage(message) = segment else {
+                continue;
+            };
+            strata
+                .entry((file.clone(), message.name.clone()))
+                .or_default()
+                .push(SampledMessage {
+                    file: file.clone(),
+                    line: message.line,
+                    name: message.name,
+                    content: message.content,
+                    current: translation.map(|translation| translation.content),
+                });
+        }
+    }
+
+    let mut rng = ChaCha8Rng::seed_from_u64(rand::random());
+    let mut strata = strata
+        .into_values()
+        .map(|mut stratum| {
+            stratum.shuffle(&mut rng);
+            stratum.into_iter()
+        })
+        .collect::<Vec<_>>();
+    let mut sample = Vec::with_capacity(size);
+    while sample.len() < size {
+        let drawn = sample.len();
+        for stratum in &mut strata {
+            if sample.len() == size {
+                break;
+            }
+            sample.extend(stratum.next());
+        }
+        if sample.len() == drawn {
+            break;
+        }
+    }
+    Ok(sample)
+}
+
+/// Translates `sample` and prints every source next to its translation without
+/// recording anything, to try a backend, prompt or `examples` before a full run.
+#[anyhow_context]
+pub async fn preview_sample(
+    sample: &[SampledMessage],
+    translator: &dyn Translator,
+    examples: &FewShotExamples,
+) -> TranslatorResult<()> {
+    for message in sample {
+        let speaker = if message.name.is_empty() {
+            String::new()
+        } else {
+            format!(" {}", message.name)
+        };
+        println!("[{}:{}]{speaker}", message.file, message.line);
+        println!("  source:  {}", message.content);
+        if let Some(current) = &message.current {
+            println!("  current: {current}");
+        }
+        let hints = Hints {
+            examples: examples.for_speaker(&message.name),
+            ..Hints::default()
+        };
+        match translator
+            .translate_with_hints(&message.content, &hints)
+            .await
+        {
+            Ok(translated) => println!("  sample:  {translated}"),
+            Err(error) if is_permanent(&error) => println!("  sample:  {error:#}"),
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+#[allow(unused, clippy::too_many_arguments)]
+pub async fn translator_main(
+    job: TranslatorJob,
+    translator: Data<SharedTranslator>,
+    overrides: Data<FileTranslators>,
+    localized: Data<LocalizedTranslators>,
+    assembler: Data<Arc<RwLock<AssemblerJobQueue>>>,
+    config: Data<Arc<Config>>,
+    gate: Data<WriteGate>,
+    cache: Data<TranslationCache>,
+    cancel: Data<CancellationToken>,
+) -> AnyResult<()> {
+    let (translator, cache) = match file_translator(&overrides, &job.file_name) {
+        Some(translator) => (translator, None),
+        None => (
+            SharedTranslator::clone(&translator),
+            TranslationCache::clone(&cache),
+        ),
+    };
+    // Whatever was translated when cancelled is kept, the rest is left for `--resume`.
+    let translated = cancel.run_until_cancelled(async {
+        let glossary = name_glossary(&config.glossary).await?;
+        let context = context_providers(&config.translator, &cache);
+        let skipped = skipped_language(&config.translator)?;
+        translate_file(
+            &job.file_name,
+            translator.as_ref(),
+            &gate,
+            cache,
+            glossary,
+            &context,
+            &config.translator.few_shot,
+            skipped,
+            config.translator.min_source_chars,
+            None,
+            TranslatorJob::NAME,
+        )
+        .await?;
+        for (lang, translator) in localized.iter() {
+            translate_file_into(&job.file_name, lang, translator.as_ref(), &gate, None).await?;
+        }
+        AnyResult::Ok(())
+    });
+    let Some(translated): Option<AnyResult<()>> = translated.await else {
+        eprintln!("[{}] cancelled, not assembling it", job.file_name);
+        return Ok(());
+    };
+    record_failure(&job.file_name, PipelineStage::Translator, translated).await?;
+
+    let mut assembler = assembler.write().await;
+    assembler
+        .push(AssemblerJob {
+            file_name: job.file_name,
+            file_path: job.file_path,
+        })
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn too_long(result: TranslatorResult<Vec<String>>) -> TooLong {
+        result.unwrap_err().downcast::<TooLong>().unwrap()
+    }
+
+    #[test]
+    fn long_sources_are_packed_into_chunks_of_whole_lines() {
+        let limiting = InputLimiting::new((), 9, true);
+        // `aaa\nbbbb` is exactly 9 characters, the break included.
+        assert_eq!(limiting.chunks(r"aaa\nbbbb").unwrap(), [r"aaa\nbbbb"]);
+        assert_eq!(
+            limiting.chunks(r"aaa\nbbbbb\ncc\ndd").unwrap(),
+            ["aaa", r"bbbbb\ncc", "dd"]
+        );
+        assert_eq!(
+            too_long(limiting.chunks(r"aaa\nbbbbbbbbbb")),
+            TooLong {
+                chars: 15,
+                max_chars: 9
+            }
+        );
+    }
+
+    #[test]
+    fn long_sources_are_not_split_unless_enabled() {
+        let limiting = InputLimiting::new((), 9, false);
+        assert_eq!(
+            too_long(limiting.chunks(r"aaa\nbbbbb")),
+            TooLong {
+                chars: 10,
+                max_chars: 9
+            }
+        );
+    }
+
+    #[test]
+    fn reflow_keeps_the_number_of_lines() {
+        let cases = [
+            // Spaced text is cut at the space nearest to an even split.
+            (
+                r"the quick brown fox\njumps",
+                2,
+                r"the quick\nbrown fox jumps",
+            ),
+            // CJK text without spaces is cut by characters and joined without any.
+            (r"こんにちは\n世界", 2, r"こんに\nちは世界"),
+            // Fewer words than lines leaves lines empty rather than dropping them.
+            ("hello world", 3, r"hello\n\nworld"),
+        ];
+        for (text, lines, expected) in cases {
+            let reflowed = reflow(text, lines);
+            assert_eq!(reflowed, expected, "{text}");
+            assert_eq!(reflowed.matches(LINE_BREAK).count(), lines - 1, "{text}");
+        }
+    }
+
+    #[test]
+    fn sanitizing_strips_what_backends_wrap_translations_in() {
+        let chatter = TranslatorConfig::default()
+            .chatter_patterns
+            .iter()
+            .map(|pattern| Regex::new(pattern).unwrap())
+            .collect::<Vec<_>>();
+        let cases = [
+            (
+                "こんにちは",
+                "Sure, here is the translation: Hello",
+                "Hello",
+            ),
+            ("こんにちは", "Here's the translation:\nHello", "Hello"),
+            ("こんにちは", "Translation: Hello", "Hello"),
+            ("こんにちは", "```text\nHello\n```", "Hello"),
+            ("こんにちは", "\"Hello\"", "Hello"),
+            ("こんにちは", "「Hello」", "Hello"),
+            ("「こんにちは」", "「Hello」", "「Hello」"),
+            (
+                "\"Yes\" and \"no\"",
+                "\"Oui\" et \"non\"",
+                "\"Oui\" et \"non\"",
+            ),
+            ("了解", "Sure thing", "Sure thing"),
+        ];
+        for (source, raw, expected) in cases {
+            assert_eq!(
+                sanitize_translation(source, raw, &chatter),
+                expected,
+                "{raw}"
+            );
+        }
+    }
+
+    /// Tells which override it belongs to by its backend name.
+    struct Named(&'static str);
+
+    impl Translator for Named {
+        fn translate<'a>(&'a self, source: &'a str) -> BoxFuture<'a, TranslatorResult<String>> {
+            Box::pin(async move { Ok(source.to_string()) })
+        }
+
+        fn backend(&self) -> String {
+            self.0.into()
+        }
+
+        fn prompt(&self) -> &str {
+            ""
+        }
+    }
+
+    #[test]
+    fn the_most_specific_override_wins_and_the_first_on_a_tie() {
+        let file_override = |files: &str, name: &'static str| {
+            let file_override = FileOverride {
+                files: files.into(),
+                ..FileOverride::default()
+            };
+            (file_override, Arc::new(Named(name)) as SharedTranslator)
+        };
+        let translators: FileTranslators = Arc::new(vec![
+            file_override("*", "any"),
+            file_override("song_*", "songs"),
+            file_override("song_?1.sc", "ending in one"),
+            file_override("song_0*.sc", "leading zero"),
+        ]);
+        let backend = |name| file_translator(&translators, name).map(|t| t.backend());
+        assert_eq!(backend("intro.sc").as_deref(), Some("any"));
+        assert_eq!(backend("song_123.sc").as_deref(), Some("songs"));
+        // Both have 9 characters that aren't wildcards.
+        assert_eq!(backend("song_01.sc").as_deref(), Some("ending in one"));
+        assert_eq!(backend("song_0.sc").as_deref(), Some("leading zero"));
+        assert!(file_translator(&Arc::new(Vec::new()), "intro.sc").is_none());
+    }
+}
@@ -1,4 +1,5 @@
 use anyhow::{Result, bail};
+use sha2::{Digest, Sha256};
 
 pub trait IntoAnyResult<T> {
     fn into_any_result(self) -> Result<T>;
@@ -12,3 +13,153 @@ impl<T> IntoAnyResult<T> for Option<T> {
         }
     }
 }
+
+/// Regex class mirroring the grammar's `CJ_CHARACTERS` rule.
+pub const CJ_CHARACTERS: &str = r"[\p{Han}\p{Hiragana}\p{Katakana}\x{30FC}\x{FF01}-\x{FFEF}]";
+
+/// Lowercases `text` and maps full-width ASCII forms (U+FF01..U+FF5E) and the
+/// ideographic space to their half-width counterparts.
+pub fn fold_width_and_case(text: &str) -> String {
+    text.chars()
+        .map(|ch| match ch {
+            '\u{FF01}'..='\u{FF5E}' => char::from_u32(ch as u32 - 0xFEE0).unwrap_or(ch),
+            '\u{3000}' => ' ',
+            _ => ch,
+        })
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// A `GLOB` pattern matching text that contains `text`, up to what
+/// [`fold_width_and_case`] folds: each character becomes the class of its case
+/// and width forms.
+pub fn width_and_case_glob(text: &str) -> String {
+    let mut pattern = String::from("*");
+    for ch in fold_width_and_case(text).chars() {
+        let mut forms = vec![ch];
+        match ch {
+            ' ' => forms.push('\u{3000}'),
+            '!'..='~' => {
+                forms.extend(char::from_u32(ch as u32 + 0xFEE0));
+                if ch.is_ascii_lowercase() {
+                    let upper = ch.to_ascii_uppercase();
+                    forms.push(upper);
+                    forms.extend(char::from_u32(upper as u32 + 0xFEE0));
+                }
+            }
+            _ => {
+                let mut upper = ch.to_uppercase();
+                if let (Some(upper), None) = (upper.next(), upper.next())
+                    && upper != ch
+                {
+                    forms.push(upper);
+                }
+            }
+        }
+        if let [form] = forms[..]
+            && !matches!(form, '*' | '?' | '[')
+        {
+            pattern.push(form);
+            continue;
+        }
+        // Inside a class `]` only stands for itself first, `-` last and `^`
+        // anywhere but first.
+        forms.sort_by_key(|form| match form {
+            ']' => 0,
+            '^' => 2,
+            '-' => 3,
+            _ => 1,
+        });
+        pattern.push('[');
+        pattern.extend(forms);
+        pattern.push(']');
+    }
+    pattern.push('*');
+    pattern
+}
+
+/// Maps full-width digits (U+FF10..U+FF19) to ASCII digits, so that `１２３`
+/// parses as `123`.
+pub fn normalize_digits(text: &str) -> String {
+    text.chars()
+        .map(|ch| match ch {
+            '\u{FF10}'..='\u{FF19}' => char::from_u32(ch as u32 - 0xFEE0).unwrap_or(ch),
+            _ => ch,
+        })
+        .collect()
+}
+
+/// Lowercase hex SHA-256 of `text`, stable across runs and versions.
+pub fn sha256_hex(text: &str) -> String {
+    Sha256::digest(text.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Whether `name` matches `glob` as a whole, `*` matching any run of characters
+/// and `?` any one character.
+pub fn glob_matches(glob: &str, name: &str) -> bool {
+    let (glob, name) = (
+        glob.chars().collect::<Vec<_>>(),
+        name.chars().collect::<Vec<_>>(),
+    );
+    let (mut g, mut n) = (0, 0);
+    // Where the last `*` was and where in `name` it is retried from.
+    let mut backtrack = None;
+    while n < name.len() {
+        match glob.get(g) {
+            Some('*') => {
+                backtrack = Some((g, n));
+                g += 1;
+            }
+            Some(&ch) if ch == '?' || ch == name[n] => {
+                g += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, from)) => {
+                    backtrack = Some((star, from + 1));
+                    g = star + 1;
+                    n = from + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&ch| ch == '*')
+}
+
+/// An empty directory of the test `name` below the system's temporary one.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("musica-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_backtrack_over_stars() {
+        let cases = [
+            ("song_*.txt", "song_01.txt", true),
+            ("song_*.txt", "song_01.txt.bak", false),
+            ("*a*b", "xaab", true),
+            ("*a*b", "xaba", false),
+            ("a*b*c", "abcbc", true),
+            ("a*b*c", "abcb", false),
+            ("?b*", "ab", true),
+            ("?b*", "b", false),
+            ("*.??", "name.sc", true),
+            ("*.??", "name.txt", false),
+            ("**", "", true),
+        ];
+        for (glob, name, matches) in cases {
+            assert_eq!(glob_matches(glob, name), matches, "{glob} on {name}");
+        }
+    }
+}